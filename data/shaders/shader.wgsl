@@ -1,6 +1,9 @@
 struct VertexOutput {
     @builtin(position) position: vec4<f32>,
     @location(0) tex_coord: vec2<f32>,
+    @location(1) lightmap_coord: vec2<f32>,
+    @location(2) @interpolate(flat) light_styles: vec4<u32>,
+    @location(3) @interpolate(flat) leaf: u32,
 };
 
 struct Globals {
@@ -25,6 +28,9 @@ fn vs_main(
     @location(0) position: vec4<f32>,
     @location(1) normal: vec4<f32>,
     @location(2) tex_coord: vec2<f32>,
+    @location(3) lightmap_coord: vec2<f32>,
+    @location(4) light_styles: vec4<u32>,
+    @location(5) leaf: u32,
 ) -> VertexOutput {
     var in_position: vec4<f32>;
     in_position.x = position.x;
@@ -34,6 +40,9 @@ fn vs_main(
 
     var out: VertexOutput;
     out.tex_coord = tex_coord;
+    out.lightmap_coord = lightmap_coord;
+    out.light_styles = light_styles;
+    out.leaf = leaf;
     out.position = r_globals.transform * r_locals.transform * in_position;
     return out;
 }
@@ -42,12 +51,65 @@ fn vs_main(
 @binding(0)
 var r_texture: texture_2d<f32>;
 
+// 0 - Fullbright
+// 1 - Lightmap only
+// 2 - Lightmapped
+struct Lighting {
+    mode: u32,
+    // Tint each leaf a different color
+    show_leaves: u32,
+    _padding0: u32,
+    _padding1: u32,
+    // 64 light style values, packed 4 to an element
+    styles: array<vec4<f32>, 16>,
+};
+@group(3)
+@binding(0)
+var r_lightmap: texture_2d_array<f32>;
+@group(3)
+@binding(1)
+var r_lightmap_sampler: sampler;
+@group(3)
+@binding(2)
+var<uniform> r_lighting: Lighting;
+
+// Styles past the end (e.g. 255) mark unused lightmap slots
+fn light_style_value(style: u32) -> f32 {
+    if style >= 64u {
+        return 0.0;
+    }
+    return r_lighting.styles[style / 4u][style % 4u];
+}
+
+fn leaf_color(leaf: u32) -> vec3<f32> {
+    let hash = leaf * 2654435761u;
+    return vec3<f32>(
+        f32((hash >> 8u) & 255u),
+        f32((hash >> 16u) & 255u),
+        f32((hash >> 24u) & 255u),
+    ) / 255.0;
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     var tex_color: vec4<f32>;
     tex_color = textureSample(r_texture, r_sampler, in.tex_coord);
+    var light = textureSample(r_lightmap, r_lightmap_sampler, in.lightmap_coord, 0).xyz * light_style_value(in.light_styles.x);
+    light += textureSample(r_lightmap, r_lightmap_sampler, in.lightmap_coord, 1).xyz * light_style_value(in.light_styles.y);
+    light += textureSample(r_lightmap, r_lightmap_sampler, in.lightmap_coord, 2).xyz * light_style_value(in.light_styles.z);
+    light += textureSample(r_lightmap, r_lightmap_sampler, in.lightmap_coord, 3).xyz * light_style_value(in.light_styles.w);
     if tex_color.w == 0.0 {
         discard;
     }
-    return tex_color;
+    var color = tex_color.xyz;
+    if r_lighting.mode == 1u {
+        color = light;
+    } else if r_lighting.mode == 2u {
+        color = tex_color.xyz * light;
+    }
+    // Leaf 0 is the solid leaf, which is used for everything but the world
+    if r_lighting.show_leaves != 0u && in.leaf != 0u {
+        color = mix(color, leaf_color(in.leaf), 0.5);
+    }
+    return vec4<f32>(color, tex_color.w);
 }
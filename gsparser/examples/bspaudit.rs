@@ -7,7 +7,7 @@ use std::env;
 
 fn main() {
     let args = env::args().skip(1).collect::<Vec<_>>();
-    let path = args.get(0).unwrap();
+    let path = args.first().unwrap();
 
     let search = format!("{}/**/*.bsp", path);
     println!("search: {}", search);
@@ -17,8 +17,14 @@ fn main() {
         let bsp = bsp.unwrap();
 
         let data = std::fs::read(&bsp).unwrap();
-        let reader = BspReader::read(data);
-        let textures = reader.read_textures_header();
+        let reader = match BspReader::read(data) {
+            Ok(reader) => reader,
+            Err(error) => {
+                println!("bsp: {}  -  {}", bsp.display(), error);
+                continue;
+            }
+        };
+        let textures = reader.read_textures_header().unwrap();
         let num_textures = textures.num_textures;
 
         println!("bsp: {}  -  {}", bsp.display(), num_textures);
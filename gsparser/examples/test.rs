@@ -10,7 +10,7 @@ fn main() {
     let search = &args[2];
     let search = search.trim();
 
-    let archive = WadArchive::open(&path);
+    let archive = WadArchive::open(path).unwrap();
     let file_info = &archive.files;
     for info in file_info {
         let name = &info.name;
@@ -21,8 +21,8 @@ fn main() {
                 || info.texture_type == TextureType::MipmappedImage
             {
                 let image_data = match info.texture_type {
-                    TextureType::Decal => archive.decode_decal(&info),
-                    TextureType::MipmappedImage => archive.decode_mipmaped_image(&info),
+                    TextureType::Decal => archive.decode_decal(info).unwrap(),
+                    TextureType::MipmappedImage => archive.decode_mipmaped_image(info).unwrap(),
                     _ => panic!("New texture type! {:?}", info.texture_type),
                 };
 
@@ -32,8 +32,8 @@ fn main() {
                 image_data.mipmap3.save("test_mipmap3.png").unwrap();
             } else {
                 let image_data = match info.texture_type {
-                    TextureType::Image => archive.decode_image(&info).image,
-                    TextureType::Font => archive.decode_font(&info).image,
+                    TextureType::Image => archive.decode_image(info).unwrap().image.clone(),
+                    TextureType::Font => archive.decode_font(info).unwrap().image.clone(),
                     _ => panic!("New texture type! {:?}", info.texture_type),
                 };
 
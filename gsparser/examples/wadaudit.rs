@@ -15,14 +15,22 @@ fn main() {
         let wad = wad.unwrap();
         println!("wad: {}", wad.display());
 
-        let archive = WadArchive::open(&wad);
+        let archive = match WadArchive::open(&wad) {
+            Ok(archive) => archive,
+            Err(error) => {
+                println!("  {}", error);
+                continue;
+            }
+        };
         let file_infos = &archive.files;
         for info in file_infos {
             let name = &info.name;
             if info.texture_type == TextureType::Font {
                 println!("{} - {:?}", name, info.texture_type);
 
-                let _texture_data = archive.decode_font(&info);
+                if let Err(error) = archive.decode_font(info) {
+                    println!("  {}", error);
+                }
             }
         }
     }
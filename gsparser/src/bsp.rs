@@ -1,11 +1,13 @@
 // Sources:
 // https://developer.valvesoftware.com/wiki/BSP_(GoldSrc)
 
-use std::collections::HashMap;
+use std::{borrow::Cow, fmt::Display, str::Utf8Error};
 
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::mdl::null_terminated_bytes_to_str;
+use crate::palette::{decode_paletted_image, PALETTE_LEN};
+use crate::wad3::{MipmapedTextureData, WadWriter};
 
 macro_rules! enum_with_value {
     ($name:ident : $value_ty:ty { $($var_name:ident = $var_value:literal),* $(,)* }) => {
@@ -30,39 +32,211 @@ macro_rules! enum_with_value {
     };
 }
 
-const LUMP_ENTITIES: usize = 0;
-const LUMP_PLANES: usize = 1;
-const LUMP_TEXTURES: usize = 2;
-const LUMP_VERTICES: usize = 3;
-const LUMP_VISIBILITY: usize = 4;
-const LUMP_NODES: usize = 5;
-const LUMP_TEXINFO: usize = 6;
-const LUMP_FACES: usize = 7;
-const LUMP_LIGHTING: usize = 8;
-const LUMP_CLIPNODES: usize = 9;
-const LUMP_LEAVES: usize = 10;
-const LUMP_MARKSURFACES: usize = 11;
-const LUMP_EDGES: usize = 12;
-const LUMP_SURFEDGES: usize = 13;
-const LUMP_MODELS: usize = 14;
+enum_with_value!(BspLump : usize {
+    Entities = 0,
+    Planes = 1,
+    Textures = 2,
+    Vertices = 3,
+    Visibility = 4,
+    Nodes = 5,
+    TextureInfo = 6,
+    Faces = 7,
+    Lighting = 8,
+    ClipNodes = 9,
+    Leaves = 10,
+    MarkSurfaces = 11,
+    Edges = 12,
+    SurfaceEdges = 13,
+    Models = 14,
+});
 const HEADER_LUMPS: usize = 15;
+const GOLDSRC_BSP_VERSION: i32 = 30;
+const QUAKE_BSP_VERSION: i32 = 29;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BspVariant {
+    #[default]
+    GoldSrc,
+    // Blue Shift swaps the entities and planes lumps in the header
+    BlueShift,
+    // Quake (version 29) maps have monochrome lighting, and their textures
+    // don't carry a palette of their own
+    Quake,
+}
+
+impl BspVariant {
+    pub fn version(&self) -> i32 {
+        match self {
+            BspVariant::GoldSrc | BspVariant::BlueShift => GOLDSRC_BSP_VERSION,
+            BspVariant::Quake => QUAKE_BSP_VERSION,
+        }
+    }
+
+    // The number of bytes per luxel in the lighting lump
+    pub fn lighting_sample_size(&self) -> usize {
+        match self {
+            BspVariant::GoldSrc | BspVariant::BlueShift => 3,
+            BspVariant::Quake => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BspError {
+    InvalidHeader(bincode::Error),
+    UnsupportedVersion(i32),
+    LumpOutOfBounds {
+        lump: BspLump,
+        offset: i32,
+        len: i32,
+    },
+    LumpTooSmall {
+        lump: BspLump,
+        len: usize,
+        expected: usize,
+    },
+    InvalidLumpSize {
+        lump: BspLump,
+        len: usize,
+        record_size: usize,
+    },
+    InvalidLump {
+        lump: BspLump,
+        source: bincode::Error,
+    },
+    InvalidEntities(Utf8Error),
+    InvalidEntitySyntax(BspEntityError),
+    TextureOutOfBounds {
+        index: usize,
+        offset: i32,
+    },
+    InvalidTextureName {
+        index: usize,
+        source: Utf8Error,
+    },
+    InvalidTextureData {
+        index: usize,
+    },
+    InvalidFace {
+        index: usize,
+    },
+    InvalidLightmapExtents {
+        index: usize,
+    },
+    InvalidLeaf(usize),
+    InvalidModel(usize),
+    InvalidHull(usize),
+    InvalidHullNode {
+        hull: usize,
+        node: i32,
+    },
+    InvalidContents(i32),
+}
+
+impl Display for BspError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BspError::InvalidHeader(error) => write!(f, "Invalid BSP header: {}", error),
+            BspError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported BSP version: {}", version)
+            }
+            BspError::LumpOutOfBounds { lump, offset, len } => write!(
+                f,
+                "{:?} lump (offset: {}, len: {}) is out of bounds",
+                lump, offset, len
+            ),
+            BspError::LumpTooSmall {
+                lump,
+                len,
+                expected,
+            } => write!(
+                f,
+                "{:?} lump is too small (len: {}, expected: {})",
+                lump, len, expected
+            ),
+            BspError::InvalidLumpSize {
+                lump,
+                len,
+                record_size,
+            } => write!(
+                f,
+                "{:?} lump size ({}) is not a multiple of its record size ({})",
+                lump, len, record_size
+            ),
+            BspError::InvalidLump { lump, source } => {
+                write!(f, "Invalid {:?} lump: {}", lump, source)
+            }
+            BspError::InvalidEntities(error) => write!(f, "Invalid entities lump: {}", error),
+            BspError::InvalidEntitySyntax(error) => {
+                write!(f, "Invalid entities lump: {}", error)
+            }
+            BspError::TextureOutOfBounds { index, offset } => {
+                write!(f, "Texture {} (offset: {}) is out of bounds", index, offset)
+            }
+            BspError::InvalidTextureName { index, source } => {
+                write!(f, "Texture {} has an invalid name: {}", index, source)
+            }
+            BspError::InvalidTextureData { index } => {
+                write!(f, "Texture {} doesn't have valid image data", index)
+            }
+            BspError::InvalidFace { index } => {
+                write!(f, "Face {} references data that is out of bounds", index)
+            }
+            BspError::InvalidLightmapExtents { index } => {
+                write!(f, "Face {} has out of range lightmap extents", index)
+            }
+            BspError::InvalidLeaf(index) => write!(f, "Leaf {} doesn't exist", index),
+            BspError::InvalidModel(index) => write!(f, "Model {} doesn't exist", index),
+            BspError::InvalidHull(hull) => write!(f, "Hull {} doesn't exist", hull),
+            BspError::InvalidHullNode { hull, node } => write!(
+                f,
+                "Node {} of hull {} references data that is out of bounds",
+                node, hull
+            ),
+            BspError::InvalidContents(contents) => {
+                write!(f, "Unknown contents value: {}", contents)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BspError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BspError::InvalidHeader(error) => Some(error),
+            BspError::InvalidLump { source, .. } => Some(source),
+            BspError::InvalidEntities(error) => Some(error),
+            BspError::InvalidEntitySyntax(error) => Some(error),
+            BspError::InvalidTextureName { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+const TEXTURE_FLAG_SPECIAL: u32 = 1;
+pub const MAX_HULLS: usize = 4;
+// The engine rejects lit faces that span more than 256 texels, which is 17
+// luxels once the sample on the far edge is counted
+const MAX_LIGHTMAP_SIZE: u32 = 17;
+// Traces stop this far in front of the plane they hit
+const DIST_EPSILON: f32 = 0.03125;
 
 #[repr(C)]
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 struct BspHeader {
     version: i32,
     lumps: [BspLumpHeader; HEADER_LUMPS],
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 struct BspLumpHeader {
     offset: i32,
     len: i32,
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspFace {
     pub plane: u16,
     pub plane_side: u16,
@@ -74,7 +248,7 @@ pub struct BspFace {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspNode {
     pub plane: u32,
     pub children: [i16; 2],
@@ -100,10 +274,14 @@ enum_with_value!(BspContents : i32 {
     CurrentUp = -13,
     CurrentDown = -14,
     Translucent = -15,
+    Ladder = -16,
+    Flyfield = -17,
+    GravityFlyfield = -18,
+    Fog = -19,
 });
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspLeaf {
     pub contents: i32,
     pub vis_offset: i32,
@@ -115,21 +293,21 @@ pub struct BspLeaf {
 }
 
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspMarkSurface(pub u16);
 
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspSurfaceEdge(pub i32);
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspEdge {
     pub vertices: [u16; 2],
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspVertex {
     pub x: f32,
     pub y: f32,
@@ -137,13 +315,13 @@ pub struct BspVertex {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspTextureHeader {
     pub num_textures: u32,
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspMipTextureHeader {
     pub name: [u8; 16],
     pub width: u32,
@@ -152,7 +330,7 @@ pub struct BspMipTextureHeader {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspTextureInfo {
     pub s: [f32; 3],
     pub s_shift: f32,
@@ -163,7 +341,7 @@ pub struct BspTextureInfo {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspPlane {
     pub normal: [f32; 3],
     pub dist: f32,
@@ -171,7 +349,7 @@ pub struct BspPlane {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspModel {
     pub mins: [f32; 3],
     pub maxs: [f32; 3],
@@ -183,7 +361,7 @@ pub struct BspModel {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
 pub struct BspClipNode {
     pub plane_index: i32,
     pub children: [i16; 2],
@@ -191,103 +369,1231 @@ pub struct BspClipNode {
 
 // TODO: Borrow data
 pub struct BspReader {
+    variant: BspVariant,
     header: BspHeader,
     data: Vec<u8>,
+    planes: Vec<BspPlane>,
+    vertices: Vec<BspVertex>,
+    nodes: Vec<BspNode>,
+    texture_infos: Vec<BspTextureInfo>,
+    faces: Vec<BspFace>,
+    clip_nodes: Vec<BspClipNode>,
+    leaves: Vec<BspLeaf>,
+    mark_surfaces: Vec<BspMarkSurface>,
+    edges: Vec<BspEdge>,
+    surface_edges: Vec<BspSurfaceEdge>,
+    models: Vec<BspModel>,
 }
 
 impl BspReader {
-    pub fn read(data: Vec<u8>) -> Self {
-        let header: BspHeader = bincode::deserialize(&data).unwrap();
-        assert_eq!(header.version, 30);
-        Self { header, data }
+    pub fn read(data: Vec<u8>) -> Result<Self, BspError> {
+        let mut header: BspHeader = bincode::deserialize(&data).map_err(BspError::InvalidHeader)?;
+        let mut variant = match header.version {
+            GOLDSRC_BSP_VERSION => BspVariant::GoldSrc,
+            QUAKE_BSP_VERSION => BspVariant::Quake,
+            _ => return Err(BspError::UnsupportedVersion(header.version)),
+        };
+        for (index, lump_header) in header.lumps.iter().enumerate() {
+            let end = lump_header.offset as i64 + lump_header.len as i64;
+            if lump_header.offset < 0 || lump_header.len < 0 || end > data.len() as i64 {
+                return Err(BspError::LumpOutOfBounds {
+                    lump: BspLump::from_value(index).unwrap(),
+                    offset: lump_header.offset,
+                    len: lump_header.len,
+                });
+            }
+        }
+
+        // From here on the lumps are always in the standard order
+        if variant == BspVariant::GoldSrc && is_blue_shift(&header, &data) {
+            variant = BspVariant::BlueShift;
+            header
+                .lumps
+                .swap(BspLump::Entities as usize, BspLump::Planes as usize);
+        }
+
+        let models: Vec<BspModel> = decode_lump(&header, &data, BspLump::Models)?;
+        // Everything assumes that the world model exists
+        if models.is_empty() {
+            return Err(BspError::LumpTooSmall {
+                lump: BspLump::Models,
+                len: 0,
+                expected: std::mem::size_of::<BspModel>(),
+            });
+        }
+
+        Ok(Self {
+            planes: decode_lump(&header, &data, BspLump::Planes)?,
+            vertices: decode_lump(&header, &data, BspLump::Vertices)?,
+            nodes: decode_lump(&header, &data, BspLump::Nodes)?,
+            texture_infos: decode_lump(&header, &data, BspLump::TextureInfo)?,
+            faces: decode_lump(&header, &data, BspLump::Faces)?,
+            clip_nodes: decode_lump(&header, &data, BspLump::ClipNodes)?,
+            leaves: decode_lump(&header, &data, BspLump::Leaves)?,
+            mark_surfaces: decode_lump(&header, &data, BspLump::MarkSurfaces)?,
+            edges: decode_lump(&header, &data, BspLump::Edges)?,
+            surface_edges: decode_lump(&header, &data, BspLump::SurfaceEdges)?,
+            models,
+            variant,
+            header,
+            data,
+        })
+    }
+
+    pub fn variant(&self) -> BspVariant {
+        self.variant
     }
 
     pub fn read_nodes(&self) -> &[BspNode] {
-        self.read_lump(LUMP_NODES)
+        &self.nodes
     }
 
     pub fn read_leaves(&self) -> &[BspLeaf] {
-        self.read_lump(LUMP_LEAVES)
+        &self.leaves
     }
 
     pub fn read_mark_surfaces(&self) -> &[BspMarkSurface] {
-        self.read_lump(LUMP_MARKSURFACES)
+        &self.mark_surfaces
     }
 
     pub fn read_faces(&self) -> &[BspFace] {
-        self.read_lump(LUMP_FACES)
+        &self.faces
     }
 
     pub fn read_edges(&self) -> &[BspEdge] {
-        self.read_lump(LUMP_EDGES)
+        &self.edges
     }
 
     pub fn read_surface_edges(&self) -> &[BspSurfaceEdge] {
-        self.read_lump(LUMP_SURFEDGES)
+        &self.surface_edges
     }
 
     pub fn read_vertices(&self) -> &[BspVertex] {
-        self.read_lump(LUMP_VERTICES)
+        &self.vertices
     }
 
-    pub fn read_textures<'a>(&'a self) -> BspTextureReader<'a> {
-        let raw_data = self.read_lump_raw(LUMP_TEXTURES);
+    pub fn read_textures<'a>(&'a self) -> Result<BspTextureReader<'a>, BspError> {
+        let raw_data = self.read_lump_raw(BspLump::Textures);
 
-        let header: BspTextureHeader = bincode::deserialize(&raw_data).unwrap();
+        let header = self.read_textures_header()?;
         let offsets_start = std::mem::size_of::<BspTextureHeader>();
         let offsets_end =
-            offsets_start + (std::mem::size_of::<i32>() * header.num_textures as usize);
-        let offsets_data = &raw_data[offsets_start..offsets_end];
-        let offsets = unsafe {
-            let ptr = offsets_data.as_ptr() as *const i32;
-            std::slice::from_raw_parts(ptr, header.num_textures as usize)
-        };
+            offsets_start as u64 + (std::mem::size_of::<i32>() as u64 * header.num_textures as u64);
+        if offsets_end > raw_data.len() as u64 {
+            return Err(BspError::LumpTooSmall {
+                lump: BspLump::Textures,
+                len: raw_data.len(),
+                expected: offsets_end as usize,
+            });
+        }
+        let offsets = raw_data[offsets_start..offsets_end as usize]
+            .chunks_exact(std::mem::size_of::<i32>())
+            .map(|offset| i32::from_le_bytes(offset.try_into().unwrap()))
+            .collect();
+
+        Ok(BspTextureReader::new(self.variant, offsets, raw_data))
+    }
+
+    // Copies every embedded texture into a new WAD, keeping its palette and
+    // mip levels as they are. Quake textures don't have their own palette,
+    // the given one is used for them instead.
+    pub fn extract_textures(&self, palette: Option<&[u8]>) -> Result<WadWriter, BspError> {
+        let texture_reader = self.read_textures()?;
+        let mut writer = WadWriter::new();
+        for (index, offset) in texture_reader.offsets.iter().enumerate() {
+            if *offset < 0 {
+                continue;
+            }
+            let texture = texture_reader.get(index)?;
+            if !texture.has_local_image_data() {
+                continue;
+            }
 
-        BspTextureReader::new(offsets, raw_data)
+            let invalid_texture = || BspError::InvalidTextureData { index };
+            let name = texture.get_image_name()?;
+            let texture_palette = match texture.read_palette() {
+                Some(palette_reader) => palette_reader.raw_data(),
+                None => palette.ok_or_else(invalid_texture)?,
+            };
+            let mut mip_levels = [&[][..]; 4];
+            for (level, mip_level) in mip_levels.iter_mut().enumerate() {
+                *mip_level = texture.get_image(level).ok_or_else(invalid_texture)?.data;
+            }
+            writer
+                .add_indexed_mipmapped_image(
+                    name,
+                    texture.header().width,
+                    texture.header().height,
+                    mip_levels,
+                    texture_palette,
+                )
+                .map_err(|_| invalid_texture())?;
+        }
+        Ok(writer)
     }
 
-    pub fn read_textures_header(&self) -> BspTextureHeader {
-        let raw_data = self.read_lump_raw(LUMP_TEXTURES);
+    pub fn read_textures_header(&self) -> Result<BspTextureHeader, BspError> {
+        let raw_data = self.read_lump_raw(BspLump::Textures);
+        if raw_data.len() < std::mem::size_of::<BspTextureHeader>() {
+            return Err(BspError::LumpTooSmall {
+                lump: BspLump::Textures,
+                len: raw_data.len(),
+                expected: std::mem::size_of::<BspTextureHeader>(),
+            });
+        }
 
-        let header: BspTextureHeader = bincode::deserialize(&raw_data).unwrap();
-        header
+        bincode::deserialize(raw_data).map_err(|source| BspError::InvalidLump {
+            lump: BspLump::Textures,
+            source,
+        })
     }
 
     pub fn read_texture_infos(&self) -> &[BspTextureInfo] {
-        self.read_lump(LUMP_TEXINFO)
+        &self.texture_infos
     }
 
     pub fn read_planes(&self) -> &[BspPlane] {
-        self.read_lump(LUMP_PLANES)
+        &self.planes
     }
 
-    pub fn read_entities(&self) -> &str {
-        null_terminated_bytes_to_str(self.read_lump(LUMP_ENTITIES))
+    pub fn read_entities(&self) -> Result<&str, BspError> {
+        null_terminated_bytes_to_str(self.read_lump_raw(BspLump::Entities))
+            .map_err(BspError::InvalidEntities)
     }
 
     pub fn read_models(&self) -> &[BspModel] {
-        self.read_lump(LUMP_MODELS)
+        &self.models
     }
 
     pub fn read_clip_nodes(&self) -> &[BspClipNode] {
-        self.read_lump(LUMP_CLIPNODES)
+        &self.clip_nodes
+    }
+
+    pub fn read_lighting(&self) -> &[u8] {
+        self.read_lump_raw(BspLump::Lighting)
+    }
+
+    // Mirrors CalcSurfaceExtents from the engine. Each luxel covers 16x16
+    // texels, so the texture space bounds of the face get snapped to that grid.
+    pub fn read_lightmap_extents(&self, face_index: usize) -> Result<BspLightmapExtents, BspError> {
+        let invalid_face = || BspError::InvalidFace { index: face_index };
+        let face = self.faces.get(face_index).ok_or_else(invalid_face)?;
+        if face.edges < 3 {
+            return Err(invalid_face());
+        }
+        let texture_info = self
+            .texture_infos
+            .get(face.texture_info as usize)
+            .ok_or_else(invalid_face)?;
+
+        let mut mins = [f64::MAX; 2];
+        let mut maxs = [f64::MIN; 2];
+        for vertex in self.face_vertices(face_index)? {
+            for (axis, (vector, shift)) in [
+                (texture_info.s, texture_info.s_shift),
+                (texture_info.t, texture_info.t_shift),
+            ]
+            .iter()
+            .enumerate()
+            {
+                let value = vertex[0] as f64 * vector[0] as f64
+                    + vertex[1] as f64 * vector[1] as f64
+                    + vertex[2] as f64 * vector[2] as f64
+                    + *shift as f64;
+                mins[axis] = mins[axis].min(value);
+                maxs[axis] = maxs[axis].max(value);
+            }
+        }
+
+        let invalid_extents = || BspError::InvalidLightmapExtents { index: face_index };
+        let mut texture_mins = [0i32; 2];
+        let mut size = [0u32; 2];
+        // Casting would saturate, so values that don't fit are rejected first
+        let to_luxel = |value: f64| {
+            (i32::MIN as f64..=i32::MAX as f64)
+                .contains(&value)
+                .then_some(value as i32)
+                .ok_or_else(invalid_extents)
+        };
+        for axis in 0..2 {
+            let min = to_luxel((mins[axis] / 16.0).floor())?;
+            let max = to_luxel((maxs[axis] / 16.0).ceil())?;
+            texture_mins[axis] = min.checked_mul(16).ok_or_else(invalid_extents)?;
+            size[axis] = max
+                .checked_sub(min)
+                .and_then(|len| u32::try_from(len).ok())
+                .and_then(|len| len.checked_add(1))
+                .ok_or_else(invalid_extents)?;
+        }
+
+        Ok(BspLightmapExtents {
+            texture_mins,
+            width: size[0],
+            height: size[1],
+        })
+    }
+
+    // Returns the positions of the face's vertices in winding order. Edges
+    // are walked forwards for positive surface edges, and backwards for
+    // negative ones.
+    fn face_vertices(&self, face_index: usize) -> Result<Vec<[f32; 3]>, BspError> {
+        let invalid_face = || BspError::InvalidFace { index: face_index };
+        let face = self.faces.get(face_index).ok_or_else(invalid_face)?;
+        let first_edge = face.first_edge as usize;
+        let surface_edges = self
+            .surface_edges
+            .get(first_edge..first_edge + face.edges as usize)
+            .ok_or_else(invalid_face)?;
+
+        let mut positions = Vec::with_capacity(surface_edges.len());
+        for surface_edge in surface_edges {
+            let edge = self
+                .edges
+                .get(surface_edge.0.unsigned_abs() as usize)
+                .ok_or_else(invalid_face)?;
+            let vertex_index = if surface_edge.0 >= 0 {
+                edge.vertices[0]
+            } else {
+                edge.vertices[1]
+            };
+            let vertex = self
+                .vertices
+                .get(vertex_index as usize)
+                .ok_or_else(invalid_face)?;
+            positions.push(vertex.to_array());
+        }
+        Ok(positions)
+    }
+
+    pub fn read_lightmap(&self, face_index: usize) -> Option<BspLightmap<'_>> {
+        let face = self.read_faces().get(face_index)?;
+        if face.lightmap_offset < 0 || face.styles[0] == BspLightmap::NO_STYLE {
+            return None;
+        }
+        let texture_info = self.read_texture_infos().get(face.texture_info as usize)?;
+        if texture_info.flags & TEXTURE_FLAG_SPECIAL != 0 {
+            return None;
+        }
+
+        let extents = self.read_lightmap_extents(face_index).ok()?;
+        if extents.width > MAX_LIGHTMAP_SIZE || extents.height > MAX_LIGHTMAP_SIZE {
+            return None;
+        }
+        let style_count = face
+            .styles
+            .iter()
+            .take_while(|style| **style != BspLightmap::NO_STYLE)
+            .count();
+        let sample_size = self.variant.lighting_sample_size();
+        let len = extents.sample_count() * sample_size * style_count;
+        let start = face.lightmap_offset as usize;
+        let data = self.read_lighting().get(start..start + len)?;
+        let data = if sample_size == 1 {
+            Cow::Owned(data.iter().flat_map(|value| [*value; 3]).collect())
+        } else {
+            Cow::Borrowed(data)
+        };
+
+        Some(BspLightmap {
+            extents,
+            styles: face.styles,
+            data,
+        })
+    }
+
+    // Returns the polygon of the face, with its vertices in winding order.
+    // The texture reader is taken as is so the texture lump is only parsed
+    // once when reading many faces. A face whose texture or lightmap extents
+    // can't be read still has its polygon, see BspFaceGeometry.
+    pub fn read_face_geometry<'a>(
+        &'a self,
+        face_index: usize,
+        texture_reader: &BspTextureReader<'a>,
+    ) -> Result<BspFaceGeometry<'a>, BspError> {
+        let invalid_face = || BspError::InvalidFace { index: face_index };
+        let face = self.faces.get(face_index).ok_or_else(invalid_face)?;
+        let plane = self
+            .planes
+            .get(face.plane as usize)
+            .ok_or_else(invalid_face)?;
+        let texture_info = self
+            .texture_infos
+            .get(face.texture_info as usize)
+            .ok_or_else(invalid_face)?;
+        let positions = self.face_vertices(face_index)?;
+
+        let texture_index = texture_info.texture_index as usize;
+        let texture = texture_reader
+            .get(texture_index)
+            .and_then(|texture| {
+                let header = texture.header();
+                let size = [header.width.max(1) as f32, header.height.max(1) as f32];
+                Ok((texture.get_image_name()?, size))
+            })
+            .ok();
+        let texture_name = texture.map(|(name, _)| name);
+        let texture_size = texture.map_or([1.0; 2], |(_, size)| size);
+
+        let lightmap_extents = self.read_lightmap_extents(face_index).ok();
+        let (lightmap_mins, lightmap_size) =
+            lightmap_extents.map_or(([0.0; 2], [1.0; 2]), |extents| {
+                (
+                    extents.texture_mins.map(|min| min as f32),
+                    [extents.width as f32, extents.height as f32],
+                )
+            });
+
+        let dot = |a: [f32; 3], b: [f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+        let vertices = positions
+            .into_iter()
+            .map(|position| {
+                let texture_coord = [
+                    dot(position, texture_info.s) + texture_info.s_shift,
+                    dot(position, texture_info.t) + texture_info.t_shift,
+                ];
+                // Each luxel covers 16x16 texels, sample the center of each one
+                let lightmap_uv = [0, 1].map(|axis| {
+                    ((texture_coord[axis] - lightmap_mins[axis]) / 16.0 + 0.5) / lightmap_size[axis]
+                });
+                BspFaceVertex {
+                    position,
+                    texture_coord,
+                    uv: [
+                        texture_coord[0] / texture_size[0],
+                        texture_coord[1] / texture_size[1],
+                    ],
+                    lightmap_uv,
+                }
+            })
+            .collect();
+
+        let normal = if face.plane_side != 0 {
+            plane.normal.map(|value| -value)
+        } else {
+            plane.normal
+        };
+
+        Ok(BspFaceGeometry {
+            face_index,
+            normal,
+            texture_index,
+            texture_name,
+            lightmap_extents,
+            vertices,
+        })
+    }
+
+    pub fn iter_face_geometry(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<BspFaceGeometry<'_>, BspError>> + '_, BspError> {
+        let texture_reader = self.read_textures()?;
+        Ok((0..self.faces.len())
+            .map(move |face_index| self.read_face_geometry(face_index, &texture_reader)))
+    }
+
+    pub fn read_visibility(&self) -> &[u8] {
+        self.read_lump_raw(BspLump::Visibility)
+    }
+
+    // Walks the world's node tree and returns the index of the leaf that
+    // contains the point. Leaf 0 is the shared solid leaf.
+    pub fn find_leaf(&self, point: [f32; 3]) -> Result<usize, BspError> {
+        let mut node_index = self.models[0].head_nodes[0] as i16;
+        for _ in 0..=self.nodes.len() {
+            if node_index < 0 {
+                let leaf_index = !node_index as usize;
+                if leaf_index >= self.leaves.len() {
+                    return Err(BspError::InvalidLeaf(leaf_index));
+                }
+                return Ok(leaf_index);
+            }
+            let (node, plane) = self.world_node(node_index)?;
+            let dist = point[0] * plane.normal[0]
+                + point[1] * plane.normal[1]
+                + point[2] * plane.normal[2]
+                - plane.dist;
+            node_index = if dist > 0.0 {
+                node.children[0]
+            } else {
+                node.children[1]
+            };
+        }
+        // The path through a valid tree can't be longer than its node count
+        Err(BspError::InvalidHullNode {
+            hull: 0,
+            node: node_index as i32,
+        })
+    }
+
+    // Returns every world leaf that the box touches.
+    pub fn find_leaves_in_box(
+        &self,
+        mins: [f32; 3],
+        maxs: [f32; 3],
+    ) -> Result<Vec<usize>, BspError> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![self.models[0].head_nodes[0] as i16];
+        let mut visited = 0;
+        while let Some(node_index) = stack.pop() {
+            if node_index < 0 {
+                let leaf_index = !node_index as usize;
+                if leaf_index >= self.leaves.len() {
+                    return Err(BspError::InvalidLeaf(leaf_index));
+                }
+                if leaf_index != 0 {
+                    leaves.push(leaf_index);
+                }
+                continue;
+            }
+            // Each node of a valid tree is only reached once
+            visited += 1;
+            if visited > self.nodes.len() {
+                return Err(BspError::InvalidHullNode {
+                    hull: 0,
+                    node: node_index as i32,
+                });
+            }
+            let (node, plane) = self.world_node(node_index)?;
+            let mut near = -plane.dist;
+            let mut far = -plane.dist;
+            for axis in 0..3 {
+                if plane.normal[axis] >= 0.0 {
+                    near += plane.normal[axis] * mins[axis];
+                    far += plane.normal[axis] * maxs[axis];
+                } else {
+                    near += plane.normal[axis] * maxs[axis];
+                    far += plane.normal[axis] * mins[axis];
+                }
+            }
+            if far >= 0.0 {
+                stack.push(node.children[0]);
+            }
+            if near < 0.0 {
+                stack.push(node.children[1]);
+            }
+        }
+        Ok(leaves)
+    }
+
+    fn world_node(&self, node_index: i16) -> Result<(&BspNode, &BspPlane), BspError> {
+        let invalid_node = || BspError::InvalidHullNode {
+            hull: 0,
+            node: node_index as i32,
+        };
+        let node = self
+            .nodes
+            .get(node_index as usize)
+            .ok_or_else(invalid_node)?;
+        let plane = self
+            .planes
+            .get(node.plane as usize)
+            .ok_or_else(invalid_node)?;
+        Ok((node, plane))
+    }
+
+    // Hull 0 is the world's node tree, hulls 1 to 3 are the clip node trees
+    // the compiler expanded for the standing, large and crouching player
+    // sizes. Positions are in Half-Life coordinates.
+    pub fn point_contents(
+        &self,
+        model: usize,
+        hull: usize,
+        position: [f32; 3],
+    ) -> Result<BspContents, BspError> {
+        let hull = self.hull(model, hull)?;
+        let contents = self.hull_point_contents(hull, hull.head_node, position)?;
+        BspContents::from_value(contents).ok_or(BspError::InvalidContents(contents))
+    }
+
+    // Mirrors the engine's SV_RecursiveHullCheck. Model 0 is the world, the
+    // others are brush entities. Positions are in the model's space.
+    pub fn trace(
+        &self,
+        model: usize,
+        hull: usize,
+        start: [f32; 3],
+        end: [f32; 3],
+    ) -> Result<BspTrace, BspError> {
+        let hull = self.hull(model, hull)?;
+        let mut trace = BspTrace {
+            all_solid: true,
+            start_solid: false,
+            in_open: false,
+            in_water: false,
+            fraction: 1.0,
+            end_position: end,
+            plane: None,
+            contents: BspContents::Empty,
+        };
+        let mut visited = 0;
+        self.recursive_hull_check(
+            hull,
+            hull.head_node,
+            0.0,
+            1.0,
+            start,
+            end,
+            &mut trace,
+            &mut visited,
+        )?;
+        if trace.all_solid {
+            trace.start_solid = true;
+        }
+        let contents = self.hull_point_contents(hull, hull.head_node, trace.end_position)?;
+        trace.contents =
+            BspContents::from_value(contents).ok_or(BspError::InvalidContents(contents))?;
+        Ok(trace)
+    }
+
+    fn hull(&self, model: usize, hull: usize) -> Result<BspHull, BspError> {
+        let model = self
+            .models
+            .get(model)
+            .ok_or(BspError::InvalidModel(model))?;
+        let head_node = *model
+            .head_nodes
+            .get(hull)
+            .ok_or(BspError::InvalidHull(hull))?;
+        let node_count = if hull == 0 {
+            self.nodes.len()
+        } else {
+            self.clip_nodes.len()
+        };
+        Ok(BspHull {
+            index: hull,
+            head_node,
+            node_count,
+        })
+    }
+
+    // Returns the node's plane and its children. Negative children are
+    // contents values, the leaves of hull 0 are resolved to their contents.
+    fn hull_node(&self, hull: BspHull, node_index: i32) -> Result<(&BspPlane, [i32; 2]), BspError> {
+        let invalid_node = || BspError::InvalidHullNode {
+            hull: hull.index,
+            node: node_index,
+        };
+        let (plane_index, children) = if hull.index == 0 {
+            let node = self
+                .nodes
+                .get(node_index as usize)
+                .ok_or_else(invalid_node)?;
+            let mut children = [0; 2];
+            for (child, node_child) in children.iter_mut().zip(node.children) {
+                *child = if node_child >= 0 {
+                    node_child as i32
+                } else {
+                    let leaf = self
+                        .leaves
+                        .get(!node_child as usize)
+                        .ok_or_else(invalid_node)?;
+                    leaf.contents
+                };
+            }
+            (node.plane as usize, children)
+        } else {
+            let node = self
+                .clip_nodes
+                .get(node_index as usize)
+                .ok_or_else(invalid_node)?;
+            (
+                usize::try_from(node.plane_index).map_err(|_| invalid_node())?,
+                node.children.map(|child| child as i32),
+            )
+        };
+        let plane = self.planes.get(plane_index).ok_or_else(invalid_node)?;
+        Ok((plane, children))
+    }
+
+    fn hull_point_contents(
+        &self,
+        hull: BspHull,
+        mut node_index: i32,
+        position: [f32; 3],
+    ) -> Result<i32, BspError> {
+        for _ in 0..=hull.node_count {
+            if node_index < 0 {
+                return Ok(node_index);
+            }
+            let (plane, children) = self.hull_node(hull, node_index)?;
+            node_index = if plane.distance_to(position) < 0.0 {
+                children[1]
+            } else {
+                children[0]
+            };
+        }
+        // The path through a valid tree can't be longer than its node count
+        Err(BspError::InvalidHullNode {
+            hull: hull.index,
+            node: node_index,
+        })
+    }
+
+    // Returns false once the trace has been stopped by solid contents.
+    #[allow(clippy::too_many_arguments)]
+    fn recursive_hull_check(
+        &self,
+        hull: BspHull,
+        node_index: i32,
+        start_fraction: f32,
+        end_fraction: f32,
+        start: [f32; 3],
+        end: [f32; 3],
+        trace: &mut BspTrace,
+        visited: &mut usize,
+    ) -> Result<bool, BspError> {
+        if node_index < 0 {
+            if node_index == BspContents::Solid as i32 {
+                trace.start_solid = true;
+            } else {
+                trace.all_solid = false;
+                if node_index == BspContents::Empty as i32 {
+                    trace.in_open = true;
+                } else {
+                    trace.in_water = true;
+                }
+            }
+            return Ok(true);
+        }
+
+        // Each node of a valid tree is only reached once
+        *visited += 1;
+        if *visited > hull.node_count {
+            return Err(BspError::InvalidHullNode {
+                hull: hull.index,
+                node: node_index,
+            });
+        }
+        let (plane, children) = self.hull_node(hull, node_index)?;
+        let t1 = plane.distance_to(start);
+        let t2 = plane.distance_to(end);
+        if t1 >= 0.0 && t2 >= 0.0 {
+            return self.recursive_hull_check(
+                hull,
+                children[0],
+                start_fraction,
+                end_fraction,
+                start,
+                end,
+                trace,
+                visited,
+            );
+        }
+        if t1 < 0.0 && t2 < 0.0 {
+            return self.recursive_hull_check(
+                hull,
+                children[1],
+                start_fraction,
+                end_fraction,
+                start,
+                end,
+                trace,
+                visited,
+            );
+        }
+
+        // Put the crossing point on the near side of the plane
+        let mut fraction = if t1 < 0.0 {
+            (t1 + DIST_EPSILON) / (t1 - t2)
+        } else {
+            (t1 - DIST_EPSILON) / (t1 - t2)
+        }
+        .clamp(0.0, 1.0);
+        let lerp = |fraction: f32| {
+            let mid_fraction = start_fraction + (end_fraction - start_fraction) * fraction;
+            let mid = [0, 1, 2].map(|axis| start[axis] + fraction * (end[axis] - start[axis]));
+            (mid_fraction, mid)
+        };
+        let (mut mid_fraction, mut mid) = lerp(fraction);
+
+        let side = (t1 < 0.0) as usize;
+        if !self.recursive_hull_check(
+            hull,
+            children[side],
+            start_fraction,
+            mid_fraction,
+            start,
+            mid,
+            trace,
+            visited,
+        )? {
+            return Ok(false);
+        }
+
+        if self.hull_point_contents(hull, children[side ^ 1], mid)? != BspContents::Solid as i32 {
+            return self.recursive_hull_check(
+                hull,
+                children[side ^ 1],
+                mid_fraction,
+                end_fraction,
+                mid,
+                end,
+                trace,
+                visited,
+            );
+        }
+
+        // Never got out of the solid area
+        if trace.all_solid {
+            return Ok(false);
+        }
+
+        // The other side of the node is solid, this is the impact point
+        trace.plane = Some(if side == 0 {
+            BspTracePlane {
+                normal: plane.normal,
+                dist: plane.dist,
+            }
+        } else {
+            BspTracePlane {
+                normal: plane.normal.map(|value| -value),
+                dist: -plane.dist,
+            }
+        });
+
+        // Backing off by the epsilon can still leave us in solid
+        while self.hull_point_contents(hull, hull.head_node, mid)? == BspContents::Solid as i32 {
+            fraction -= 0.1;
+            if fraction < 0.0 {
+                break;
+            }
+            (mid_fraction, mid) = lerp(fraction);
+        }
+
+        trace.fraction = mid_fraction;
+        trace.end_position = mid;
+        Ok(false)
+    }
+
+    // Decompresses the potentially visible set of the given leaf. Maps
+    // compiled without vis (and the solid leaf) see everything.
+    pub fn read_visible_leaves(&self, leaf_index: usize) -> Result<BspVisibleLeaves, BspError> {
+        let leaf = self
+            .leaves
+            .get(leaf_index)
+            .ok_or(BspError::InvalidLeaf(leaf_index))?;
+        let leaf_count = self.leaves.len();
+        // The vis data only covers the leaves of the world model, and
+        // doesn't include the solid leaf.
+        let vis_leaves =
+            (self.models[0].vis_leaves.max(0) as usize).min(leaf_count.saturating_sub(1));
+        let row_len = vis_leaves.div_ceil(8);
+
+        let visibility = self.read_visibility();
+        let vis_offset = leaf.vis_offset;
+        if leaf_index == 0 || vis_offset < 0 || visibility.is_empty() {
+            return Ok(BspVisibleLeaves::all(vis_leaves));
+        }
+
+        // Runs of zero bytes are stored as a zero followed by the run length.
+        let mut bits = Vec::with_capacity(row_len);
+        let mut data = visibility.iter().skip(vis_offset as usize);
+        while bits.len() < row_len {
+            match data.next() {
+                Some(0) => {
+                    let run = *data.next().unwrap_or(&0) as usize;
+                    bits.extend(std::iter::repeat_n(0, run.min(row_len - bits.len())));
+                }
+                Some(byte) => bits.push(*byte),
+                None => bits.resize(row_len, 0),
+            }
+        }
+
+        Ok(BspVisibleLeaves {
+            bits,
+            leaf_count: vis_leaves,
+        })
+    }
+
+    fn read_lump_raw(&self, lump: BspLump) -> &[u8] {
+        lump_data(&self.header, &self.data, lump)
+    }
+}
+
+// A standard map's planes lump is always a whole number of planes, and its
+// entities lump is text.
+fn is_blue_shift(header: &BspHeader, data: &[u8]) -> bool {
+    let entities = lump_data(header, data, BspLump::Entities);
+    let planes = lump_data(header, data, BspLump::Planes);
+    !planes.len().is_multiple_of(std::mem::size_of::<BspPlane>())
+        || (planes.first() == Some(&b'{') && entities.first() != Some(&b'{'))
+}
+
+// Lump bounds are validated when the file is read
+fn lump_data<'a>(header: &BspHeader, data: &'a [u8], lump: BspLump) -> &'a [u8] {
+    let lump_header = header.lumps[lump as usize];
+    let start = lump_header.offset as usize;
+    let end = start + lump_header.len as usize;
+    &data[start..end]
+}
+
+// Lumps aren't guaranteed to be aligned within the file, so records are
+// decoded field by field (as little endian) instead of being cast in place.
+fn decode_lump<T: DeserializeOwned>(
+    header: &BspHeader,
+    data: &[u8],
+    lump: BspLump,
+) -> Result<Vec<T>, BspError> {
+    let lump_data = lump_data(header, data, lump);
+    let record_size = std::mem::size_of::<T>();
+    if !lump_data.len().is_multiple_of(record_size) {
+        return Err(BspError::InvalidLumpSize {
+            lump,
+            len: lump_data.len(),
+            record_size,
+        });
+    }
+    lump_data
+        .chunks_exact(record_size)
+        .map(|record| {
+            bincode::deserialize(record).map_err(|source| BspError::InvalidLump { lump, source })
+        })
+        .collect()
+}
+
+// Rebuilds a BSP file from its (possibly modified) lumps. The lumps are
+// written in order after the header, each padded to a 4 byte boundary.
+#[derive(Clone, Debug, Default)]
+pub struct BspWriter {
+    pub variant: BspVariant,
+    pub entities: String,
+    pub planes: Vec<BspPlane>,
+    // Each entry is a complete mip texture: the header followed by the image
+    // data and palette when the texture is embedded. Empty entries are
+    // written as missing textures.
+    pub textures: Vec<Vec<u8>>,
+    pub vertices: Vec<BspVertex>,
+    pub visibility: Vec<u8>,
+    pub nodes: Vec<BspNode>,
+    pub texture_infos: Vec<BspTextureInfo>,
+    pub faces: Vec<BspFace>,
+    pub lighting: Vec<u8>,
+    pub clip_nodes: Vec<BspClipNode>,
+    pub leaves: Vec<BspLeaf>,
+    pub mark_surfaces: Vec<BspMarkSurface>,
+    pub edges: Vec<BspEdge>,
+    pub surface_edges: Vec<BspSurfaceEdge>,
+    pub models: Vec<BspModel>,
+}
+
+impl BspWriter {
+    pub fn from_reader(reader: &BspReader) -> Result<Self, BspError> {
+        let mut textures = Vec::new();
+        if !reader.read_lump_raw(BspLump::Textures).is_empty() {
+            let texture_reader = reader.read_textures()?;
+            textures.reserve(texture_reader.len());
+            for i in 0..texture_reader.len() {
+                let texture = match texture_reader.get_raw_data(i) {
+                    Ok(data) => data.to_vec(),
+                    Err(BspError::TextureOutOfBounds { offset: -1, .. }) => Vec::new(),
+                    Err(error) => return Err(error),
+                };
+                textures.push(texture);
+            }
+        }
+
+        Ok(Self {
+            variant: reader.variant(),
+            entities: reader.read_entities()?.to_owned(),
+            planes: reader.read_planes().to_vec(),
+            textures,
+            vertices: reader.read_vertices().to_vec(),
+            visibility: reader.read_visibility().to_vec(),
+            nodes: reader.read_nodes().to_vec(),
+            texture_infos: reader.read_texture_infos().to_vec(),
+            faces: reader.read_faces().to_vec(),
+            lighting: reader.read_lighting().to_vec(),
+            clip_nodes: reader.read_clip_nodes().to_vec(),
+            leaves: reader.read_leaves().to_vec(),
+            mark_surfaces: reader.read_mark_surfaces().to_vec(),
+            edges: reader.read_edges().to_vec(),
+            surface_edges: reader.read_surface_edges().to_vec(),
+            models: reader.read_models().to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut entities = self.entities.as_bytes().to_vec();
+        entities.push(0);
+        // Must match the order of BspLump
+        let mut lumps = [
+            entities,
+            encode_records(&self.planes),
+            self.encode_textures(),
+            encode_records(&self.vertices),
+            self.visibility.clone(),
+            encode_records(&self.nodes),
+            encode_records(&self.texture_infos),
+            encode_records(&self.faces),
+            self.lighting.clone(),
+            encode_records(&self.clip_nodes),
+            encode_records(&self.leaves),
+            encode_records(&self.mark_surfaces),
+            encode_records(&self.edges),
+            encode_records(&self.surface_edges),
+            encode_records(&self.models),
+        ];
+        if self.variant == BspVariant::BlueShift {
+            lumps.swap(BspLump::Entities as usize, BspLump::Planes as usize);
+        }
+
+        let mut header = BspHeader {
+            version: self.variant.version(),
+            lumps: [BspLumpHeader { offset: 0, len: 0 }; HEADER_LUMPS],
+        };
+        let header_len = std::mem::size_of::<BspHeader>();
+        let mut data = vec![0u8; header_len];
+        for (lump_header, lump_data) in header.lumps.iter_mut().zip(&lumps) {
+            lump_header.offset = data.len() as i32;
+            lump_header.len = lump_data.len() as i32;
+            data.extend_from_slice(lump_data);
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+        data[..header_len].copy_from_slice(&encode_records(&[header]));
+        data
+    }
+
+    // Embeds the textures that are stored in external WADs. The lookup
+    // returns the WAD3 mip texture data of a texture by name, which already
+    // has the layout the texture lump uses. Returns the names of the
+    // textures that couldn't be embedded.
+    pub fn embed_textures<F: FnMut(&str) -> Option<Vec<u8>>>(
+        &mut self,
+        mut lookup: F,
+    ) -> Result<Vec<String>, BspError> {
+        let mut missing = Vec::new();
+        for (index, texture) in self.textures.iter_mut().enumerate() {
+            if texture.is_empty() {
+                continue;
+            }
+            let header: BspMipTextureHeader =
+                bincode::deserialize(texture).map_err(|source| BspError::InvalidLump {
+                    lump: BspLump::Textures,
+                    source,
+                })?;
+            if header.offsets[0] != 0 {
+                continue;
+            }
+            let name = null_terminated_bytes_to_str(&header.name)
+                .map_err(|source| BspError::InvalidTextureName { index, source })?;
+
+            let embedded = lookup(name).filter(|data| {
+                bincode::deserialize::<BspMipTextureHeader>(data).is_ok_and(|wad_header| {
+                    wad_header.width == header.width
+                        && wad_header.height == header.height
+                        && has_mip_levels(&wad_header, data.len())
+                })
+            });
+            match embedded {
+                Some(mut data) => {
+                    // Keep the name the map uses, WADs don't always match its case
+                    data[..header.name.len()].copy_from_slice(&header.name);
+                    *texture = data;
+                }
+                None => missing.push(name.to_owned()),
+            }
+        }
+        Ok(missing)
+    }
+
+    fn encode_textures(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(self.textures.len() as u32).to_le_bytes());
+        data.resize(
+            data.len() + self.textures.len() * std::mem::size_of::<i32>(),
+            0,
+        );
+        for (i, texture) in self.textures.iter().enumerate() {
+            let offset = if texture.is_empty() {
+                -1
+            } else {
+                data.len() as i32
+            };
+            let offset_start =
+                std::mem::size_of::<BspTextureHeader>() + i * std::mem::size_of::<i32>();
+            data[offset_start..offset_start + std::mem::size_of::<i32>()]
+                .copy_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(texture);
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+        data
+    }
+}
+
+// Checks that every mip level fits in the texture's data, with room for the
+// palette after the last one.
+fn has_mip_levels(header: &BspMipTextureHeader, len: usize) -> bool {
+    let mip_level_ends = BspMipTextureReader::MIP_LEVELS
+        .iter()
+        .zip(header.offsets)
+        .map(|(mip_level, offset)| {
+            let width = (header.width / *mip_level as u32) as u64;
+            let height = (header.height / *mip_level as u32) as u64;
+            (offset != 0).then_some(offset as u64 + width * height)
+        })
+        .collect::<Option<Vec<_>>>();
+    mip_level_ends.is_some_and(|ends| {
+        ends[3] + 2 + PALETTE_LEN as u64 <= len as u64 && ends.iter().all(|end| *end <= len as u64)
+    })
+}
+
+fn encode_records<T: Serialize>(records: &[T]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(std::mem::size_of_val(records));
+    for record in records {
+        // Serializing plain records into memory can't fail
+        bincode::serialize_into(&mut data, record).unwrap();
+    }
+    data
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BspLightmapExtents {
+    pub texture_mins: [i32; 2],
+    pub width: u32,
+    pub height: u32,
+}
+
+impl BspLightmapExtents {
+    pub fn sample_count(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+// One of the clipping hulls of a model
+#[derive(Copy, Clone, Debug)]
+struct BspHull {
+    index: usize,
+    head_node: i32,
+    // The number of nodes in the hull's tree
+    node_count: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BspTrace {
+    // The whole trace was inside solid contents
+    pub all_solid: bool,
+    pub start_solid: bool,
+    // Set when the trace passed through empty or liquid contents
+    pub in_open: bool,
+    pub in_water: bool,
+    // How much of the move was completed before hitting something
+    pub fraction: f32,
+    pub end_position: [f32; 3],
+    // The plane that was hit, facing the start of the trace
+    pub plane: Option<BspTracePlane>,
+    // The contents at the end position
+    pub contents: BspContents,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BspTracePlane {
+    pub normal: [f32; 3],
+    pub dist: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct BspFaceGeometry<'a> {
+    pub face_index: usize,
+    // Flipped for faces on the back of their plane
+    pub normal: [f32; 3],
+    pub texture_index: usize,
+    // None if the texture couldn't be read, the UVs are then in texels
+    pub texture_name: Option<&'a str>,
+    // None if the face has no valid lightmap, the lightmap UVs are then in
+    // luxels
+    pub lightmap_extents: Option<BspLightmapExtents>,
+    pub vertices: Vec<BspFaceVertex>,
+}
+
+impl BspFaceGeometry<'_> {
+    // Fans the polygon out into triangles, as indices into the vertices.
+    pub fn triangles(&self) -> impl Iterator<Item = [usize; 3]> {
+        (0..self.vertices.len().saturating_sub(2)).map(|i| [i + 2, i + 1, 0])
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BspFaceVertex {
+    pub position: [f32; 3],
+    // Unscaled texture space coordinates (in texels)
+    pub texture_coord: [f32; 2],
+    pub uv: [f32; 2],
+    // Relative to the face's own lightmap
+    pub lightmap_uv: [f32; 2],
+}
+
+// Quake's monochrome lighting is expanded to RGB.
+pub struct BspLightmap<'a> {
+    pub extents: BspLightmapExtents,
+    pub styles: [u8; 4],
+    data: Cow<'a, [u8]>,
+}
+
+impl<'a> BspLightmap<'a> {
+    pub const NO_STYLE: u8 = 255;
+
+    pub fn style_count(&self) -> usize {
+        self.data.len() / (self.extents.sample_count() * 3)
+    }
+
+    // Returns the light style and its RGB samples, row by row.
+    pub fn get_style(&self, index: usize) -> Option<(u8, &[[u8; 3]])> {
+        if index >= self.style_count() {
+            return None;
+        }
+        let len = self.extents.sample_count() * 3;
+        let (samples, _) = self.data[index * len..(index + 1) * len].as_chunks::<3>();
+        Some((self.styles[index], samples))
+    }
+
+    pub fn raw_data(&self) -> &[u8] {
+        &self.data
     }
+}
 
-    fn read_lump_raw(&self, index: usize) -> &[u8] {
-        let lump_header = self.header.lumps[index];
-        let start = lump_header.offset as usize;
-        let end = start + lump_header.len as usize;
-        let lump_data = &self.data[start..end];
-        lump_data
+// Bit N of the set corresponds to leaf N + 1.
+#[derive(Clone, Debug)]
+pub struct BspVisibleLeaves {
+    bits: Vec<u8>,
+    leaf_count: usize,
+}
+
+impl BspVisibleLeaves {
+    fn all(leaf_count: usize) -> Self {
+        let mut bits = vec![0xFF; leaf_count.div_ceil(8)];
+        if !leaf_count.is_multiple_of(8) {
+            if let Some(last) = bits.last_mut() {
+                *last = (1 << (leaf_count % 8)) - 1;
+            }
+        }
+        Self { bits, leaf_count }
     }
 
-    fn read_lump<T: Sized>(&self, index: usize) -> &[T] {
-        let lump_data = &self.read_lump_raw(index);
-        let len = lump_data.len() / std::mem::size_of::<T>();
-        unsafe {
-            let ptr = lump_data.as_ptr() as *const T;
-            std::slice::from_raw_parts(ptr, len)
+    pub fn contains(&self, leaf_index: usize) -> bool {
+        if leaf_index == 0 || leaf_index > self.leaf_count {
+            return false;
         }
+        let bit = leaf_index - 1;
+        self.bits[bit / 8] & (1 << (bit % 8)) != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (1..=self.leaf_count).filter(|leaf_index| self.contains(*leaf_index))
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn raw_bits(&self) -> &[u8] {
+        &self.bits
     }
 }
 
@@ -295,9 +1601,24 @@ pub trait FromValue<T: Sized + Copy>: Sized {
     fn from_value(value: T) -> Option<Self>;
 }
 
+impl BspPlane {
+    // Axial planes only look at their axis, like the engine does.
+    pub fn distance_to(&self, position: [f32; 3]) -> f32 {
+        match self.ty {
+            0..=2 => position[self.ty as usize] - self.dist,
+            _ => {
+                self.normal[0] * position[0]
+                    + self.normal[1] * position[1]
+                    + self.normal[2] * position[2]
+                    - self.dist
+            }
+        }
+    }
+}
+
 impl BspLeaf {
-    pub fn contents(&self) -> BspContents {
-        BspContents::from_value(self.contents).unwrap()
+    pub fn contents(&self) -> Result<BspContents, BspError> {
+        BspContents::from_value(self.contents).ok_or(BspError::InvalidContents(self.contents))
     }
 }
 
@@ -308,59 +1629,97 @@ impl BspVertex {
 }
 
 pub struct BspTextureReader<'a> {
-    offsets: &'a [i32],
+    variant: BspVariant,
+    offsets: Vec<i32>,
     lump_data: &'a [u8],
 }
 
 impl<'a> BspTextureReader<'a> {
-    fn new(offsets: &'a [i32], lump_data: &'a [u8]) -> Self {
-        Self { offsets, lump_data }
+    fn new(variant: BspVariant, offsets: Vec<i32>, lump_data: &'a [u8]) -> Self {
+        Self {
+            variant,
+            offsets,
+            lump_data,
+        }
     }
 
     pub fn len(&self) -> usize {
         self.offsets.len()
     }
 
-    pub fn get(&self, index: usize) -> Option<BspMipTextureReader<'a>> {
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Result<BspMipTextureReader<'a>, BspError> {
         let data = self.get_raw_data(index)?;
-        let header_ptr = data.as_ptr() as *const BspMipTextureHeader;
-        let header = unsafe { header_ptr.as_ref() }?;
-        Some(BspMipTextureReader::new(header, data))
+        let header = bincode::deserialize(data).map_err(|source| BspError::InvalidLump {
+            lump: BspLump::Textures,
+            source,
+        })?;
+        Ok(BspMipTextureReader::new(self.variant, index, header, data))
     }
 
-    fn get_raw_data(&self, index: usize) -> Option<&'a [u8]> {
-        let offset = *self.offsets.get(index)? as usize;
-        let end = *self
+    fn get_raw_data(&self, index: usize) -> Result<&'a [u8], BspError> {
+        let offset = *self.offsets.get(index).unwrap_or(&-1);
+        let out_of_bounds = BspError::TextureOutOfBounds { index, offset };
+        if offset < 0 {
+            return Err(out_of_bounds);
+        }
+        // Textures are usually stored in order, but that isn't guaranteed
+        let end = self
             .offsets
-            .get(index + 1)
-            .unwrap_or(&(self.lump_data.len() as i32)) as usize;
-        let data = &self.lump_data[offset..end];
-        Some(data)
+            .iter()
+            .filter(|end| **end > offset)
+            .min()
+            .map(|end| *end as usize)
+            .unwrap_or(self.lump_data.len())
+            .min(self.lump_data.len());
+        let data = self
+            .lump_data
+            .get(offset as usize..end)
+            .ok_or(out_of_bounds)?;
+        if data.len() < std::mem::size_of::<BspMipTextureHeader>() {
+            return Err(BspError::TextureOutOfBounds { index, offset });
+        }
+        Ok(data)
     }
 }
 
 pub struct BspMipTextureReader<'a> {
-    header: &'a BspMipTextureHeader,
+    variant: BspVariant,
+    index: usize,
+    header: BspMipTextureHeader,
     data: &'a [u8],
 }
 
 impl<'a> BspMipTextureReader<'a> {
     const MIP_LEVELS: [usize; 4] = [1, 2, 4, 8];
 
-    fn new(header: &'a BspMipTextureHeader, data: &'a [u8]) -> Self {
-        Self { header, data }
+    fn new(variant: BspVariant, index: usize, header: BspMipTextureHeader, data: &'a [u8]) -> Self {
+        Self {
+            variant,
+            index,
+            header,
+            data,
+        }
     }
 
     pub fn raw_data(&self) -> &[u8] {
-        &self.data
+        self.data
     }
 
     pub fn header(&self) -> &BspMipTextureHeader {
-        self.header
+        &self.header
     }
 
-    pub fn get_image_name(&self) -> &'a str {
-        null_terminated_bytes_to_str(&self.header.name)
+    pub fn get_image_name(&self) -> Result<&'a str, BspError> {
+        // Borrow the name from the texture's data rather than the decoded header
+        let name = &self.data[..self.header.name.len()];
+        null_terminated_bytes_to_str(name).map_err(|source| BspError::InvalidTextureName {
+            index: self.index,
+            source,
+        })
     }
 
     pub fn has_local_image_data(&self) -> bool {
@@ -380,11 +1739,52 @@ impl<'a> BspMipTextureReader<'a> {
         Some(BspBitmap::new(
             width,
             height,
-            &self.data[offset..offset + len],
+            self.data.get(offset..offset + len)?,
         ))
     }
 
-    pub fn read_palette(&self) -> BspPaletteReader<'a> {
+    // Decodes all four mip levels of an embedded texture.
+    pub fn decode(&self) -> Result<MipmapedTextureData, BspError> {
+        let palette_reader = self
+            .read_palette()
+            .ok_or(BspError::InvalidTextureData { index: self.index })?;
+        self.decode_with_palette(palette_reader.data)
+    }
+
+    // Quake textures have to be decoded with the game's palette.
+    pub fn decode_with_palette(
+        &self,
+        palette_data: &[u8],
+    ) -> Result<MipmapedTextureData, BspError> {
+        let decode_mip_level = |level| {
+            self.get_image(level)
+                .and_then(|bitmap| {
+                    decode_paletted_image(
+                        bitmap.data,
+                        palette_data,
+                        bitmap.width as u32,
+                        bitmap.height as u32,
+                        None,
+                    )
+                })
+                .ok_or(BspError::InvalidTextureData { index: self.index })
+        };
+
+        Ok(MipmapedTextureData {
+            image_width: self.header.width,
+            image_height: self.header.height,
+            image: decode_mip_level(0)?,
+            mipmap1: decode_mip_level(1)?,
+            mipmap2: decode_mip_level(2)?,
+            mipmap3: decode_mip_level(3)?,
+        })
+    }
+
+    // Quake textures use the game's shared palette (gfx/palette.lmp)
+    pub fn read_palette(&self) -> Option<BspPaletteReader<'a>> {
+        if self.variant == BspVariant::Quake {
+            return None;
+        }
         let last_image_offset = self.header.offsets[3] as usize;
         let mip_level = Self::MIP_LEVELS[3];
         let width = self.header.width as usize / mip_level;
@@ -394,7 +1794,10 @@ impl<'a> BspMipTextureReader<'a> {
         let palette_offset = last_image_offset + image_len + 2;
         let palette_len = 256 * 3;
 
-        BspPaletteReader::new(&self.data[palette_offset..palette_offset + palette_len])
+        Some(BspPaletteReader::new(
+            self.data
+                .get(palette_offset..palette_offset + palette_len)?,
+        ))
     }
 }
 
@@ -407,6 +1810,10 @@ impl<'a> BspPaletteReader<'a> {
         Self { data }
     }
 
+    pub fn raw_data(&self) -> &'a [u8] {
+        self.data
+    }
+
     pub fn get(&self, index: usize) -> BspPixel {
         let offset = index * 3;
         let data = &self.data[offset..offset + 3];
@@ -439,31 +1846,1074 @@ impl<'a> BspBitmap<'a> {
         }
     }
 
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn raw_data(&self) -> &'a [u8] {
+        self.data
+    }
+
     pub fn decode(&self, palette_reader: &BspPaletteReader<'a>) -> Vec<BspPixel> {
-        todo!()
+        self.data
+            .iter()
+            .map(|index| palette_reader.get(*index as usize))
+            .collect()
+    }
+}
+
+// Keys are kept in the order they appear in the lump, including repeated
+// keys.
+#[derive(Clone, Debug, Default)]
+pub struct BspEntity<'a>(pub Vec<(&'a str, &'a str)>);
+
+#[derive(Debug)]
+pub enum BspEntityError {
+    UnexpectedToken { line: usize, token: String },
+    UnterminatedString { line: usize },
+    MissingValue { line: usize, key: String },
+    UnclosedEntity { line: usize },
+}
+
+impl BspEntityError {
+    pub fn line(&self) -> usize {
+        match self {
+            BspEntityError::UnexpectedToken { line, .. }
+            | BspEntityError::UnterminatedString { line }
+            | BspEntityError::MissingValue { line, .. }
+            | BspEntityError::UnclosedEntity { line } => *line,
+        }
     }
 }
 
-pub struct BspEntity<'a>(pub HashMap<&'a str, &'a str>);
+impl Display for BspEntityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.line())?;
+        match self {
+            BspEntityError::UnexpectedToken { token, .. } => {
+                write!(f, "unexpected \"{}\"", token)
+            }
+            BspEntityError::UnterminatedString { .. } => write!(f, "unterminated string"),
+            BspEntityError::MissingValue { key, .. } => {
+                write!(f, "key \"{}\" is missing a value", key)
+            }
+            BspEntityError::UnclosedEntity { .. } => write!(f, "entity is never closed"),
+        }
+    }
+}
+
+impl std::error::Error for BspEntityError {}
 
 impl<'a> BspEntity<'a> {
-    pub fn parse_entities(source: &'a str) -> Vec<BspEntity<'a>> {
+    pub fn parse_entities(source: &'a str) -> Result<Vec<BspEntity<'a>>, BspEntityError> {
+        let mut tokenizer = EntityTokenizer::new(source);
         let mut entities = Vec::new();
-        let mut current_entity = None;
-        for line in source.lines() {
-            if line == "{" {
-                current_entity = Some(BspEntity(HashMap::new()));
-            } else if line == "}" {
-                let entity = current_entity.take().unwrap();
-                entities.push(entity);
+        while let Some((token, line)) = tokenizer.next_token()? {
+            if token != EntityToken::OpenBrace {
+                return Err(token.unexpected(line));
+            }
+            let mut entity = BspEntity::default();
+            loop {
+                match tokenizer.next_token()? {
+                    Some((EntityToken::CloseBrace, _)) => break,
+                    Some((EntityToken::String(key), key_line)) => match tokenizer.next_token()? {
+                        Some((EntityToken::String(value), _)) => entity.0.push((key, value)),
+                        _ => {
+                            return Err(BspEntityError::MissingValue {
+                                line: key_line,
+                                key: key.to_owned(),
+                            })
+                        }
+                    },
+                    Some((token, line)) => return Err(token.unexpected(line)),
+                    None => return Err(BspEntityError::UnclosedEntity { line }),
+                }
+            }
+            entities.push(entity);
+        }
+        Ok(entities)
+    }
+
+    // Serializes the entities the same way the map compilers write them.
+    pub fn write_entities(entities: &[BspEntity]) -> String {
+        entities.iter().map(|entity| entity.to_string()).collect()
+    }
+
+    // Like the engine, the last value wins when a key is repeated.
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.get_all(key).last()
+    }
+
+    pub fn get_all<'b>(&'b self, key: &'b str) -> impl DoubleEndedIterator<Item = &'a str> + 'b {
+        self.0
+            .iter()
+            .filter(move |(entry_key, _)| *entry_key == key)
+            .map(|(_, value)| *value)
+    }
+}
+
+impl Display for BspEntity<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{{")?;
+        for (key, value) in &self.0 {
+            writeln!(f, "\"{}\" \"{}\"", key, value)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum EntityToken<'a> {
+    OpenBrace,
+    CloseBrace,
+    String(&'a str),
+}
+
+impl EntityToken<'_> {
+    fn unexpected(&self, line: usize) -> BspEntityError {
+        let token = match self {
+            EntityToken::OpenBrace => "{",
+            EntityToken::CloseBrace => "}",
+            EntityToken::String(value) => value,
+        };
+        BspEntityError::UnexpectedToken {
+            line,
+            token: token.to_owned(),
+        }
+    }
+}
+
+// Follows the rules of the engine's COM_Parse: quoted strings are taken as
+// is, braces are tokens of their own and "//" starts a comment.
+struct EntityTokenizer<'a> {
+    source: &'a str,
+    position: usize,
+    line: usize,
+}
+
+impl<'a> EntityTokenizer<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            position: 0,
+            line: 1,
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<(EntityToken<'a>, usize)>, BspEntityError> {
+        self.skip_whitespace_and_comments();
+        let rest = &self.source[self.position..];
+        let line = self.line;
+        let token = match rest.chars().next() {
+            // The lump is null terminated
+            None | Some('\0') => return Ok(None),
+            Some('{') => {
+                self.position += 1;
+                EntityToken::OpenBrace
+            }
+            Some('}') => {
+                self.position += 1;
+                EntityToken::CloseBrace
+            }
+            Some('"') => {
+                let len = rest[1..]
+                    .find('"')
+                    .ok_or(BspEntityError::UnterminatedString { line })?;
+                let value = &rest[1..len + 1];
+                self.line += value.matches('\n').count();
+                self.position += len + 2;
+                EntityToken::String(value)
+            }
+            Some(_) => {
+                let len = rest
+                    .find(|c: char| c.is_whitespace() || matches!(c, '{' | '}' | '"'))
+                    .unwrap_or(rest.len());
+                self.position += len;
+                EntityToken::String(&rest[..len])
+            }
+        };
+        Ok(Some((token, line)))
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        loop {
+            let rest = &self.source[self.position..];
+            if rest.starts_with("//") {
+                self.position += rest.find('\n').unwrap_or(rest.len());
+            } else if let Some(c) = rest.chars().next().filter(|c| c.is_whitespace()) {
+                if c == '\n' {
+                    self.line += 1;
+                }
+                self.position += c.len_utf8();
             } else {
-                let entity = current_entity.as_mut().unwrap();
-                let mut split = line.split("\" \"");
-                let key = split.next().unwrap().trim_matches('\"');
-                let value = split.next().unwrap().trim_matches('\"');
-                entity.0.insert(&key, value);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad3::WadArchive;
+
+    // Builds maps lump by lump, laid out the way the compile tools write them
+    #[derive(Default)]
+    struct TestMap {
+        lumps: [Vec<u8>; HEADER_LUMPS],
+    }
+
+    impl TestMap {
+        fn push<T: Serialize>(&mut self, lump: BspLump, values: &[T]) -> &mut Self {
+            for value in values {
+                self.lumps[lump as usize].extend(bincode::serialize(value).unwrap());
+            }
+            self
+        }
+
+        // The textures lump is an offset table followed by the mip textures
+        fn textures(&mut self, textures: &[Vec<u8>]) -> &mut Self {
+            let lump = &mut self.lumps[BspLump::Textures as usize];
+            lump.extend((textures.len() as u32).to_le_bytes());
+            let mut offset = 4 + textures.len() * 4;
+            for texture in textures {
+                lump.extend((offset as i32).to_le_bytes());
+                offset += texture.len();
+            }
+            textures.iter().for_each(|texture| lump.extend(texture));
+            self
+        }
+
+        // A polygon on the z = 0 plane, with one edge per side
+        fn polygon(&mut self, corners: &[[f32; 2]]) -> &mut Self {
+            let first_vertex =
+                self.lumps[BspLump::Vertices as usize].len() / std::mem::size_of::<BspVertex>();
+            let first_edge =
+                self.lumps[BspLump::Edges as usize].len() / std::mem::size_of::<BspEdge>();
+            for (i, [x, y]) in corners.iter().copied().enumerate() {
+                let next = (i + 1) % corners.len();
+                self.push(BspLump::Vertices, &[BspVertex { x, y, z: 0.0 }]);
+                self.push(
+                    BspLump::Edges,
+                    &[BspEdge {
+                        vertices: [(first_vertex + i) as u16, (first_vertex + next) as u16],
+                    }],
+                );
+                self.push(
+                    BspLump::SurfaceEdges,
+                    &[BspSurfaceEdge((first_edge + i) as i32)],
+                );
+            }
+            self
+        }
+
+        fn world_model(vis_leaves: i32) -> BspModel {
+            BspModel {
+                mins: [-64.0; 3],
+                maxs: [64.0; 3],
+                origin: [0.0; 3],
+                head_nodes: [0; 4],
+                vis_leaves,
+                first_face: 0,
+                faces: 0,
+            }
+        }
+
+        fn leaf(contents: BspContents, vis_offset: i32) -> BspLeaf {
+            BspLeaf {
+                contents: contents as i32,
+                vis_offset,
+                mins: [0; 3],
+                maxs: [0; 3],
+                first_mark_surface: 0,
+                mark_surfaces: 0,
+                ambient_levels: [0; 4],
+            }
+        }
+
+        fn node(plane: u32, children: [i16; 2]) -> BspNode {
+            BspNode {
+                plane,
+                children,
+                mins: [0; 3],
+                maxs: [0; 3],
+                first_face: 0,
+                faces: 0,
+            }
+        }
+
+        fn texture_info(s: [f32; 3], s_shift: f32, flags: u32) -> BspTextureInfo {
+            BspTextureInfo {
+                s,
+                s_shift,
+                t: [0.0, 1.0, 0.0],
+                t_shift: 0.0,
+                texture_index: 0,
+                flags,
+            }
+        }
+
+        // An embedded mip texture with its mip levels and palette
+        fn mip_texture(name: &str, width: u32, height: u32) -> Vec<u8> {
+            let mut data = vec![0u8; std::mem::size_of::<BspMipTextureHeader>()];
+            data[..name.len()].copy_from_slice(name.as_bytes());
+            data[16..20].copy_from_slice(&width.to_le_bytes());
+            data[20..24].copy_from_slice(&height.to_le_bytes());
+            for level in 0..4 {
+                let offset = data.len() as u32;
+                data[24 + level * 4..28 + level * 4].copy_from_slice(&offset.to_le_bytes());
+                let len = (width >> level) * (height >> level);
+                data.extend((0..len).map(|i| i as u8));
+            }
+            data.extend_from_slice(&256u16.to_le_bytes());
+            data.extend((0..768).map(|i| (i % 256) as u8));
+            data.extend_from_slice(&[0, 0]);
+            data
+        }
+
+        // A texture that only has a name and size, for textures in WADs
+        fn external_texture(name: &str, width: u32, height: u32) -> Vec<u8> {
+            let mut data = Self::mip_texture(name, width, height);
+            data.truncate(std::mem::size_of::<BspMipTextureHeader>());
+            data[24..40].fill(0);
+            data
+        }
+
+        fn face(
+            first_edge: u32,
+            edges: u16,
+            texture_info: u16,
+            styles: [u8; 4],
+            lightmap_offset: i32,
+        ) -> BspFace {
+            BspFace {
+                plane: 0,
+                plane_side: 0,
+                first_edge,
+                edges,
+                texture_info,
+                styles,
+                lightmap_offset,
+            }
+        }
+
+        fn to_bytes(&self) -> Vec<u8> {
+            self.to_bytes_with_alignment(4)
+        }
+
+        fn to_bytes_with_alignment(&self, alignment: usize) -> Vec<u8> {
+            let header_len = 4 + HEADER_LUMPS * 8;
+            let mut headers = Vec::new();
+            let mut lump_data = Vec::new();
+            for lump in &self.lumps {
+                headers.push([(header_len + lump_data.len()) as i32, lump.len() as i32]);
+                lump_data.extend_from_slice(lump);
+                lump_data.resize(lump_data.len().next_multiple_of(alignment), 0);
             }
+            let mut data = GOLDSRC_BSP_VERSION.to_le_bytes().to_vec();
+            data.extend(
+                headers
+                    .iter()
+                    .flatten()
+                    .flat_map(|value| value.to_le_bytes()),
+            );
+            data.extend(lump_data);
+            data
+        }
+
+        fn read(&self) -> BspReader {
+            BspReader::read(self.to_bytes()).unwrap()
+        }
+    }
+
+    #[test]
+    fn lightmaps_are_split_by_style() {
+        let mut map = TestMap::default();
+        map.push(BspLump::Models, &[TestMap::world_model(0)]);
+        map.polygon(&[[0.0, 0.0], [40.0, 0.0], [40.0, 20.0], [0.0, 20.0]]);
+        map.push(
+            BspLump::TextureInfo,
+            &[
+                TestMap::texture_info([1.0, 0.0, 0.0], 8.0, 0),
+                TestMap::texture_info([1.0, 0.0, 0.0], 8.0, TEXTURE_FLAG_SPECIAL),
+            ],
+        );
+        map.push(
+            BspLump::Faces,
+            &[
+                TestMap::face(0, 4, 0, [0, 5, 255, 255], 3),
+                TestMap::face(0, 4, 0, [255; 4], 3),
+                TestMap::face(0, 4, 1, [0, 255, 255, 255], 3),
+                TestMap::face(0, 4, 0, [0, 255, 255, 255], -1),
+                TestMap::face(0, 4, 0, [0, 255, 255, 255], 64),
+            ],
+        );
+        // Two styles of 4x3 samples, after some unrelated data
+        let mut lighting: Vec<u8> = vec![255; 3];
+        lighting.extend((0..12).flat_map(|i| [i; 3]));
+        lighting.extend((0..12).flat_map(|i| [100 + i; 3]));
+        map.push(BspLump::Lighting, &lighting);
+        let reader = map.read();
+
+        // The texture space bounds (8..48, 0..20) are snapped to whole luxels
+        let lightmap = reader.read_lightmap(0).unwrap();
+        assert_eq!(lightmap.extents.texture_mins, [0, 0]);
+        assert_eq!([lightmap.extents.width, lightmap.extents.height], [4, 3]);
+        assert_eq!(lightmap.style_count(), 2);
+        let (style, samples) = lightmap.get_style(0).unwrap();
+        assert_eq!(style, 0);
+        assert_eq!(samples.len(), 12);
+        assert_eq!(samples[5], [5, 5, 5]);
+        let (style, samples) = lightmap.get_style(1).unwrap();
+        assert_eq!(style, 5);
+        assert_eq!(samples[11], [111, 111, 111]);
+        assert!(lightmap.get_style(2).is_none());
+
+        // Unlit faces, special textures, faces without lighting data and
+        // faces whose data is past the end of the lump don't have lightmaps
+        for face_index in 1..5 {
+            assert!(reader.read_lightmap(face_index).is_none());
+        }
+    }
+
+    #[test]
+    fn visible_leaves_are_run_length_decoded() {
+        let mut map = TestMap::default();
+        map.push(BspLump::Models, &[TestMap::world_model(19)]);
+        map.push(
+            BspLump::Leaves,
+            &[
+                TestMap::leaf(BspContents::Solid, -1),
+                TestMap::leaf(BspContents::Empty, 0),
+                TestMap::leaf(BspContents::Empty, 3),
+                TestMap::leaf(BspContents::Empty, 7),
+                TestMap::leaf(BspContents::Empty, -1),
+            ],
+        );
+        map.push(
+            BspLump::Leaves,
+            &[TestMap::leaf(BspContents::Empty, 10); 15],
+        );
+        map.push::<u8>(
+            BspLump::Visibility,
+            &[
+                // Leaves 1 and 3, then two empty bytes
+                0b101, 0, 2, //
+                // One empty byte, then leaves 16 and 17
+                0, 1, 0x80, 0x01, //
+                // A run that goes past the end of the row
+                0x10, 0, 200, //
+                // Data that ends early
+                0xFF,
+            ],
+        );
+        let reader = map.read();
+
+        let visible = |leaf_index| {
+            reader
+                .read_visible_leaves(leaf_index)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(visible(1), vec![1, 3]);
+        assert_eq!(visible(2), vec![16, 17]);
+        assert_eq!(visible(3), vec![5]);
+        assert_eq!(visible(5), (1..=8).collect::<Vec<_>>());
+        assert_eq!(reader.read_visible_leaves(1).unwrap().raw_bits().len(), 3);
+
+        // The solid leaf and leaves without vis data see everything
+        assert_eq!(visible(0), (1..=19).collect::<Vec<_>>());
+        assert_eq!(visible(4), (1..=19).collect::<Vec<_>>());
+
+        assert!(matches!(
+            reader.read_visible_leaves(20),
+            Err(BspError::InvalidLeaf(20))
+        ));
+    }
+
+    #[test]
+    fn points_are_found_in_the_world_leaves() {
+        let mut map = TestMap::default();
+        map.push(BspLump::Models, &[TestMap::world_model(3)]);
+        map.push(
+            BspLump::Leaves,
+            &[
+                TestMap::leaf(BspContents::Solid, -1),
+                TestMap::leaf(BspContents::Empty, -1),
+                TestMap::leaf(BspContents::Empty, -1),
+                TestMap::leaf(BspContents::Empty, -1),
+            ],
+        );
+        map.push(
+            BspLump::Planes,
+            &[
+                BspPlane {
+                    normal: [1.0, 0.0, 0.0],
+                    dist: 0.0,
+                    ty: 0,
+                },
+                BspPlane {
+                    normal: [0.0, 1.0, 0.0],
+                    dist: 16.0,
+                    ty: 1,
+                },
+            ],
+        );
+        // x > 0 splits on y > 16, the rest is leaf 1
+        map.push(
+            BspLump::Nodes,
+            &[TestMap::node(0, [1, !1]), TestMap::node(1, [!2, !3])],
+        );
+        let reader = map.read();
+
+        assert_eq!(reader.find_leaf([8.0, 32.0, 0.0]).unwrap(), 2);
+        assert_eq!(reader.find_leaf([8.0, 0.0, 0.0]).unwrap(), 3);
+        assert_eq!(reader.find_leaf([-8.0, 32.0, 0.0]).unwrap(), 1);
+        // Points on a plane are behind it
+        assert_eq!(reader.find_leaf([0.0, 32.0, 0.0]).unwrap(), 1);
+        assert_eq!(reader.find_leaf([8.0, 16.0, 0.0]).unwrap(), 3);
+    }
+
+    #[test]
+    fn malformed_maps_are_errors() {
+        let mut map = TestMap::default();
+        assert!(matches!(
+            BspReader::read(map.to_bytes()),
+            Err(BspError::LumpTooSmall {
+                lump: BspLump::Models,
+                ..
+            })
+        ));
+
+        map.push(BspLump::Models, &[TestMap::world_model(0)]);
+        let data = map.to_bytes();
+        assert!(matches!(
+            BspReader::read(data[..16].to_vec()),
+            Err(BspError::InvalidHeader(_))
+        ));
+        let mut version = data.clone();
+        version[..4].copy_from_slice(&20i32.to_le_bytes());
+        assert!(matches!(
+            BspReader::read(version),
+            Err(BspError::UnsupportedVersion(20))
+        ));
+        // The models lump is the last one, so it ends at the end of the data
+        let mut truncated = data.clone();
+        truncated.pop();
+        assert!(matches!(
+            BspReader::read(truncated),
+            Err(BspError::LumpOutOfBounds {
+                lump: BspLump::Models,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn lumps_are_decoded_wherever_they_start() {
+        let mut map = TestMap::default();
+        // The entities leave every lump after them misaligned
+        map.push(BspLump::Entities, b"{\n}");
+        map.push(
+            BspLump::Planes,
+            &[BspPlane {
+                normal: [0.0, 0.0, 1.0],
+                dist: 16.0,
+                ty: 2,
+            }],
+        );
+        map.push(
+            BspLump::Vertices,
+            &[BspVertex {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0,
+            }],
+        );
+        map.push(BspLump::Models, &[TestMap::world_model(7)]);
+        let reader = BspReader::read(map.to_bytes_with_alignment(1)).unwrap();
+        assert_eq!(reader.read_planes()[0].normal, [0.0, 0.0, 1.0]);
+        assert_eq!(reader.read_planes()[0].dist, 16.0);
+        assert_eq!(reader.read_vertices()[0].to_array(), [1.0, 2.0, 3.0]);
+        assert_eq!(reader.read_models()[0].vis_leaves, 7);
+
+        // Lumps that end partway through a record are errors
+        map.push::<u8>(BspLump::Edges, &[0; 3]);
+        assert!(matches!(
+            BspReader::read(map.to_bytes()),
+            Err(BspError::InvalidLumpSize {
+                lump: BspLump::Edges,
+                len: 3,
+                record_size: 4,
+            })
+        ));
+    }
+
+    #[test]
+    fn writer_round_trips_with_aligned_lumps() {
+        let mut writer = BspWriter {
+            entities: "{\n\"classname\" \"worldspawn\"\n}\n".to_owned(),
+            ..Default::default()
+        };
+        writer.planes.push(BspPlane {
+            normal: [0.0, 0.0, 1.0],
+            dist: 16.0,
+            ty: 2,
+        });
+        writer.textures.push(TestMap::mip_texture("odd", 16, 8));
+        writer.textures.push(Vec::new());
+        writer.vertices.push(BspVertex {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        });
+        writer.visibility = vec![1, 2, 3];
+        writer.lighting = vec![4, 5, 6, 7, 8];
+        writer.leaves.push(TestMap::leaf(BspContents::Solid, -1));
+        writer.models.push(TestMap::world_model(0));
+
+        let data = writer.to_bytes();
+        assert!(data.len().is_multiple_of(4));
+        let reader = BspReader::read(data.clone()).unwrap();
+        for lump_header in &reader.header.lumps {
+            assert!(lump_header.offset % 4 == 0);
         }
-        entities
+        // The padding isn't part of the lumps
+        assert_eq!(
+            reader.read_entities().unwrap(),
+            "{\n\"classname\" \"worldspawn\"\n}\n"
+        );
+        assert_eq!(reader.read_visibility(), &[1, 2, 3]);
+        assert_eq!(reader.read_lighting(), &[4, 5, 6, 7, 8]);
+        assert_eq!(reader.read_planes()[0].dist, 16.0);
+        assert_eq!(reader.read_vertices()[0].z, 3.0);
+
+        let rewritten = BspWriter::from_reader(&reader).unwrap();
+        // Textures are read back with the padding that follows them
+        assert!(rewritten.textures[0].starts_with(&writer.textures[0]));
+        assert!(rewritten.textures[1].is_empty());
+        assert_eq!(rewritten.to_bytes(), data);
+    }
+
+    #[test]
+    fn entities_are_tokenized_like_the_engine() {
+        let source = concat!(
+            "// Written by hand\n",
+            "{\n",
+            "\"classname\" \"worldspawn\"\n",
+            "\"wad\" \"\\half-life\\valve\\halflife.wad;{braces} // not a comment\"\n",
+            "}\n",
+            "{ classname info_target // trailing comment\n",
+            "\"target\" \"a\"\n",
+            "\"target\" \"b\"\n",
+            "\"message\" \"\"\n",
+            "}\n",
+        );
+        let entities = BspEntity::parse_entities(source).unwrap();
+        assert_eq!(entities.len(), 2);
+        assert_eq!(
+            entities[0].get("wad"),
+            Some("\\half-life\\valve\\halflife.wad;{braces} // not a comment")
+        );
+        assert_eq!(
+            entities[1].0,
+            vec![
+                ("classname", "info_target"),
+                ("target", "a"),
+                ("target", "b"),
+                ("message", ""),
+            ]
+        );
+        assert_eq!(entities[1].get("target"), Some("b"));
+        assert_eq!(
+            entities[1].get_all("target").collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+
+        // Writing keeps the order and the repeated keys
+        let written = BspEntity::write_entities(&entities);
+        let reparsed = BspEntity::parse_entities(&written).unwrap();
+        assert_eq!(reparsed.len(), entities.len());
+        for (reparsed, entity) in reparsed.iter().zip(&entities) {
+            assert_eq!(reparsed.0, entity.0);
+        }
+        assert_eq!(BspEntity::write_entities(&reparsed), written);
+    }
+
+    #[test]
+    fn entity_errors_have_line_numbers() {
+        let error = |source| BspEntity::parse_entities(source).unwrap_err();
+        assert!(matches!(
+            error("{\n\"classname\" \"worldspawn\n}"),
+            BspEntityError::UnterminatedString { line: 2 }
+        ));
+        assert!(matches!(
+            error("{\n\"classname\"\n}"),
+            BspEntityError::MissingValue { line: 2, .. }
+        ));
+        assert!(matches!(
+            error("{\n\"classname\" \"worldspawn\"\n"),
+            BspEntityError::UnclosedEntity { .. }
+        ));
+        assert!(matches!(
+            error("\"classname\" \"worldspawn\""),
+            BspEntityError::UnexpectedToken { line: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn blue_shift_lump_order_is_detected() {
+        let plane_size = std::mem::size_of::<BspPlane>();
+        let map = |variant, entities: &str| {
+            let mut writer = BspWriter {
+                variant,
+                entities: entities.to_owned(),
+                ..Default::default()
+            };
+            writer.planes.push(BspPlane {
+                normal: [1.0, 0.0, 0.0],
+                dist: 0.0,
+                ty: 0,
+            });
+            writer.models.push(TestMap::world_model(0));
+            BspReader::read(writer.to_bytes()).unwrap()
+        };
+
+        // The planes lump isn't a whole number of planes
+        let entities = "{\n\"classname\" \"worldspawn\"\n}\n";
+        assert!(!(entities.len() + 1).is_multiple_of(plane_size));
+        let reader = map(BspVariant::BlueShift, entities);
+        assert_eq!(reader.variant(), BspVariant::BlueShift);
+        assert_eq!(reader.read_entities().unwrap(), entities);
+        assert_eq!(reader.read_planes().len(), 1);
+        assert_eq!(reader.read_planes()[0].normal, [1.0, 0.0, 0.0]);
+
+        // The entities happen to be the size of a plane, so only the text
+        // gives it away
+        let mut entities = entities.to_owned();
+        while !(entities.len() + 1).is_multiple_of(plane_size) {
+            entities.insert(1, ' ');
+        }
+        let reader = map(BspVariant::BlueShift, &entities);
+        assert_eq!(reader.variant(), BspVariant::BlueShift);
+        assert_eq!(reader.read_entities().unwrap(), entities);
+
+        for variant in [BspVariant::GoldSrc, BspVariant::Quake] {
+            let reader = map(variant, &entities);
+            assert_eq!(reader.variant(), variant);
+            assert_eq!(reader.read_entities().unwrap(), entities);
+        }
+    }
+
+    #[test]
+    fn embedded_textures_decode_with_their_palette() {
+        let mut map = TestMap::default();
+        map.textures(&[TestMap::mip_texture("wall", 16, 16)]);
+        map.push(BspLump::Models, &[TestMap::world_model(0)]);
+        let reader = map.read();
+        let textures = reader.read_textures().unwrap();
+        let texture = textures.get(0).unwrap();
+
+        let bitmap = texture.get_image(1).unwrap();
+        assert_eq!((bitmap.width(), bitmap.height()), (8, 8));
+        let pixel = &bitmap.decode(&texture.read_palette().unwrap())[1];
+        assert_eq!([pixel.r, pixel.g, pixel.b], [3, 4, 5]);
+
+        let decoded = texture.decode().unwrap();
+        assert_eq!(decoded.image.get_pixel(1, 0).0, [3, 4, 5, 255]);
+        assert_eq!(decoded.mipmap3.dimensions(), (2, 2));
+        let decoded = texture
+            .decode_with_palette(&crate::palette::greyscale_palette())
+            .unwrap();
+        assert_eq!(decoded.image.get_pixel(1, 0).0, [1, 1, 1, 255]);
+    }
+
+    #[test]
+    fn face_geometry_walks_edge_zero_forwards() {
+        let mut map = TestMap::default();
+        map.push(
+            BspLump::Planes,
+            &[BspPlane {
+                normal: [0.0, 0.0, 1.0],
+                dist: 0.0,
+                ty: 2,
+            }],
+        );
+        map.textures(&[TestMap::mip_texture("wall", 16, 16)]);
+        map.polygon(&[[0.0, 0.0], [32.0, 0.0], [32.0, 32.0], [0.0, 32.0]]);
+        map.push(
+            BspLump::TextureInfo,
+            &[TestMap::texture_info([1.0, 0.0, 0.0], 0.0, 0)],
+        );
+        // Both faces are on the back of the plane
+        let mut face = TestMap::face(0, 4, 0, [0, 255, 255, 255], 0);
+        face.plane_side = 1;
+        map.push(BspLump::Faces, &[face; 2]);
+        map.push(BspLump::Models, &[TestMap::world_model(0)]);
+        let reader = map.read();
+
+        let faces = reader
+            .iter_face_geometry()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(faces.len(), 2);
+        let geometry = &faces[1];
+        assert_eq!(geometry.face_index, 1);
+        assert_eq!(geometry.texture_name, Some("wall"));
+        assert_eq!(geometry.normal, [-0.0, -0.0, -1.0]);
+        let positions = geometry
+            .vertices
+            .iter()
+            .map(|vertex| vertex.position)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            positions,
+            [
+                [0.0, 0.0, 0.0],
+                [32.0, 0.0, 0.0],
+                [32.0, 32.0, 0.0],
+                [0.0, 32.0, 0.0]
+            ]
+        );
+        assert_eq!(geometry.vertices[2].uv, [2.0, 2.0]);
+        assert_eq!(geometry.triangles().count(), 2);
+
+        let extents = reader.read_lightmap_extents(1).unwrap();
+        assert_eq!(extents.texture_mins, [0, 0]);
+        assert_eq!([extents.width, extents.height], [3, 3]);
+
+        let texture_reader = reader.read_textures().unwrap();
+        assert!(matches!(
+            reader.read_face_geometry(2, &texture_reader),
+            Err(BspError::InvalidFace { index: 2 })
+        ));
+    }
+
+    #[test]
+    fn face_geometry_without_texture_or_lightmap_keeps_its_polygon() {
+        let mut map = TestMap::default();
+        map.push(
+            BspLump::Planes,
+            &[BspPlane {
+                normal: [0.0, 0.0, 1.0],
+                dist: 0.0,
+                ty: 2,
+            }],
+        );
+        map.textures(&[]);
+        map.polygon(&[[0.0, 0.0], [32.0, 0.0], [32.0, 32.0]]);
+        // The texture doesn't exist and the shift puts the lightmap out of
+        // range
+        let mut texture_info = TestMap::texture_info([1.0, 0.0, 0.0], 1e12, 0);
+        texture_info.texture_index = 1;
+        map.push(BspLump::TextureInfo, &[texture_info]);
+        map.push(
+            BspLump::Faces,
+            &[TestMap::face(0, 3, 0, [255, 255, 255, 255], -1)],
+        );
+        map.push(BspLump::Models, &[TestMap::world_model(0)]);
+        let reader = map.read();
+
+        let texture_reader = reader.read_textures().unwrap();
+        let geometry = reader.read_face_geometry(0, &texture_reader).unwrap();
+        assert_eq!(geometry.texture_name, None);
+        assert!(geometry.lightmap_extents.is_none());
+        assert_eq!(geometry.vertices.len(), 3);
+        assert_eq!(geometry.vertices[2].position, [32.0, 32.0, 0.0]);
+        // In texels without a texture
+        assert_eq!(geometry.vertices[2].uv[1], 32.0);
+    }
+
+    #[test]
+    fn traces_use_the_hulls_of_the_given_model() {
+        let mut map = TestMap::default();
+        map.push(
+            BspLump::Planes,
+            &[BspPlane {
+                normal: [0.0, 0.0, 1.0],
+                dist: 16.0,
+                ty: 2,
+            }],
+        );
+        // Hull 1 of the world is a ladder, that of the brush model is empty
+        // above the plane and solid below it
+        map.push(
+            BspLump::ClipNodes,
+            &[
+                BspClipNode {
+                    plane_index: 0,
+                    children: [BspContents::Ladder as i16; 2],
+                },
+                BspClipNode {
+                    plane_index: 0,
+                    children: [BspContents::Empty as i16, BspContents::Solid as i16],
+                },
+            ],
+        );
+        map.push(BspLump::Leaves, &[TestMap::leaf(BspContents::Solid, -1)]);
+        let mut brush_model = TestMap::world_model(0);
+        brush_model.head_nodes[1] = 1;
+        map.push(BspLump::Models, &[TestMap::world_model(0), brush_model]);
+        let reader = map.read();
+
+        assert!(matches!(
+            reader.point_contents(0, 1, [0.0, 0.0, 0.0]),
+            Ok(BspContents::Ladder)
+        ));
+        assert!(matches!(
+            reader.point_contents(1, 1, [0.0, 0.0, 0.0]),
+            Ok(BspContents::Solid)
+        ));
+
+        let trace = reader
+            .trace(1, 1, [0.0, 0.0, 32.0], [0.0, 0.0, 0.0])
+            .unwrap();
+        assert!(!trace.start_solid && trace.in_open);
+        assert_eq!(trace.plane.unwrap().normal, [0.0, 0.0, 1.0]);
+        // The end is backed off from the plane a little
+        assert!(trace.end_position[2] > 16.0 && trace.end_position[2] < 16.1);
+        assert!(trace.fraction > 0.49 && trace.fraction < 0.5);
+        assert!(matches!(trace.contents, BspContents::Empty));
+
+        assert!(matches!(
+            reader.trace(2, 1, [0.0; 3], [0.0; 3]),
+            Err(BspError::InvalidModel(2))
+        ));
+        assert!(matches!(
+            reader.point_contents(0, 4, [0.0; 3]),
+            Err(BspError::InvalidHull(4))
+        ));
+    }
+
+    #[test]
+    fn embedded_textures_are_extracted() {
+        let mut map = TestMap::default();
+        // Textures that are only named are left in their WADs
+        map.textures(&[
+            TestMap::mip_texture("wall", 16, 16),
+            TestMap::external_texture("sky", 16, 16),
+        ]);
+        map.push(BspLump::Models, &[TestMap::world_model(0)]);
+        let reader = map.read();
+
+        let archive =
+            WadArchive::from_bytes(reader.extract_textures(None).unwrap().to_bytes()).unwrap();
+        assert_eq!(archive.files.len(), 1);
+        assert_eq!(archive.files[0].name, "wall");
+        let texture = archive.decode_mipmaped_image(&archive.files[0]).unwrap();
+        assert_eq!(texture.image.get_pixel(1, 0).0, [3, 4, 5, 255]);
+        assert_eq!(texture.mipmap1.get_pixel(1, 1).0, [27, 28, 29, 255]);
+    }
+
+    #[test]
+    fn external_textures_are_embedded() {
+        let mut writer = BspWriter::default();
+        writer
+            .textures
+            .push(TestMap::external_texture("wall", 16, 16));
+        writer
+            .textures
+            .push(TestMap::external_texture("floor", 16, 16));
+        writer
+            .textures
+            .push(TestMap::external_texture("door", 32, 16));
+
+        let missing = writer
+            .embed_textures(|name| match name {
+                "wall" => Some(TestMap::mip_texture("WALL", 16, 16)),
+                // Textures that don't match the map's size are skipped
+                "door" => Some(TestMap::mip_texture("DOOR", 16, 16)),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(missing, ["floor", "door"]);
+        assert_eq!(writer.textures[0], TestMap::mip_texture("wall", 16, 16));
+        assert_eq!(
+            writer.textures[1],
+            TestMap::external_texture("floor", 16, 16)
+        );
+
+        writer.models.push(TestMap::world_model(0));
+        let reader = BspReader::read(writer.to_bytes()).unwrap();
+        let texture = reader.read_textures().unwrap().get(0).unwrap();
+        assert!(texture.has_local_image_data());
+        assert_eq!(texture.get_image_name().unwrap(), "wall");
+    }
+
+    #[test]
+    fn cyclic_hulls_are_rejected() {
+        let mut map = TestMap::default();
+        map.push(
+            BspLump::Planes,
+            &[BspPlane {
+                normal: [0.0, 0.0, 1.0],
+                dist: 16.0,
+                ty: 2,
+            }],
+        );
+        // Both sides of the node lead back to it
+        map.push(
+            BspLump::ClipNodes,
+            &[BspClipNode {
+                plane_index: 0,
+                children: [0, 0],
+            }],
+        );
+        map.push(BspLump::Leaves, &[TestMap::leaf(BspContents::Solid, -1)]);
+        map.push(BspLump::Models, &[TestMap::world_model(0)]);
+        let reader = map.read();
+
+        assert!(matches!(
+            reader.point_contents(0, 1, [0.0; 3]),
+            Err(BspError::InvalidHullNode { hull: 1, node: 0 })
+        ));
+        assert!(matches!(
+            reader.trace(0, 1, [0.0, 0.0, 32.0], [0.0, 0.0, 0.0]),
+            Err(BspError::InvalidHullNode { hull: 1, node: 0 })
+        ));
+    }
+
+    #[test]
+    fn bad_lightmap_extents_are_errors() {
+        let mut map = TestMap::default();
+        map.polygon(&[[0.0, 0.0], [512.0, 0.0], [512.0, 32.0]]);
+        map.push(
+            BspLump::TextureInfo,
+            &[
+                TestMap::texture_info([1.0, 0.0, 0.0], 0.0, 0),
+                TestMap::texture_info([1.0e30, 0.0, 0.0], 0.0, 0),
+            ],
+        );
+        let styles = [0, 255, 255, 255];
+        map.push(
+            BspLump::Faces,
+            &[
+                TestMap::face(0, 0, 0, styles, 0),
+                TestMap::face(0, 3, 1, styles, 0),
+                TestMap::face(0, 3, 0, styles, 0),
+            ],
+        );
+        map.push(BspLump::Lighting, &[0u8; 4096]);
+        map.push(BspLump::Models, &[TestMap::world_model(0)]);
+        let reader = map.read();
+
+        assert!(matches!(
+            reader.read_lightmap_extents(0),
+            Err(BspError::InvalidFace { index: 0 })
+        ));
+        assert!(matches!(
+            reader.read_lightmap_extents(1),
+            Err(BspError::InvalidLightmapExtents { index: 1 })
+        ));
+        // Wider than the engine allows
+        let extents = reader.read_lightmap_extents(2).unwrap();
+        assert_eq!([extents.width, extents.height], [33, 3]);
+        assert!(reader.read_lightmap(2).is_none());
     }
 }
@@ -1,3 +1,4 @@
 pub mod bsp;
 pub mod mdl;
+pub mod palette;
 pub mod wad3;
@@ -3,13 +3,106 @@ extern crate byteorder;
 extern crate image;
 extern crate serde;
 
-use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
-use std::path::Path;
-use std::str;
+use std::fmt::Display;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::palette::{decode_paletted_image, PALETTE_LEN};
+
+const MDL_MAGIC: [u8; 4] = *b"IDST";
+const SEQUENCE_GROUP_MAGIC: [u8; 4] = *b"IDSQ";
+const MDL_VERSION: u32 = 10;
+// MAXSTUDIOBLENDS in the engine
+const MAX_BLENDS: u32 = 4;
+
+#[derive(Debug)]
+pub enum MdlError {
+    Io(std::io::Error),
+    TextureFile {
+        name: String,
+        source: std::io::Error,
+    },
+    InvalidMagic([u8; 4]),
+    UnsupportedVersion(u32),
+    InvalidData {
+        offset: u64,
+        source: bincode::Error,
+    },
+    InvalidAnimation {
+        sequence: String,
+        offset: usize,
+    },
+    InvalidTexture(String),
+    InvalidSequenceGroup {
+        sequence: String,
+        group: i32,
+    },
+    InvalidBone(i32),
+    InvalidBoneController(i32),
+    TooManyBlends {
+        sequence: String,
+        count: u32,
+    },
+}
 
-use byteorder::{LittleEndian, ReadBytesExt};
-use serde::Deserialize;
+impl Display for MdlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MdlError::Io(error) => write!(f, "{}", error),
+            MdlError::TextureFile { name, source } => {
+                write!(f, "Could not open texture file \"{}\": {}", name, source)
+            }
+            MdlError::InvalidMagic(magic) => write!(f, "Invalid MDL magic: {:?}", magic),
+            MdlError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported MDL version: {}", version)
+            }
+            MdlError::InvalidData { offset, source } => {
+                write!(f, "Invalid data at offset {}: {}", offset, source)
+            }
+            MdlError::InvalidAnimation { sequence, offset } => write!(
+                f,
+                "Animation data for \"{}\" (offset: {}) is out of bounds",
+                sequence, offset
+            ),
+            MdlError::InvalidTexture(name) => {
+                write!(f, "Texture \"{}\" has invalid image data", name)
+            }
+            MdlError::InvalidSequenceGroup { sequence, group } => write!(
+                f,
+                "\"{}\" uses sequence group {}, which doesn't exist",
+                sequence, group
+            ),
+            MdlError::InvalidBone(bone) => write!(f, "Bone {} doesn't exist", bone),
+            MdlError::InvalidBoneController(ty) => {
+                write!(f, "Unknown bone controller type: 0x{:X}", ty)
+            }
+            MdlError::TooManyBlends { sequence, count } => write!(
+                f,
+                "\"{}\" has {} blends, the most a sequence can have is {}",
+                sequence, count, MAX_BLENDS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MdlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MdlError::Io(error) => Some(error),
+            MdlError::TextureFile { source, .. } => Some(source),
+            MdlError::InvalidData { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MdlError {
+    fn from(error: std::io::Error) -> Self {
+        MdlError::Io(error)
+    }
+}
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -17,11 +110,91 @@ struct AnimationValueOffsets {
     offsets: [u16; 6],
 }
 
+impl AnimationValueOffsets {
+    fn read(data: &[u8]) -> Option<Self> {
+        let data = data.get(..std::mem::size_of::<Self>())?;
+        let mut offsets = [0u16; 6];
+        for (offset, bytes) in offsets.iter_mut().zip(data.chunks_exact(2)) {
+            *offset = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Some(Self { offsets })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Animation {
     pub name: String,
     pub fps: f32,
-    pub bone_animations: Vec<BoneAnimation>,
+    // The bone animations of each blend, there's always at least one
+    pub blends: Vec<Vec<BoneAnimation>>,
+    pub events: Vec<AnimationEvent>,
+}
+
+// The local transform of a bone in Half-Life coordinates. The rotation is a
+// quaternion (x, y, z, w).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BonePose {
+    pub translation: [f32; 3],
+    pub rotation: [f32; 4],
+}
+
+#[derive(Clone, Debug)]
+pub struct AnimationEvent {
+    pub frame: i32,
+    pub id: i32,
+    pub options: String,
+}
+
+// Events below 1000 are specific to the monster that plays the sequence
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AnimationEventKind {
+    Script(ScriptEvent),
+    // The options are the path of the sound to play
+    Sound,
+    MuzzleFlash { attachment: usize },
+    Spark,
+    Other,
+}
+
+// Events handled by scripted sequences (1000-1010)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScriptEvent {
+    Dead,
+    NoInterrupt,
+    CanInterrupt,
+    FireEvent,
+    Sound,
+    Sentence,
+    InAir,
+    EndAnimation,
+    SoundVoice,
+    RandomSentence,
+    NotDead,
+}
+
+impl AnimationEvent {
+    pub fn kind(&self) -> AnimationEventKind {
+        match self.id {
+            1000 => AnimationEventKind::Script(ScriptEvent::Dead),
+            1001 => AnimationEventKind::Script(ScriptEvent::NoInterrupt),
+            1002 => AnimationEventKind::Script(ScriptEvent::CanInterrupt),
+            1003 => AnimationEventKind::Script(ScriptEvent::FireEvent),
+            1004 => AnimationEventKind::Script(ScriptEvent::Sound),
+            1005 => AnimationEventKind::Script(ScriptEvent::Sentence),
+            1006 => AnimationEventKind::Script(ScriptEvent::InAir),
+            1007 => AnimationEventKind::Script(ScriptEvent::EndAnimation),
+            1008 => AnimationEventKind::Script(ScriptEvent::SoundVoice),
+            1009 => AnimationEventKind::Script(ScriptEvent::RandomSentence),
+            1010 => AnimationEventKind::Script(ScriptEvent::NotDead),
+            // One event per attachment: 5001, 5011, 5021 and 5031
+            5001 | 5011 | 5021 | 5031 => AnimationEventKind::MuzzleFlash {
+                attachment: (self.id as usize - 5001) / 10,
+            },
+            5002 => AnimationEventKind::Spark,
+            5004 => AnimationEventKind::Sound,
+            _ => AnimationEventKind::Other,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -50,6 +223,13 @@ pub enum VectorChannel {
 }
 
 impl ComponentTransformTarget {
+    fn index(&self) -> usize {
+        match self {
+            ComponentTransformTarget::Translation(channel) => channel.index(),
+            ComponentTransformTarget::Rotation(channel) => channel.index() + 3,
+        }
+    }
+
     fn from_index(index: usize) -> Self {
         if index < 3 {
             ComponentTransformTarget::Translation(VectorChannel::from_index(index))
@@ -62,6 +242,14 @@ impl ComponentTransformTarget {
 }
 
 impl VectorChannel {
+    fn index(&self) -> usize {
+        match self {
+            VectorChannel::X => 0,
+            VectorChannel::Y => 1,
+            VectorChannel::Z => 2,
+        }
+    }
+
     fn from_index(index: usize) -> Self {
         match index {
             0 | 3 => VectorChannel::X,
@@ -95,6 +283,18 @@ pub struct AnimationSequenceGroup {
     pub unused_2: i32,
 }
 
+impl AnimationSequence {
+    // Maps a blend value to how far it is between the start and end of the
+    // blend, from 0 to 1.
+    fn blend_weight(&self, index: usize, value: f32) -> f32 {
+        let (start, end) = (self.blend_start[index], self.blend_end[index]);
+        if self.blend_type[index] == 0 || start == end {
+            return 0.0;
+        }
+        ((value - start) / (end - start)).clamp(0.0, 1.0)
+    }
+}
+
 impl AnimationSequenceGroup {
     pub fn name(&self) -> &[u8; 64] {
         unsafe { std::mem::transmute(&self.name) }
@@ -195,10 +395,89 @@ pub struct MdlFile {
     pub animation_sequences: Vec<AnimationSequence>,
     pub animation_sequence_groups: Vec<AnimationSequenceGroup>,
     pub animations: Vec<Animation>,
+    pub bone_controllers: Vec<BoneController>,
+    pub attachments: Vec<Attachment>,
+    pub hitboxes: Vec<Hitbox>,
+    // Sequence group files that couldn't be opened, the sequences in those
+    // groups are left in the rest pose
+    pub missing_sequence_groups: Vec<String>,
     header: MdlHeader,
     raw_data: Vec<u8>,
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct BoneController {
+    pub bone: usize,
+    pub target: ComponentTransformTarget,
+    // Rotations that wrap around instead of being clamped to the range
+    pub wraps: bool,
+    pub start: f32,
+    pub end: f32,
+    pub rest: i32,
+    // The controller that drives this one, 0-3 or MOUTH_CONTROLLER
+    pub index: i32,
+}
+
+pub const MOUTH_CONTROLLER: i32 = 4;
+
+mod bone_controller_type {
+    pub const X: i32 = 0x1;
+    pub const Y: i32 = 0x2;
+    pub const Z: i32 = 0x4;
+    pub const XR: i32 = 0x8;
+    pub const YR: i32 = 0x10;
+    pub const ZR: i32 = 0x20;
+    pub const TYPES: i32 = 0x7FFF;
+    pub const RLOOP: i32 = 0x8000;
+}
+
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub name: String,
+    pub bone: usize,
+    // Relative to the bone
+    pub origin: [f32; 3],
+    pub vectors: [[f32; 3]; 3],
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HitGroup {
+    Generic,
+    Head,
+    Chest,
+    Stomach,
+    LeftArm,
+    RightArm,
+    LeftLeg,
+    RightLeg,
+    Other(i32),
+}
+
+impl HitGroup {
+    fn from_raw(group: i32) -> Self {
+        match group {
+            0 => HitGroup::Generic,
+            1 => HitGroup::Head,
+            2 => HitGroup::Chest,
+            3 => HitGroup::Stomach,
+            4 => HitGroup::LeftArm,
+            5 => HitGroup::RightArm,
+            6 => HitGroup::LeftLeg,
+            7 => HitGroup::RightLeg,
+            _ => HitGroup::Other(group),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Hitbox {
+    pub bone: usize,
+    pub group: HitGroup,
+    // Relative to the bone
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct BoneHeader {
     pub name: [u8; 32],
@@ -259,6 +538,49 @@ struct BodyPartHeader {
     model_offset: u32,
 }
 
+#[derive(Copy, Clone, Deserialize, Debug)]
+struct BoneControllerHeader {
+    bone: i32,
+    ty: i32,
+    start: f32,
+    end: f32,
+    rest: i32,
+    index: i32,
+}
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, Deserialize, Debug)]
+struct AttachmentHeader {
+    name: [u8; 32],
+    ty: i32,
+    bone: i32,
+    origin: [f32; 3],
+    vectors: [[f32; 3]; 3],
+}
+
+#[derive(Copy, Clone, Deserialize, Debug)]
+struct HitboxHeader {
+    bone: i32,
+    group: i32,
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, Deserialize, Debug)]
+struct EventHeader {
+    frame: i32,
+    event: i32,
+    ty: i32,
+    options: [[u8; 8]; 8],
+}
+
+impl EventHeader {
+    fn options(&self) -> &[u8; 64] {
+        unsafe { std::mem::transmute(&self.options) }
+    }
+}
+
 impl BodyPartHeader {
     fn name(&self) -> &[u8; 64] {
         unsafe { std::mem::transmute(&self.name) }
@@ -359,61 +681,175 @@ struct MdlHeader {
     transition_offset: u32,
 }
 
+// The header of "<name>01.mdl" and the other files that hold the
+// animations of a sequence group
+#[allow(dead_code)]
+#[derive(Copy, Clone, Deserialize)]
+struct SequenceGroupHeader {
+    id: u32,
+    version: u32,
+    name: [[u8; 8]; 8],
+    length: u32,
+}
+
+impl SequenceGroupHeader {
+    fn validate(&self) -> Result<(), MdlError> {
+        let magic = self.id.to_le_bytes();
+        if magic != SEQUENCE_GROUP_MAGIC {
+            return Err(MdlError::InvalidMagic(magic));
+        }
+        if self.version != MDL_VERSION {
+            return Err(MdlError::UnsupportedVersion(self.version));
+        }
+        Ok(())
+    }
+}
+
 impl MdlHeader {
+    fn validate(&self) -> Result<(), MdlError> {
+        let magic = self.id.to_le_bytes();
+        if magic != MDL_MAGIC {
+            return Err(MdlError::InvalidMagic(magic));
+        }
+        if self.version != MDL_VERSION {
+            return Err(MdlError::UnsupportedVersion(self.version));
+        }
+        Ok(())
+    }
+
     fn name(&self) -> &[u8; 64] {
         unsafe { std::mem::transmute(&self.name) }
     }
 
     fn name_string(&self) -> String {
         let name = self.name();
-        let name_string = str::from_utf8(name).unwrap();
+        let name_string = String::from_utf8_lossy(name);
         let name_string = name_string.trim_matches(char::from(0));
         name_string.to_string()
     }
 }
 
+// Loads the files that are stored next to a model, like the "<name>t.mdl"
+// file that holds the textures of some models and the "<name>01.mdl" files
+// that hold the animations of sequence groups.
+pub trait MdlFileResolver {
+    fn read_file(&mut self, file_name: &str) -> std::io::Result<Vec<u8>>;
+}
+
+impl<F: FnMut(&str) -> std::io::Result<Vec<u8>>> MdlFileResolver for F {
+    fn read_file(&mut self, file_name: &str) -> std::io::Result<Vec<u8>> {
+        self(file_name)
+    }
+}
+
+// Reads companion files from the directory the model is in.
+pub struct MdlDirectoryResolver {
+    directory: PathBuf,
+}
+
+impl MdlDirectoryResolver {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        Self {
+            directory: directory.as_ref().to_owned(),
+        }
+    }
+}
+
+impl MdlFileResolver for MdlDirectoryResolver {
+    fn read_file(&mut self, file_name: &str) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.directory.join(file_name))
+    }
+}
+
+// Used when there's nowhere to look for companion files.
+struct NoFileResolver;
+
+impl MdlFileResolver for NoFileResolver {
+    fn read_file(&mut self, _file_name: &str) -> std::io::Result<Vec<u8>> {
+        Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+    }
+}
+
 impl MdlFile {
-    pub fn open<P: AsRef<Path>>(mdl_path: P) -> MdlFile {
+    // Companion files are looked up next to the model, and are named after
+    // the model's file.
+    pub fn open<P: AsRef<Path>>(mdl_path: P) -> Result<MdlFile, MdlError> {
         let mdl_path = mdl_path.as_ref();
-        let file = File::open(mdl_path).unwrap();
-        let file_size = file.metadata().unwrap().len();
-        let mut file = BufReader::new(file);
+        let file_data = std::fs::read(mdl_path)?;
+        let file_stem = mdl_path.file_stem().unwrap_or_default().to_string_lossy();
+        let directory = mdl_path.parent().unwrap_or_else(|| Path::new(""));
+        Self::load(
+            file_data,
+            Some(&file_stem),
+            MdlDirectoryResolver::new(directory),
+        )
+    }
+
+    // Models that keep their textures in separate files can't be loaded this
+    // way, and sequences in other groups are left in the rest pose. Use
+    // from_reader_with_resolver instead.
+    pub fn from_bytes(file_data: Vec<u8>) -> Result<MdlFile, MdlError> {
+        Self::load(file_data, None, NoFileResolver)
+    }
+
+    pub fn from_reader<R: Read + Seek>(reader: R) -> Result<MdlFile, MdlError> {
+        Self::from_reader_with_resolver(reader, NoFileResolver)
+    }
+
+    // Companion files are named after the name stored in the model, e.g.
+    // "scientistt.mdl" and "scientist01.mdl" for "scientist.mdl".
+    pub fn from_reader_with_resolver<R: Read + Seek, F: MdlFileResolver>(
+        mut reader: R,
+        resolver: F,
+    ) -> Result<MdlFile, MdlError> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut file_data = Vec::new();
+        reader.read_to_end(&mut file_data)?;
+        Self::load(file_data, None, resolver)
+    }
 
-        let mut header: MdlHeader = bincode::deserialize_from(&mut file).unwrap();
+    fn load<F: MdlFileResolver>(
+        file_data: Vec<u8>,
+        file_stem: Option<&str>,
+        mut resolver: F,
+    ) -> Result<MdlFile, MdlError> {
+        let mut file = Cursor::new(file_data.as_slice());
+
+        let mut header: MdlHeader = read_value(&mut file)?;
+        header.validate()?;
         let file_name = header.name_string();
+        let file_stem = match file_stem {
+            Some(file_stem) => file_stem.to_owned(),
+            None => name_stem(&file_name).to_owned(),
+        };
 
         let textures = if header.texture_count == 0 {
-            let mut texture_mdl_path = mdl_path.to_owned();
-            let file_stem = texture_mdl_path
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned();
-            texture_mdl_path.set_file_name(format!("{}t.mdl", file_stem));
-            let texture_mdl_path = texture_mdl_path.into_os_string();
-            let texture_mdl_path = texture_mdl_path.into_string().unwrap();
-
-            let file = File::open(texture_mdl_path).unwrap();
-            //let file_size = file.metadata().unwrap().len();
-            let mut file = BufReader::new(file);
-            let texture_header: MdlHeader = bincode::deserialize_from(&mut file).unwrap();
+            let texture_file_name = format!("{}t.mdl", file_stem);
+            let texture_data =
+                resolver
+                    .read_file(&texture_file_name)
+                    .map_err(|source| MdlError::TextureFile {
+                        name: texture_file_name.clone(),
+                        source,
+                    })?;
+            let mut texture_file = Cursor::new(texture_data.as_slice());
+            let texture_header: MdlHeader = read_value(&mut texture_file)?;
+            texture_header.validate()?;
 
             header.texture_count = texture_header.texture_count;
             header.texture_offset = texture_header.texture_offset;
             header.texture_data_index = texture_header.texture_data_index;
-            read_textures(&mut file, &texture_header)
+            read_textures(&mut texture_file, &texture_header)?
         } else {
-            read_textures(&mut file, &header)
+            read_textures(&mut file, &header)?
         };
 
         let body_parts = {
             let mut body_part_headers = Vec::new();
 
-            file.seek(SeekFrom::Start(header.body_part_offset as u64))
-                .unwrap();
+            seek(&mut file, header.body_part_offset)?;
             for _ in 0..header.body_part_count {
-                let body_header: BodyPartHeader = bincode::deserialize_from(&mut file).unwrap();
+                let body_header: BodyPartHeader = read_value(&mut file)?;
 
                 body_part_headers.push(body_header);
             }
@@ -421,11 +857,10 @@ impl MdlFile {
             let mut body_parts = Vec::new();
             for body_header in body_part_headers {
                 // Model
-                file.seek(SeekFrom::Start(body_header.model_offset as u64))
-                    .unwrap();
+                seek(&mut file, body_header.model_offset)?;
                 let mut model_headers = Vec::new();
                 for _ in 0..body_header.model_count {
-                    let model_header: ModelHeader = bincode::deserialize_from(&mut file).unwrap();
+                    let model_header: ModelHeader = read_value(&mut file)?;
                     model_headers.push(model_header);
                 }
 
@@ -433,56 +868,45 @@ impl MdlFile {
                 for model_header in model_headers {
                     // Model Vertex
                     let mut vertices = Vec::new();
-                    file.seek(SeekFrom::Start(model_header.vertex_offset as u64))
-                        .unwrap();
+                    seek(&mut file, model_header.vertex_offset)?;
                     for _ in 0..model_header.vertex_count {
-                        let mut vertex = [0f32; 3];
-                        vertex[0] = file.read_f32::<LittleEndian>().unwrap();
-                        vertex[1] = file.read_f32::<LittleEndian>().unwrap();
-                        vertex[2] = file.read_f32::<LittleEndian>().unwrap();
+                        let vertex: [f32; 3] = read_value(&mut file)?;
 
                         vertices.push(vertex);
                     }
 
                     // Model Normal
                     let mut normals = Vec::new();
-                    file.seek(SeekFrom::Start(model_header.normal_offset as u64))
-                        .unwrap();
+                    seek(&mut file, model_header.normal_offset)?;
                     for _ in 0..model_header.normal_count {
-                        let mut normal = [0f32; 3];
-                        normal[0] = file.read_f32::<LittleEndian>().unwrap();
-                        normal[1] = file.read_f32::<LittleEndian>().unwrap();
-                        normal[2] = file.read_f32::<LittleEndian>().unwrap();
+                        let normal: [f32; 3] = read_value(&mut file)?;
 
                         normals.push(normal);
                     }
 
                     // Model Vertex bone indices
                     let mut vertex_bone_indices = Vec::new();
-                    file.seek(SeekFrom::Start(model_header.vertex_info_offset as u64))
-                        .unwrap();
+                    seek(&mut file, model_header.vertex_info_offset)?;
                     for _ in 0..model_header.vertex_count {
-                        let index = file.read_u8().unwrap();
+                        let index: u8 = read_value(&mut file)?;
                         vertex_bone_indices.push(index);
                     }
 
                     // Mesh
                     let mut mesh_headers = Vec::new();
-                    file.seek(SeekFrom::Start(model_header.mesh_offset as u64))
-                        .unwrap();
+                    seek(&mut file, model_header.mesh_offset)?;
                     for _ in 0..model_header.mesh_count {
-                        let mesh_header: MeshHeader = bincode::deserialize_from(&mut file).unwrap();
+                        let mesh_header: MeshHeader = read_value(&mut file)?;
                         mesh_headers.push(mesh_header);
                     }
 
                     let mut meshes = Vec::new();
                     for mesh_header in mesh_headers {
                         // Mesh Vertex
-                        file.seek(SeekFrom::Start(mesh_header.trivert_offset as u64))
-                            .unwrap();
+                        seek(&mut file, mesh_header.trivert_offset)?;
                         let mut sequences = Vec::new();
                         let mut total_triverts = 0;
-                        let mut num_triverts: i16 = bincode::deserialize_from(&mut file).unwrap();
+                        let mut num_triverts: i16 = read_value(&mut file)?;
                         while num_triverts != 0 {
                             {
                                 // Positive means triangle strip, negative means triangle fan
@@ -493,8 +917,7 @@ impl MdlFile {
                                 };
                                 let mut triverts = Vec::with_capacity(num_triverts);
                                 for _ in 0..num_triverts {
-                                    let vertex_header: VertexHeader =
-                                        bincode::deserialize_from(&mut file).unwrap();
+                                    let vertex_header: VertexHeader = read_value(&mut file)?;
                                     let vertex = MdlMeshVertex {
                                         vertex_index: vertex_header.vertex_index as u32,
                                         normal_index: vertex_header.normal_index as u32,
@@ -509,7 +932,7 @@ impl MdlFile {
                                     triverts,
                                 });
                             }
-                            num_triverts = bincode::deserialize_from(&mut file).unwrap();
+                            num_triverts = read_value(&mut file)?;
                         }
                         // Why don't these match?
                         //assert_eq!(total_triverts, mesh_header.trivert_count as usize);
@@ -524,16 +947,16 @@ impl MdlFile {
 
                     models.push(MdlModel {
                         name: model_header.name_string(),
-                        meshes: meshes,
-                        vertices: vertices,
-                        normals: normals,
+                        meshes,
+                        vertices,
+                        normals,
                         vertex_bone_indices,
                     })
                 }
 
                 body_parts.push(MdlBodyPart {
                     name: body_header.name_string(),
-                    models: models,
+                    models,
                 });
             }
 
@@ -544,10 +967,9 @@ impl MdlFile {
         let bones = {
             let mut bones = Vec::new();
 
-            file.seek(SeekFrom::Start(header.bone_offset as u64))
-                .unwrap();
+            seek(&mut file, header.bone_offset)?;
             for _ in 0..header.bone_count {
-                let body_header: BoneHeader = bincode::deserialize_from(&mut file).unwrap();
+                let body_header: BoneHeader = read_value(&mut file)?;
 
                 bones.push(body_header);
             }
@@ -555,14 +977,99 @@ impl MdlFile {
             bones
         };
 
+        let bone_index = |bone: i32| -> Result<usize, MdlError> {
+            if bone >= 0 && (bone as usize) < bones.len() {
+                Ok(bone as usize)
+            } else {
+                Err(MdlError::InvalidBone(bone))
+            }
+        };
+
+        // Bone controllers
+        let bone_controllers = {
+            let mut bone_controllers = Vec::new();
+
+            seek(&mut file, header.bone_controller_offset)?;
+            for _ in 0..header.bone_controller_count {
+                let controller_header: BoneControllerHeader = read_value(&mut file)?;
+                let target = match controller_header.ty & bone_controller_type::TYPES {
+                    bone_controller_type::X => {
+                        ComponentTransformTarget::Translation(VectorChannel::X)
+                    }
+                    bone_controller_type::Y => {
+                        ComponentTransformTarget::Translation(VectorChannel::Y)
+                    }
+                    bone_controller_type::Z => {
+                        ComponentTransformTarget::Translation(VectorChannel::Z)
+                    }
+                    bone_controller_type::XR => {
+                        ComponentTransformTarget::Rotation(VectorChannel::X)
+                    }
+                    bone_controller_type::YR => {
+                        ComponentTransformTarget::Rotation(VectorChannel::Y)
+                    }
+                    bone_controller_type::ZR => {
+                        ComponentTransformTarget::Rotation(VectorChannel::Z)
+                    }
+                    _ => return Err(MdlError::InvalidBoneController(controller_header.ty)),
+                };
+                bone_controllers.push(BoneController {
+                    bone: bone_index(controller_header.bone)?,
+                    target,
+                    wraps: controller_header.ty & bone_controller_type::RLOOP != 0,
+                    start: controller_header.start,
+                    end: controller_header.end,
+                    rest: controller_header.rest,
+                    index: controller_header.index,
+                });
+            }
+
+            bone_controllers
+        };
+
+        // Attachments
+        let attachments = {
+            let mut attachments = Vec::new();
+
+            seek(&mut file, header.attachment_offset)?;
+            for _ in 0..header.attachment_count {
+                let attachment_header: AttachmentHeader = read_value(&mut file)?;
+                attachments.push(Attachment {
+                    name: null_terminated_bytes_to_string_lossy(&attachment_header.name),
+                    bone: bone_index(attachment_header.bone)?,
+                    origin: attachment_header.origin,
+                    vectors: attachment_header.vectors,
+                });
+            }
+
+            attachments
+        };
+
+        // Hitboxes
+        let hitboxes = {
+            let mut hitboxes = Vec::new();
+
+            seek(&mut file, header.hit_box_offset)?;
+            for _ in 0..header.hit_box_count {
+                let hitbox_header: HitboxHeader = read_value(&mut file)?;
+                hitboxes.push(Hitbox {
+                    bone: bone_index(hitbox_header.bone)?,
+                    group: HitGroup::from_raw(hitbox_header.group),
+                    min: hitbox_header.min,
+                    max: hitbox_header.max,
+                });
+            }
+
+            hitboxes
+        };
+
         // Animation sequences
         let sequences = {
             let mut sequences = Vec::new();
 
-            file.seek(SeekFrom::Start(header.anim_seq_offset as u64))
-                .unwrap();
+            seek(&mut file, header.anim_seq_offset)?;
             for _ in 0..header.anim_seq_count {
-                let sequence: AnimationSequence = bincode::deserialize_from(&mut file).unwrap();
+                let sequence: AnimationSequence = read_value(&mut file)?;
                 sequences.push(sequence);
             }
 
@@ -573,101 +1080,142 @@ impl MdlFile {
         let sequence_groups = {
             let mut sequence_groups = Vec::new();
 
-            file.seek(SeekFrom::Start(header.seq_group_offset as u64))
-                .unwrap();
+            seek(&mut file, header.seq_group_offset)?;
             for _ in 0..header.seq_group_count {
-                let group: AnimationSequenceGroup = bincode::deserialize_from(&mut file).unwrap();
+                let group: AnimationSequenceGroup = read_value(&mut file)?;
                 sequence_groups.push(group);
             }
 
             sequence_groups
         };
 
-        // Copy file data
-        let file_data = {
-            file.seek(SeekFrom::Start(0)).unwrap();
-            let mut file_data = vec![0u8; file_size as usize];
-            file.read(&mut file_data).unwrap();
-            file_data
-        };
+        // Sequences outside of group 0 keep their animations in files of
+        // their own. The group stores the path of its file, which is usually
+        // "<name>01.mdl", "<name>02.mdl", etc.
+        let mut sequence_group_data = vec![None; sequence_groups.len()];
+        let mut missing_sequence_groups = Vec::new();
+        for (i, group_data) in sequence_group_data.iter_mut().enumerate().skip(1) {
+            if !sequences
+                .iter()
+                .any(|sequence| sequence.sequence_group == i as i32)
+            {
+                continue;
+            }
+            let group_file_name = sequence_group_file_name(&sequence_groups[i], &file_stem, i);
+            let Ok(data) = resolver.read_file(&group_file_name) else {
+                missing_sequence_groups.push(group_file_name);
+                continue;
+            };
+            let group_header: SequenceGroupHeader = read_value(&mut Cursor::new(data.as_slice()))?;
+            group_header.validate()?;
+            *group_data = Some(data);
+        }
 
         // Animations
-        let mut animations = Vec::new();
+        let mut animations = Vec::with_capacity(sequences.len());
         for animated_sequence in &sequences {
-            let name = null_terminated_bytes_to_str(&animated_sequence.name);
-
-            // TODO: Load other files
-            if animated_sequence.sequence_group == 0 {
-                //println!("  {}", name);
-
-                let sequence_group = &sequence_groups[animated_sequence.sequence_group as usize];
-                assert_eq!(sequence_group.unused_2, 0);
-                let animation_offset = /*sequence_group.unused_2 as usize +*/ animated_sequence.animation_offset as usize;
-                let animation_data = &file_data[animation_offset..];
-                let animation_value_offsets_ptr =
-                    animation_data.as_ptr() as *const AnimationValueOffsets;
-
-                let mut bone_animations = Vec::new();
-                for i in 0..bones.len() {
-                    //println!("    Bone {}:", i);
-
-                    let animation_value_offsets =
-                        unsafe { animation_value_offsets_ptr.add(i).as_ref().unwrap() };
-                    let animation_data =
-                        { animation_value_offsets as *const AnimationValueOffsets as *const u8 };
-
-                    let mut channels = Vec::new();
-                    for (j, offset) in animation_value_offsets.offsets.iter().enumerate() {
-                        if *offset != 0 {
-                            let anim_value_ptr = unsafe {
-                                animation_data.add(*offset as usize) as *const AnimationValue
-                            };
-                            let scale = bones[i].scale[j];
-
-                            //println!("      ({})", scale);
-                            //print!("      ");
-                            let mut keyframes = Vec::new();
-                            let target = ComponentTransformTarget::from_index(j);
-                            for frame in 0..animated_sequence.num_frames as i32 {
-                                let mut value =
-                                    unsafe { decode_animation_frame(anim_value_ptr, frame, scale) };
-                                value += bones[i].value[j];
-                                //print!("{}:{}, ", frame, value);
-                                keyframes.push(value);
-                            }
-                            //println!();
-
-                            channels.push(BoneChannelAnimation { target, keyframes })
-                        }
-                    }
-
-                    if !channels.is_empty() {
-                        bone_animations.push(BoneAnimation {
-                            target: i,
-                            channels,
-                        })
-                    }
-                }
-
-                animations.push(Animation {
-                    name: name.to_owned(),
-                    fps: animated_sequence.fps,
-                    bone_animations,
-                })
-            }
+            let animation_data = match animated_sequence.sequence_group {
+                0 => Some(file_data.as_slice()),
+                group => sequence_group_data
+                    .get(group as usize)
+                    .ok_or_else(|| MdlError::InvalidSequenceGroup {
+                        sequence: null_terminated_bytes_to_string_lossy(&animated_sequence.name),
+                        group,
+                    })?
+                    .as_deref(),
+            };
+            // Events are stored in the main file, even for sequence groups
+            let events = read_events(&mut file, animated_sequence)?;
+            animations.push(match animation_data {
+                Some(data) => read_animation(data, animated_sequence, &bones, events)?,
+                None => unresolved_animation(animated_sequence, events),
+            });
         }
 
-        MdlFile {
+        Ok(MdlFile {
             name: file_name,
-            textures: textures,
-            body_parts: body_parts,
+            textures,
+            body_parts,
             bones,
             animation_sequences: sequences,
             animation_sequence_groups: sequence_groups,
             animations,
-            header: header,
+            bone_controllers,
+            attachments,
+            hitboxes,
+            missing_sequence_groups,
+            header,
             raw_data: file_data,
+        })
+    }
+
+    // Evaluates the local pose of every bone for a sequence. The frame can be
+    // fractional, and the blend values are in the units of the sequence's
+    // blend types (e.g. degrees of pitch for aim blends). Like the engine,
+    // sequences with 2 blends use the first value and sequences with 4 blends
+    // use both.
+    pub fn blended_pose(
+        &self,
+        sequence: usize,
+        frame: f32,
+        blend: [f32; 2],
+    ) -> Option<Vec<BonePose>> {
+        let animated_sequence = self.animation_sequences.get(sequence)?;
+        let animation = self.animations.get(sequence)?;
+        let blend_pose = |index: usize| self.blend_pose(&animation.blends[index], frame);
+        let weights = [
+            animated_sequence.blend_weight(0, blend[0]),
+            animated_sequence.blend_weight(1, blend[1]),
+        ];
+
+        let pose = match animation.blends.len() {
+            1 => blend_pose(0),
+            2 | 3 => mix_poses(&blend_pose(0), &blend_pose(1), weights[0]),
+            _ => {
+                let first = mix_poses(&blend_pose(0), &blend_pose(1), weights[0]);
+                let second = mix_poses(&blend_pose(2), &blend_pose(3), weights[0]);
+                mix_poses(&first, &second, weights[1])
+            }
+        };
+        Some(pose)
+    }
+
+    fn blend_pose(&self, bone_animations: &[BoneAnimation], frame: f32) -> Vec<BonePose> {
+        let frame = frame.max(0.0);
+        let first = self.sample_blend(bone_animations, frame as usize);
+        let second = self.sample_blend(bone_animations, frame as usize + 1);
+        let s = frame.fract();
+        first
+            .iter()
+            .zip(second.iter())
+            .map(|(first, second)| BonePose {
+                translation: [
+                    lerp(first[0], second[0], s),
+                    lerp(first[1], second[1], s),
+                    lerp(first[2], second[2], s),
+                ],
+                rotation: quaternion_slerp(
+                    angle_quaternion([first[3], first[4], first[5]]),
+                    angle_quaternion([second[3], second[4], second[5]]),
+                    s,
+                ),
+            })
+            .collect()
+    }
+
+    // Returns the 6 channel values of every bone, frames past the end hold
+    // the last frame.
+    fn sample_blend(&self, bone_animations: &[BoneAnimation], frame: usize) -> Vec<[f32; 6]> {
+        let mut values: Vec<_> = self.bones.iter().map(|bone| bone.value).collect();
+        for bone_animation in bone_animations {
+            for channel in &bone_animation.channels {
+                let keyframes = &channel.keyframes;
+                if let Some(value) = keyframes.get(frame).or(keyframes.last()) {
+                    values[bone_animation.target][channel.target.index()] = *value;
+                }
+            }
         }
+        values
     }
 
     // TODO: Remove
@@ -676,14 +1224,139 @@ impl MdlFile {
     }
 }
 
-fn read_textures<T: Read + Seek>(mut reader: &mut T, header: &MdlHeader) -> Vec<MdlTexture> {
+// Animation offsets are relative to the start of the file that holds the
+// sequence's group.
+fn read_animation(
+    data: &[u8],
+    animated_sequence: &AnimationSequence,
+    bones: &[BoneHeader],
+    events: Vec<AnimationEvent>,
+) -> Result<Animation, MdlError> {
+    let name = null_terminated_bytes_to_string_lossy(&animated_sequence.name);
+    let animation_offset = animated_sequence.animation_offset as usize;
+    let invalid_animation = |offset| MdlError::InvalidAnimation {
+        sequence: name.clone(),
+        offset,
+    };
+
+    if animated_sequence.num_blends > MAX_BLENDS {
+        return Err(MdlError::TooManyBlends {
+            sequence: name,
+            count: animated_sequence.num_blends,
+        });
+    }
+
+    // Each blend has its own set of offsets for every bone
+    let mut blends = Vec::new();
+    for blend in 0..animated_sequence.num_blends.max(1) as usize {
+        let blend_offset =
+            animation_offset + (blend * bones.len() * std::mem::size_of::<AnimationValueOffsets>());
+        blends.push(read_blend(
+            data,
+            blend_offset,
+            animated_sequence.num_frames,
+            bones,
+            invalid_animation,
+        )?);
+    }
+
+    Ok(Animation {
+        name,
+        fps: animated_sequence.fps,
+        blends,
+        events,
+    })
+}
+
+// Stands in for a sequence whose group file is missing. No bone is animated,
+// so every blend is the rest pose.
+fn unresolved_animation(
+    animated_sequence: &AnimationSequence,
+    events: Vec<AnimationEvent>,
+) -> Animation {
+    let blend_count = animated_sequence.num_blends.clamp(1, MAX_BLENDS) as usize;
+    Animation {
+        name: null_terminated_bytes_to_string_lossy(&animated_sequence.name),
+        fps: animated_sequence.fps,
+        blends: vec![Vec::new(); blend_count],
+        events,
+    }
+}
+
+fn read_blend<F: Fn(usize) -> MdlError>(
+    data: &[u8],
+    blend_offset: usize,
+    num_frames: u32,
+    bones: &[BoneHeader],
+    invalid_animation: F,
+) -> Result<Vec<BoneAnimation>, MdlError> {
+    let mut bone_animations = Vec::new();
+    for (i, bone) in bones.iter().enumerate() {
+        let offsets_offset = blend_offset + (i * std::mem::size_of::<AnimationValueOffsets>());
+        let animation_value_offsets = data
+            .get(offsets_offset..)
+            .and_then(AnimationValueOffsets::read)
+            .ok_or_else(|| invalid_animation(offsets_offset))?;
+
+        let mut channels = Vec::new();
+        for (j, offset) in animation_value_offsets.offsets.iter().enumerate() {
+            if *offset != 0 {
+                let values_offset = offsets_offset + *offset as usize;
+                let anim_values = data
+                    .get(values_offset..)
+                    .ok_or_else(|| invalid_animation(values_offset))?;
+                let scale = bone.scale[j];
+
+                let mut keyframes = Vec::new();
+                let target = ComponentTransformTarget::from_index(j);
+                for frame in 0..num_frames as i32 {
+                    let mut value = decode_animation_frame(anim_values, frame, scale)
+                        .ok_or_else(|| invalid_animation(values_offset))?;
+                    value += bone.value[j];
+                    keyframes.push(value);
+                }
+
+                channels.push(BoneChannelAnimation { target, keyframes })
+            }
+        }
+
+        if !channels.is_empty() {
+            bone_animations.push(BoneAnimation {
+                target: i,
+                channels,
+            })
+        }
+    }
+
+    Ok(bone_animations)
+}
+
+fn read_events<R: Read + Seek>(
+    reader: &mut R,
+    animated_sequence: &AnimationSequence,
+) -> Result<Vec<AnimationEvent>, MdlError> {
+    let mut events = Vec::new();
+    seek(reader, animated_sequence.event_offset)?;
+    for _ in 0..animated_sequence.num_events {
+        let event_header: EventHeader = read_value(reader)?;
+        events.push(AnimationEvent {
+            frame: event_header.frame,
+            id: event_header.event,
+            options: null_terminated_bytes_to_string_lossy(event_header.options()),
+        });
+    }
+    Ok(events)
+}
+
+fn read_textures<T: Read + Seek>(
+    reader: &mut T,
+    header: &MdlHeader,
+) -> Result<Vec<MdlTexture>, MdlError> {
     let num_textures = header.texture_count as usize;
     let mut texture_headers = Vec::with_capacity(num_textures);
-    reader
-        .seek(SeekFrom::Start(header.texture_offset as u64))
-        .unwrap();
+    seek(reader, header.texture_offset)?;
     for _ in 0..num_textures {
-        let texture_header: TextureHeader = bincode::deserialize_from(&mut reader).unwrap();
+        let texture_header: TextureHeader = read_value(reader)?;
         texture_headers.push(texture_header);
     }
 
@@ -691,21 +1364,21 @@ fn read_textures<T: Read + Seek>(mut reader: &mut T, header: &MdlHeader) -> Vec<
     for texture_header in &texture_headers {
         let name_string = texture_header.name_string();
 
-        let mut image_data = vec![0u8; (texture_header.width * texture_header.height) as usize];
-        reader
-            .seek(SeekFrom::Start(texture_header.offset as u64))
-            .unwrap();
-        reader.read_exact(image_data.as_mut_slice()).unwrap();
+        seek(reader, texture_header.offset)?;
+        let image_data = read_bytes(
+            reader,
+            texture_header.width as u64 * texture_header.height as u64,
+        )?;
+        let palette_data = read_bytes(reader, PALETTE_LEN as u64)?;
 
-        let mut palette_data = [0u8; 256 * 3];
-        reader.read_exact(&mut palette_data).unwrap();
-
-        let converted_image = create_image(
+        let converted_image = decode_paletted_image(
             &image_data,
             &palette_data,
             texture_header.width,
             texture_header.height,
-        );
+            None,
+        )
+        .ok_or_else(|| MdlError::InvalidTexture(name_string.to_string()))?;
 
         textures.push(MdlTexture {
             name: name_string.to_string(),
@@ -715,69 +1388,780 @@ fn read_textures<T: Read + Seek>(mut reader: &mut T, header: &MdlHeader) -> Vec<
         });
     }
 
-    textures
-}
-
-// TODO: Consolodate these image decoders into one crate
-fn create_image(
-    image_data: &[u8],
-    palette_data: &[u8],
-    texture_width: u32,
-    texture_height: u32,
-) -> image::ImageBuffer<image::Rgba<u8>, Vec<u8>> {
-    let mut image_rgba_data = Vec::<u8>::new();
-    for palette_index in image_data {
-        let index = (*palette_index as usize) * 3;
-        let r_color = palette_data[index + 0];
-        let g_color = palette_data[index + 1];
-        let b_color = palette_data[index + 2];
-
-        if r_color == 0 && g_color == 0 && b_color == 255 {
-            image_rgba_data.push(0);
-            image_rgba_data.push(0);
-            image_rgba_data.push(0);
-            image_rgba_data.push(0);
-        } else {
-            image_rgba_data.push(r_color);
-            image_rgba_data.push(g_color);
-            image_rgba_data.push(b_color);
-            image_rgba_data.push(255);
-        }
+    Ok(textures)
+}
+
+pub fn null_terminated_bytes_to_str(bytes: &[u8]) -> Result<&str, std::str::Utf8Error> {
+    let end = bytes.iter().position(|x| *x == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..end])
+}
+
+// The name stored in a model may include the directories it was compiled to
+fn name_stem(name: &str) -> &str {
+    let file_name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    Path::new(file_name)
+        .file_stem()
+        .and_then(|file_stem| file_stem.to_str())
+        .unwrap_or(file_name)
+}
+
+// Only the file name of the stored path is used, the resolver decides where
+// to look for it. Groups without a name fall back to the engine's naming.
+fn sequence_group_file_name(
+    group: &AnimationSequenceGroup,
+    file_stem: &str,
+    index: usize,
+) -> String {
+    let name = null_terminated_bytes_to_string_lossy(group.name());
+    match name.rsplit(['/', '\\']).next() {
+        Some(file_name) if !file_name.is_empty() => file_name.to_owned(),
+        _ => format!("{}{:02}.mdl", file_stem, index),
     }
+}
 
-    image::ImageBuffer::<image::Rgba<u8>, Vec<u8>>::from_vec(
-        texture_width,
-        texture_height,
-        image_rgba_data,
-    )
-    .unwrap()
+fn null_terminated_bytes_to_string_lossy(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|x| *x == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
 }
 
-pub fn null_terminated_bytes_to_str<'a>(bytes: &'a [u8]) -> &'a str {
-    let name = std::str::from_utf8(bytes).unwrap();
-    let end = name.find('\0').unwrap_or(name.len());
-    let name = &name[..end];
-    name
+fn read_value<T: DeserializeOwned, R: Read + Seek>(reader: &mut R) -> Result<T, MdlError> {
+    let offset = reader.stream_position()?;
+    bincode::deserialize_from(reader).map_err(|source| MdlError::InvalidData { offset, source })
 }
 
-// TODO: This code is bananas, write a safer version
-unsafe fn decode_animation_frame(
-    mut anim_value_ptr: *const AnimationValue,
-    frame: i32,
-    scale: f32,
-) -> f32 {
-    let mut k = frame;
+fn read_bytes<R: Read + Seek>(reader: &mut R, len: u64) -> Result<Vec<u8>, MdlError> {
+    let offset = reader.stream_position()?;
+    let mut data = Vec::new();
+    reader.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(MdlError::InvalidData {
+            offset,
+            source: std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into(),
+        });
+    }
+    Ok(data)
+}
 
-    while (*anim_value_ptr).encoded_value.total as i32 <= k {
-        k -= (*anim_value_ptr).encoded_value.total as i32;
-        anim_value_ptr = anim_value_ptr.add((*anim_value_ptr).encoded_value.valid as usize + 1);
+fn seek<R: Seek>(reader: &mut R, offset: u32) -> Result<(), MdlError> {
+    reader.seek(SeekFrom::Start(offset as u64))?;
+    Ok(())
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn mix_poses(first: &[BonePose], second: &[BonePose], t: f32) -> Vec<BonePose> {
+    first
+        .iter()
+        .zip(second.iter())
+        .map(|(first, second)| BonePose {
+            translation: [
+                lerp(first.translation[0], second.translation[0], t),
+                lerp(first.translation[1], second.translation[1], t),
+                lerp(first.translation[2], second.translation[2], t),
+            ],
+            rotation: quaternion_slerp(first.rotation, second.rotation, t),
+        })
+        .collect()
+}
+
+// Angles are rotations around x (roll), y (pitch) and z (yaw) in radians
+fn angle_quaternion(angles: [f32; 3]) -> [f32; 4] {
+    let (sy, cy) = (angles[2] * 0.5).sin_cos();
+    let (sp, cp) = (angles[1] * 0.5).sin_cos();
+    let (sr, cr) = (angles[0] * 0.5).sin_cos();
+    [
+        sr * cp * cy - cr * sp * sy,
+        cr * sp * cy + sr * cp * sy,
+        cr * cp * sy - sr * sp * cy,
+        cr * cp * cy + sr * sp * sy,
+    ]
+}
+
+// Matches QuaternionSlerp from the engine
+fn quaternion_slerp(p: [f32; 4], mut q: [f32; 4], t: f32) -> [f32; 4] {
+    // Take the shortest path
+    let a: f32 = (0..4).map(|i| (p[i] - q[i]).powi(2)).sum();
+    let b: f32 = (0..4).map(|i| (p[i] + q[i]).powi(2)).sum();
+    if a > b {
+        q = q.map(|value| -value);
     }
 
-    let value = if (*anim_value_ptr).encoded_value.valid as i32 > k {
-        (*anim_value_ptr.add(k as usize + 1)).value
+    let cosom: f32 = (0..4).map(|i| p[i] * q[i]).sum();
+    if 1.0 + cosom > 0.000001 {
+        let (sclp, sclq) = if 1.0 - cosom > 0.000001 {
+            let omega = cosom.acos();
+            let sinom = omega.sin();
+            (((1.0 - t) * omega).sin() / sinom, (t * omega).sin() / sinom)
+        } else {
+            (1.0 - t, t)
+        };
+        [0, 1, 2, 3].map(|i| sclp * p[i] + sclq * q[i])
     } else {
-        (*anim_value_ptr.add((*anim_value_ptr).encoded_value.valid as usize)).value
+        let qt = [-q[1], q[0], -q[3], q[2]];
+        let sclp = ((1.0 - t) * std::f32::consts::FRAC_PI_2).sin();
+        let sclq = (t * std::f32::consts::FRAC_PI_2).sin();
+        [
+            sclp * p[0] + sclq * qt[0],
+            sclp * p[1] + sclq * qt[1],
+            sclp * p[2] + sclq * qt[2],
+            qt[3],
+        ]
+    }
+}
+
+// Each run of animation values starts with a header holding how many values
+// are stored (valid) and how many frames the run covers (total). Frames past
+// the stored values repeat the last one.
+fn decode_animation_frame(data: &[u8], frame: i32, scale: f32) -> Option<f32> {
+    let read_value = |index: usize| -> Option<[u8; 2]> {
+        let bytes = data.get(index * 2..(index * 2) + 2)?;
+        Some([bytes[0], bytes[1]])
     };
-    //let value = u16::MAX - value;
-    value as f32 * scale
+
+    let mut index = 0;
+    let mut k = frame;
+    loop {
+        let [valid, total] = read_value(index)?;
+        if total == 0 {
+            return None;
+        }
+        if total as i32 > k {
+            let value_index = if valid as i32 > k {
+                index + k as usize + 1
+            } else {
+                index + valid as usize
+            };
+            let value = i16::from_le_bytes(read_value(value_index)?);
+            //let value = u16::MAX - value;
+            return Some(value as f32 * scale);
+        }
+        k -= total as i32;
+        index += valid as usize + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_LEN: usize = 244;
+
+    fn put_u32(data: &mut Vec<u8>, value: u32) {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_i32(data: &mut Vec<u8>, value: i32) {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_f32(data: &mut Vec<u8>, value: f32) {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_name(data: &mut Vec<u8>, name: &str, len: usize) {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        data.extend_from_slice(&bytes);
+    }
+
+    #[derive(Default)]
+    struct TestSequence {
+        name: &'static str,
+        group: i32,
+        // The x translation of the root bone for each frame of each blend
+        blends: Vec<Vec<i16>>,
+        // Frame, event and options
+        events: Vec<(i32, i32, &'static str)>,
+        // The start and end of each blend type that's used
+        blend_ranges: Vec<(f32, f32)>,
+    }
+
+    // A model with a single bone and a 1x1 texture
+    #[derive(Default)]
+    struct TestModel {
+        name: &'static str,
+        sequences: Vec<TestSequence>,
+        // The stored names of the groups after group 0
+        group_names: Vec<&'static str>,
+        // Keeps the texture in a "<name>t.mdl" file
+        external_textures: bool,
+        // Bone, type, start, end and index
+        bone_controllers: Vec<(i32, i32, f32, f32, i32)>,
+        // Name, bone and origin
+        attachments: Vec<(&'static str, i32, [f32; 3])>,
+        // Bone and group
+        hitboxes: Vec<(i32, i32)>,
+    }
+
+    struct TestFiles {
+        model: Vec<u8>,
+        textures: Option<Vec<u8>>,
+        groups: Vec<Vec<u8>>,
+    }
+
+    impl TestModel {
+        fn build(&self) -> TestFiles {
+            let mut data = vec![0; HEADER_LEN];
+            let mut group_files: Vec<_> = self
+                .group_names
+                .iter()
+                .map(|_| {
+                    let mut group_file = SEQUENCE_GROUP_MAGIC.to_vec();
+                    put_u32(&mut group_file, MDL_VERSION);
+                    put_name(&mut group_file, "", 64);
+                    put_u32(&mut group_file, 0);
+                    group_file
+                })
+                .collect();
+
+            let bone_offset = data.len();
+            put_name(&mut data, "root", 32);
+            put_i32(&mut data, -1);
+            put_u32(&mut data, 0);
+            (0..6).for_each(|_| put_i32(&mut data, -1));
+            (0..6).for_each(|_| put_f32(&mut data, 0.0));
+            (0..6).for_each(|_| put_f32(&mut data, 1.0));
+
+            let bone_controller_offset = data.len();
+            for (bone, ty, start, end, index) in &self.bone_controllers {
+                put_i32(&mut data, *bone);
+                put_i32(&mut data, *ty);
+                put_f32(&mut data, *start);
+                put_f32(&mut data, *end);
+                put_i32(&mut data, 0);
+                put_i32(&mut data, *index);
+            }
+
+            let attachment_offset = data.len();
+            for (name, bone, origin) in &self.attachments {
+                put_name(&mut data, name, 32);
+                put_i32(&mut data, 0);
+                put_i32(&mut data, *bone);
+                origin.iter().for_each(|value| put_f32(&mut data, *value));
+                (0..9).for_each(|_| put_f32(&mut data, 0.0));
+            }
+
+            let hitbox_offset = data.len();
+            for (bone, group) in &self.hitboxes {
+                put_i32(&mut data, *bone);
+                put_i32(&mut data, *group);
+                [-1.0, -2.0, -3.0, 1.0, 2.0, 3.0]
+                    .iter()
+                    .for_each(|value| put_f32(&mut data, *value));
+            }
+
+            let mut animation_offsets = Vec::new();
+            for sequence in &self.sequences {
+                let file = match sequence.group {
+                    0 => &mut data,
+                    group => &mut group_files[group as usize - 1],
+                };
+                animation_offsets.push(file.len());
+                Self::put_animation(file, &sequence.blends);
+            }
+
+            // Events are always in the model, even for sequence groups
+            let mut event_offsets = Vec::new();
+            for sequence in &self.sequences {
+                event_offsets.push(data.len());
+                for (frame, event, options) in &sequence.events {
+                    put_i32(&mut data, *frame);
+                    put_i32(&mut data, *event);
+                    put_i32(&mut data, 0);
+                    put_name(&mut data, options, 64);
+                }
+            }
+
+            let sequence_offset = data.len();
+            for ((sequence, animation_offset), event_offset) in self
+                .sequences
+                .iter()
+                .zip(animation_offsets)
+                .zip(event_offsets)
+            {
+                put_name(&mut data, sequence.name, 32);
+                put_f32(&mut data, 30.0);
+                // Flags, activity and activity weight
+                (0..3).for_each(|_| put_i32(&mut data, 0));
+                put_u32(&mut data, sequence.events.len() as u32);
+                put_u32(&mut data, event_offset as u32);
+                put_u32(&mut data, sequence.blends[0].len() as u32);
+                // Pivots, motion, linear movement, auto move and bounds
+                (0..4).for_each(|_| put_i32(&mut data, 0));
+                (0..3).for_each(|_| put_f32(&mut data, 0.0));
+                (0..2).for_each(|_| put_u32(&mut data, 0));
+                (0..6).for_each(|_| put_f32(&mut data, 0.0));
+                put_u32(&mut data, sequence.blends.len() as u32);
+                put_u32(&mut data, animation_offset as u32);
+                // Blend types, starts, ends and parent
+                let blend_range = |index| sequence.blend_ranges.get(index).copied();
+                for index in 0..2 {
+                    // STUDIO_XR
+                    put_i32(
+                        &mut data,
+                        if blend_range(index).is_some() { 0x8 } else { 0 },
+                    );
+                }
+                for index in 0..2 {
+                    put_f32(&mut data, blend_range(index).map_or(0.0, |range| range.0));
+                }
+                for index in 0..2 {
+                    put_f32(&mut data, blend_range(index).map_or(0.0, |range| range.1));
+                }
+                put_i32(&mut data, 0);
+                put_i32(&mut data, sequence.group);
+                // Entry, exit, node flags and next sequence
+                (0..4).for_each(|_| put_i32(&mut data, 0));
+            }
+
+            let group_offset = data.len();
+            for (label, name) in [("default", "")]
+                .into_iter()
+                .chain(self.group_names.iter().map(|name| ("", *name)))
+            {
+                put_name(&mut data, label, 32);
+                put_name(&mut data, name, 64);
+                put_i32(&mut data, 0);
+                put_i32(&mut data, 0);
+            }
+
+            let mut texture_file = vec![0; HEADER_LEN];
+            let textures = match self.external_textures {
+                true => &mut texture_file,
+                false => &mut data,
+            };
+            let texture_offset = textures.len();
+            put_name(textures, "skin.bmp", 64);
+            put_u32(textures, 0);
+            put_u32(textures, 1);
+            put_u32(textures, 1);
+            put_u32(textures, (texture_offset + 80) as u32);
+            textures.push(0);
+            textures.extend((0..=255u8).flat_map(|i| [i, 128, 255 - i]));
+
+            let counts = [
+                (1, bone_offset),
+                (self.bone_controllers.len(), bone_controller_offset),
+                (self.hitboxes.len(), hitbox_offset),
+                (self.sequences.len(), sequence_offset),
+                (self.group_names.len() + 1, group_offset),
+            ];
+            let attachments = (self.attachments.len(), attachment_offset);
+            let textures = if self.external_textures {
+                Self::put_header(
+                    &mut texture_file,
+                    self.name,
+                    [(0, 0); 5],
+                    (1, texture_offset),
+                    (0, 0),
+                );
+                Self::put_header(&mut data, self.name, counts, (0, 0), attachments);
+                Some(texture_file)
+            } else {
+                Self::put_header(
+                    &mut data,
+                    self.name,
+                    counts,
+                    (1, texture_offset),
+                    attachments,
+                );
+                None
+            };
+
+            TestFiles {
+                model: data,
+                textures,
+                groups: group_files,
+            }
+        }
+
+        // The counts and offsets of the bones, bone controllers, hitboxes,
+        // sequences and sequence groups, followed by those of the textures and
+        // attachments
+        fn put_header(
+            data: &mut [u8],
+            name: &str,
+            counts: [(usize, usize); 5],
+            textures: (usize, usize),
+            attachments: (usize, usize),
+        ) {
+            let mut header = MDL_MAGIC.to_vec();
+            put_u32(&mut header, MDL_VERSION);
+            put_name(&mut header, name, 64);
+            put_u32(&mut header, data.len() as u32);
+            // Eye position, hull and view bounds, and flags
+            (0..15).for_each(|_| put_f32(&mut header, 0.0));
+            put_u32(&mut header, 0);
+            for (count, offset) in counts {
+                put_u32(&mut header, count as u32);
+                put_u32(&mut header, offset as u32);
+            }
+            put_u32(&mut header, textures.0 as u32);
+            put_u32(&mut header, textures.1 as u32);
+            put_u32(&mut header, (textures.1 + 80) as u32);
+            // Skins and body parts
+            (0..5).for_each(|_| put_u32(&mut header, 0));
+            put_u32(&mut header, attachments.0 as u32);
+            put_u32(&mut header, attachments.1 as u32);
+            // Sounds and transitions
+            (0..6).for_each(|_| put_u32(&mut header, 0));
+            assert_eq!(header.len(), HEADER_LEN);
+            data[..HEADER_LEN].copy_from_slice(&header);
+        }
+
+        // Every frame is stored, so each blend is a single run of values
+        fn put_animation(data: &mut Vec<u8>, blends: &[Vec<i16>]) {
+            let offsets_len = std::mem::size_of::<AnimationValueOffsets>();
+            let start = data.len();
+            let mut values_offset = start + blends.len() * offsets_len;
+            for (i, frames) in blends.iter().enumerate() {
+                let offsets_offset = start + i * offsets_len;
+                data.extend_from_slice(&((values_offset - offsets_offset) as u16).to_le_bytes());
+                data.extend_from_slice(&[0; 10]);
+                values_offset += (frames.len() + 1) * 2;
+            }
+            for frames in blends {
+                data.extend_from_slice(&[frames.len() as u8, frames.len() as u8]);
+                for value in frames {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    fn keyframes(file: &MdlFile, sequence: usize, blend: usize) -> &[f32] {
+        &file.animations[sequence].blends[blend][0].channels[0].keyframes
+    }
+
+    #[test]
+    fn models_load_from_bytes_and_readers() {
+        let model = TestModel {
+            name: "models/test.mdl",
+            sequences: vec![TestSequence {
+                name: "idle",
+                blends: vec![vec![1, 2]],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+        .build()
+        .model;
+
+        for file in [
+            MdlFile::from_bytes(model.clone()).unwrap(),
+            MdlFile::from_reader(Cursor::new(model.clone())).unwrap(),
+        ] {
+            assert_eq!(file.name, "models/test.mdl");
+            assert_eq!(file.bones.len(), 1);
+            assert_eq!(keyframes(&file, 0, 0), [1.0, 2.0]);
+            assert_eq!(file.textures[0].name, "skin.bmp");
+            assert_eq!(
+                file.textures[0].image_data.get_pixel(0, 0).0,
+                [0, 128, 255, 255]
+            );
+            assert_eq!(file.raw_data(), model);
+        }
+    }
+
+    #[test]
+    fn textures_are_loaded_from_companion_files() {
+        let files = TestModel {
+            name: "models/test.mdl",
+            external_textures: true,
+            ..Default::default()
+        }
+        .build();
+        let textures = files.textures.unwrap();
+
+        let error = MdlFile::from_bytes(files.model.clone()).unwrap_err();
+        assert!(matches!(
+            error,
+            MdlError::TextureFile { name, .. } if name == "testt.mdl"
+        ));
+
+        let resolver = |file_name: &str| match file_name {
+            "testt.mdl" => Ok(textures.clone()),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+        };
+        let file =
+            MdlFile::from_reader_with_resolver(Cursor::new(files.model.clone()), resolver).unwrap();
+        assert_eq!(file.textures[0].name, "skin.bmp");
+
+        // Opening a file names its companions after the file instead
+        let directory = std::env::temp_dir().join(format!("gsparser-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("other.mdl"), &files.model).unwrap();
+        std::fs::write(directory.join("othert.mdl"), &textures).unwrap();
+        let file = MdlFile::open(directory.join("other.mdl"));
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(file.unwrap().textures[0].name, "skin.bmp");
+    }
+
+    #[test]
+    fn sequence_groups_are_loaded_from_their_own_files() {
+        let model = TestModel {
+            name: "models/test.mdl",
+            sequences: vec![
+                TestSequence {
+                    name: "idle",
+                    blends: vec![vec![1, 2]],
+                    ..Default::default()
+                },
+                TestSequence {
+                    name: "walk",
+                    group: 1,
+                    blends: vec![vec![3, 4, 5]],
+                    ..Default::default()
+                },
+            ],
+            group_names: vec![""],
+            ..Default::default()
+        };
+        let files = model.build();
+
+        let resolver = |file_name: &str| match file_name {
+            "test01.mdl" => Ok(files.groups[0].clone()),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+        };
+        let file =
+            MdlFile::from_reader_with_resolver(Cursor::new(files.model.clone()), resolver).unwrap();
+        assert_eq!(keyframes(&file, 0, 0), [1.0, 2.0]);
+        assert_eq!(keyframes(&file, 1, 0), [3.0, 4.0, 5.0]);
+
+        assert!(file.missing_sequence_groups.is_empty());
+
+        // Without the group's file, its sequences can't move any bones
+        let file = MdlFile::from_bytes(files.model).unwrap();
+        assert_eq!(file.missing_sequence_groups, ["test01.mdl"]);
+        assert_eq!(keyframes(&file, 0, 0), [1.0, 2.0]);
+        assert_eq!(file.animations[1].name, "walk");
+        assert!(file.animations[1].blends[0].is_empty());
+    }
+
+    #[test]
+    fn bone_controllers_attachments_and_hitboxes_are_read() {
+        let model = TestModel {
+            name: "test.mdl",
+            bone_controllers: vec![
+                (
+                    0,
+                    bone_controller_type::YR | bone_controller_type::RLOOP,
+                    -180.0,
+                    180.0,
+                    0,
+                ),
+                (0, bone_controller_type::X, 0.0, 10.0, MOUTH_CONTROLLER),
+            ],
+            attachments: vec![("muzzle", 0, [1.0, 2.0, 3.0])],
+            hitboxes: vec![(0, 1), (0, 12)],
+            ..Default::default()
+        };
+        let file = MdlFile::from_bytes(model.build().model).unwrap();
+
+        let controllers = &file.bone_controllers;
+        assert_eq!(controllers.len(), 2);
+        assert!(matches!(
+            controllers[0].target,
+            ComponentTransformTarget::Rotation(VectorChannel::Y)
+        ));
+        assert!(controllers[0].wraps);
+        assert_eq!((controllers[0].start, controllers[0].end), (-180.0, 180.0));
+        assert!(matches!(
+            controllers[1].target,
+            ComponentTransformTarget::Translation(VectorChannel::X)
+        ));
+        assert!(!controllers[1].wraps);
+        assert_eq!(controllers[1].index, MOUTH_CONTROLLER);
+
+        assert_eq!(file.attachments[0].name, "muzzle");
+        assert_eq!(file.attachments[0].bone, 0);
+        assert_eq!(file.attachments[0].origin, [1.0, 2.0, 3.0]);
+
+        assert_eq!(file.hitboxes[0].group, HitGroup::Head);
+        assert_eq!(file.hitboxes[1].group, HitGroup::Other(12));
+        assert_eq!(file.hitboxes[1].min, [-1.0, -2.0, -3.0]);
+        assert_eq!(file.hitboxes[1].max, [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn missing_bones_and_unknown_controllers_are_errors() {
+        let hitbox = TestModel {
+            name: "test.mdl",
+            hitboxes: vec![(3, 0)],
+            ..Default::default()
+        };
+        assert!(matches!(
+            MdlFile::from_bytes(hitbox.build().model),
+            Err(MdlError::InvalidBone(3))
+        ));
+
+        let attachment = TestModel {
+            name: "test.mdl",
+            attachments: vec![("muzzle", -2, [0.0; 3])],
+            ..Default::default()
+        };
+        assert!(matches!(
+            MdlFile::from_bytes(attachment.build().model),
+            Err(MdlError::InvalidBone(-2))
+        ));
+
+        let controller = TestModel {
+            name: "test.mdl",
+            bone_controllers: vec![(0, 0x40, 0.0, 1.0, 0)],
+            ..Default::default()
+        };
+        assert!(matches!(
+            MdlFile::from_bytes(controller.build().model),
+            Err(MdlError::InvalidBoneController(0x40))
+        ));
+    }
+
+    #[test]
+    fn events_are_read_for_every_sequence() {
+        let model = TestModel {
+            name: "test.mdl",
+            sequences: vec![
+                TestSequence {
+                    name: "shoot",
+                    blends: vec![vec![0, 0, 0]],
+                    events: vec![(0, 5011, ""), (2, 5004, "weapons/shot.wav")],
+                    ..Default::default()
+                },
+                TestSequence {
+                    name: "die",
+                    group: 1,
+                    blends: vec![vec![0, 0]],
+                    events: vec![(1, 1000, "")],
+                    ..Default::default()
+                },
+            ],
+            group_names: vec!["test01.mdl"],
+            ..Default::default()
+        };
+        let files = model.build();
+        let resolver = |_: &str| Ok(files.groups[0].clone());
+        let file = MdlFile::from_reader_with_resolver(Cursor::new(files.model), resolver).unwrap();
+
+        let events = &file.animations[0].events;
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].kind(),
+            AnimationEventKind::MuzzleFlash { attachment: 1 }
+        );
+        assert_eq!(events[1].frame, 2);
+        assert_eq!(events[1].kind(), AnimationEventKind::Sound);
+        assert_eq!(events[1].options, "weapons/shot.wav");
+
+        let events = &file.animations[1].events;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].frame, 1);
+        assert_eq!(
+            events[0].kind(),
+            AnimationEventKind::Script(ScriptEvent::Dead)
+        );
+    }
+
+    #[test]
+    fn poses_mix_blends_by_their_blend_values() {
+        let model = TestModel {
+            name: "test.mdl",
+            sequences: vec![
+                TestSequence {
+                    name: "aim",
+                    blends: vec![vec![0, 10], vec![20, 30]],
+                    blend_ranges: vec![(-45.0, 45.0)],
+                    ..Default::default()
+                },
+                TestSequence {
+                    name: "look",
+                    blends: vec![vec![1], vec![2], vec![3], vec![4]],
+                    blend_ranges: vec![(-45.0, 45.0), (0.0, 90.0)],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let file = MdlFile::from_bytes(model.build().model).unwrap();
+        assert_eq!(file.animations[0].blends.len(), 2);
+        assert_eq!(keyframes(&file, 0, 1), [20.0, 30.0]);
+        let x = |sequence, frame, blend| {
+            file.blended_pose(sequence, frame, blend).unwrap()[0].translation[0]
+        };
+
+        assert_eq!(x(0, 0.0, [-45.0, 0.0]), 0.0);
+        assert_eq!(x(0, 0.0, [0.0, 0.0]), 10.0);
+        assert_eq!(x(0, 0.5, [45.0, 0.0]), 25.0);
+        // Values past the ends of the range are clamped
+        assert_eq!(x(0, 1.0, [90.0, 0.0]), 30.0);
+
+        // With 4 blends, the second value mixes the first and second pairs
+        assert_eq!(x(1, 0.0, [45.0, 0.0]), 2.0);
+        assert_eq!(x(1, 0.0, [-45.0, 90.0]), 3.0);
+        assert_eq!(x(1, 0.0, [0.0, 45.0]), 2.5);
+
+        let pose = file.blended_pose(0, 0.0, [0.0, 0.0]).unwrap();
+        assert_eq!(pose[0].rotation, [0.0, 0.0, 0.0, 1.0]);
+        assert!(file.blended_pose(2, 0.0, [0.0, 0.0]).is_none());
+    }
+
+    #[test]
+    fn sequence_groups_are_found_by_their_stored_name() {
+        let model = TestModel {
+            name: "models/test.mdl",
+            sequences: vec![
+                TestSequence {
+                    name: "idle",
+                    blends: vec![vec![1, 2]],
+                    ..Default::default()
+                },
+                TestSequence {
+                    name: "walk",
+                    group: 1,
+                    blends: vec![vec![3, 4, 5]],
+                    ..Default::default()
+                },
+            ],
+            group_names: vec!["models\\renamed_anims.mdl"],
+            ..Default::default()
+        };
+        let files = model.build();
+
+        let mut requested = Vec::new();
+        let resolver = |file_name: &str| {
+            requested.push(file_name.to_owned());
+            match file_name {
+                "renamed_anims.mdl" => Ok(files.groups[0].clone()),
+                _ => Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+            }
+        };
+        let file = MdlFile::from_reader_with_resolver(Cursor::new(files.model), resolver).unwrap();
+        assert_eq!(requested, ["renamed_anims.mdl"]);
+        assert_eq!(keyframes(&file, 0, 0), [1.0, 2.0]);
+        assert_eq!(keyframes(&file, 1, 0), [3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn blend_counts_are_limited() {
+        let model = |blend_count| {
+            TestModel {
+                name: "test.mdl",
+                sequences: vec![TestSequence {
+                    name: "aim",
+                    blends: vec![vec![1]; blend_count],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }
+            .build()
+            .model
+        };
+
+        let file = MdlFile::from_bytes(model(4)).unwrap();
+        assert_eq!(file.animations[0].blends.len(), 4);
+
+        let error = MdlFile::from_bytes(model(5)).unwrap_err();
+        assert!(matches!(error, MdlError::TooManyBlends { count: 5, .. }));
+    }
 }
@@ -0,0 +1,182 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+use color_quant::NeuQuant;
+use image::RgbaImage;
+
+pub const PALETTE_LEN: usize = 256 * 3;
+
+// Pure blue marks transparent pixels in GoldSrc textures
+const TRANSPARENT_COLOR: [u8; 3] = [0, 0, 255];
+// The palette index that transparent pixels use
+pub const TRANSPARENT_INDEX: u8 = 255;
+// Pixels below this alpha are treated as transparent when quantizing
+const ALPHA_THRESHOLD: u8 = 128;
+
+// Expands 8-bit palette indices to RGBA. Pixels that are pure blue, or that
+// use the alpha key, are left transparent. Returns None if the data doesn't
+// match the dimensions or the palette has fewer than 256 colors.
+pub fn decode_paletted_image(
+    image_data: &[u8],
+    palette_data: &[u8],
+    width: u32,
+    height: u32,
+    alpha_key: Option<u8>,
+) -> Option<RgbaImage> {
+    if palette_data.len() < PALETTE_LEN {
+        return None;
+    }
+
+    let mut image_rgba_data = Vec::with_capacity(image_data.len() * 4);
+    for palette_index in image_data {
+        let index = (*palette_index as usize) * 3;
+        let color = &palette_data[index..index + 3];
+        if color == TRANSPARENT_COLOR || Some(*palette_index) == alpha_key {
+            image_rgba_data.extend_from_slice(&[0, 0, 0, 0]);
+        } else {
+            image_rgba_data.extend_from_slice(&[color[0], color[1], color[2], 255]);
+        }
+    }
+
+    RgbaImage::from_vec(width, height, image_rgba_data)
+}
+
+pub fn greyscale_palette() -> Vec<u8> {
+    (0..=255u8).flat_map(|value| [value; 3]).collect()
+}
+
+// Builds a 256 color palette for an image and maps colors to it. Images with
+// 256 colors or less keep their exact colors. With an alpha key, the last
+// entry is reserved for pure blue and transparent pixels are mapped to it.
+pub struct PaletteQuantizer {
+    palette: Vec<u8>,
+    // The entries that were filled in, the rest of the palette is padding
+    color_count: usize,
+    alpha_key: bool,
+    // Downsampled mip levels mostly reuse the same colors
+    cache: RefCell<HashMap<[u8; 3], u8>>,
+}
+
+impl PaletteQuantizer {
+    pub fn new(image: &RgbaImage, alpha_key: bool) -> Self {
+        let max_colors = if alpha_key { 255 } else { 256 };
+        let mut colors = Vec::new();
+        let mut unique_colors = HashSet::new();
+        for pixel in image.pixels() {
+            if alpha_key && pixel[3] < ALPHA_THRESHOLD {
+                continue;
+            }
+            let color = [pixel[0], pixel[1], pixel[2]];
+            colors.extend_from_slice(&[color[0], color[1], color[2], 255]);
+            if unique_colors.len() <= max_colors {
+                unique_colors.insert(color);
+            }
+        }
+
+        let mut palette = if unique_colors.len() <= max_colors {
+            let mut unique_colors: Vec<_> = unique_colors.into_iter().collect();
+            unique_colors.sort();
+            unique_colors.concat()
+        } else {
+            // Textures are small enough to learn from every pixel
+            NeuQuant::new(1, max_colors, &colors).color_map_rgb()
+        };
+        // Opaque colors can't be pure blue, or they would become transparent
+        for color in palette.chunks_exact_mut(3) {
+            if color == TRANSPARENT_COLOR {
+                color[2] = 254;
+            }
+        }
+        let color_count = (palette.len() / 3).max(1);
+        palette.resize(PALETTE_LEN, 0);
+        if alpha_key {
+            let index = TRANSPARENT_INDEX as usize * 3;
+            palette[index..index + 3].copy_from_slice(&TRANSPARENT_COLOR);
+        }
+
+        Self {
+            palette,
+            color_count,
+            alpha_key,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn palette(&self) -> &[u8] {
+        &self.palette
+    }
+
+    // Returns the palette index of every pixel of the image.
+    pub fn quantize(&self, image: &RgbaImage) -> Vec<u8> {
+        image
+            .pixels()
+            .map(|pixel| {
+                if self.alpha_key && pixel[3] < ALPHA_THRESHOLD {
+                    TRANSPARENT_INDEX
+                } else {
+                    self.closest_index([pixel[0], pixel[1], pixel[2]])
+                }
+            })
+            .collect()
+    }
+
+    fn closest_index(&self, color: [u8; 3]) -> u8 {
+        *self.cache.borrow_mut().entry(color).or_insert_with(|| {
+            let distance = |entry: &[u8]| -> i32 {
+                (0..3)
+                    .map(|i| (entry[i] as i32 - color[i] as i32).pow(2))
+                    .sum()
+            };
+            self.palette
+                .chunks_exact(3)
+                .take(self.color_count)
+                .enumerate()
+                .min_by_key(|(_, entry)| distance(entry))
+                .map(|(index, _)| index as u8)
+                .unwrap()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palettes_keep_exact_colors() {
+        let image = RgbaImage::from_fn(4, 4, |x, _| image::Rgba([x as u8 * 64, 0, 0, 255]));
+        let quantizer = PaletteQuantizer::new(&image, false);
+        let indices = quantizer.quantize(&image);
+        let decoded = decode_paletted_image(&indices, quantizer.palette(), 4, 4, None).unwrap();
+        assert_eq!(decoded, image);
+    }
+
+    #[test]
+    fn padding_is_not_matched() {
+        let image = RgbaImage::from_fn(2, 1, |x, _| {
+            let value = if x == 0 { 10 } else { 250 };
+            image::Rgba([value, value, value, 255])
+        });
+        let quantizer = PaletteQuantizer::new(&image, false);
+        // Colors that aren't in the image, like those of smaller mip levels,
+        // map to the closest real color rather than the black padding
+        let black = RgbaImage::from_pixel(1, 1, image::Rgba([0, 0, 0, 255]));
+        let index = quantizer.quantize(&black)[0] as usize * 3;
+        assert_eq!(quantizer.palette()[index..index + 3], [10, 10, 10]);
+    }
+
+    #[test]
+    fn transparent_pixels_use_the_alpha_key() {
+        let image = RgbaImage::from_fn(2, 1, |x, _| {
+            image::Rgba([0, 0, 255, if x == 0 { 0 } else { 255 }])
+        });
+        let quantizer = PaletteQuantizer::new(&image, true);
+        let indices = quantizer.quantize(&image);
+        assert_eq!(indices[0], TRANSPARENT_INDEX);
+        // Opaque pure blue is nudged so it stays visible
+        let index = indices[1] as usize * 3;
+        assert_eq!(quantizer.palette()[index..index + 3], [0, 0, 254]);
+    }
+}
@@ -3,13 +3,152 @@ extern crate byteorder;
 extern crate image;
 extern crate serde;
 
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
 use std::fs::File;
 use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
 use std::path::Path;
 use std::str;
+use std::sync::{Arc, Mutex};
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use serde::Deserialize;
+use image::{imageops::FilterType, RgbaImage};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use crate::mdl::null_terminated_bytes_to_str;
+use crate::palette::{decode_paletted_image, greyscale_palette, PaletteQuantizer, PALETTE_LEN};
+
+const WAD3_MAGIC: [u8; 4] = *b"WAD3";
+const WAD2_MAGIC: [u8; 4] = *b"WAD2";
+const ENTRY_NAME_LEN: usize = 16;
+const DEFAULT_CACHE_CAPACITY: usize = 64;
+
+#[derive(Debug)]
+pub enum WadError {
+    Io(std::io::Error),
+    InvalidHeader(bincode::Error),
+    InvalidMagic([u8; 4]),
+    InvalidDirectory {
+        offset: u64,
+        source: bincode::Error,
+    },
+    InvalidEntryName {
+        offset: u64,
+        source: str::Utf8Error,
+    },
+    UnknownEntryType {
+        name: String,
+        ty: u8,
+    },
+    EntryOutOfBounds {
+        name: String,
+        offset: u32,
+        size: u32,
+    },
+    UnexpectedEntryType {
+        name: String,
+        ty: TextureType,
+    },
+    InvalidEntry {
+        name: String,
+        source: bincode::Error,
+    },
+    InvalidTexture(bincode::Error),
+    InvalidName(String),
+    InvalidImageData {
+        name: String,
+    },
+    InvalidImageSize {
+        name: String,
+        width: u32,
+        height: u32,
+    },
+    MissingPalette {
+        name: String,
+    },
+}
+
+impl Display for WadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WadError::Io(error) => write!(f, "{}", error),
+            WadError::InvalidHeader(error) => write!(f, "Invalid WAD header: {}", error),
+            WadError::InvalidMagic(magic) => write!(f, "Invalid WAD magic: {:?}", magic),
+            WadError::InvalidDirectory { offset, source } => write!(
+                f,
+                "Invalid directory entry at offset {}: {}",
+                offset, source
+            ),
+            WadError::InvalidEntryName { offset, source } => write!(
+                f,
+                "Directory entry at offset {} has an invalid name: {}",
+                offset, source
+            ),
+            WadError::UnknownEntryType { name, ty } => {
+                write!(f, "Unknown type for \"{}\": 0x{:X}", name, ty)
+            }
+            WadError::EntryOutOfBounds { name, offset, size } => write!(
+                f,
+                "\"{}\" (offset: {}, size: {}) is out of bounds",
+                name, offset, size
+            ),
+            WadError::UnexpectedEntryType { name, ty } => {
+                write!(f, "\"{}\" has an unexpected type: {:?}", name, ty)
+            }
+            WadError::InvalidEntry { name, source } => {
+                write!(f, "\"{}\" could not be decoded: {}", name, source)
+            }
+            WadError::InvalidTexture(error) => {
+                write!(f, "Texture could not be decoded: {}", error)
+            }
+            WadError::InvalidName(name) => write!(
+                f,
+                "\"{}\" is not a valid entry name (at most {} ASCII characters)",
+                name,
+                ENTRY_NAME_LEN - 1
+            ),
+            WadError::InvalidImageData { name } => {
+                write!(f, "\"{}\" has image data that doesn't match its size", name)
+            }
+            WadError::InvalidImageSize {
+                name,
+                width,
+                height,
+            } => write!(
+                f,
+                "\"{}\" ({}x{}) must be a multiple of 16 pixels in size",
+                name, width, height
+            ),
+            WadError::MissingPalette { name } => write!(
+                f,
+                "\"{}\" can't be decoded without the game's palette",
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WadError::Io(error) => Some(error),
+            WadError::InvalidHeader(error) => Some(error),
+            WadError::InvalidDirectory { source, .. } => Some(source),
+            WadError::InvalidEntryName { source, .. } => Some(source),
+            WadError::InvalidEntry { source, .. } => Some(source),
+            WadError::InvalidTexture(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for WadError {
+    fn from(error: std::io::Error) -> Self {
+        WadError::Io(error)
+    }
+}
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TextureType {
@@ -19,6 +158,24 @@ pub enum TextureType {
     Font = 0x46,
 }
 
+// WAD2 is Quake's format. Its entries don't have their own palettes, they
+// use the game's palette (gfx/palette.lmp) instead.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WadFormat {
+    Wad2,
+    Wad3,
+}
+
+mod wad2_type {
+    pub const PALETTE: u8 = 0x40;
+    pub const IMAGE: u8 = 0x42;
+    pub const MIPMAPPED_IMAGE: u8 = 0x44;
+}
+
+// Quake's console font is a raw 128x128 image stored as a mip texture
+const CONCHARS_NAME: &str = "CONCHARS";
+const CONCHARS_SIZE: u32 = 128;
+
 #[derive(Clone)]
 pub struct WadFileInfo {
     pub name: String,
@@ -26,9 +183,123 @@ pub struct WadFileInfo {
     info: WadDirectory,
 }
 
+impl WadFileInfo {
+    // The entry type as it is stored in the archive, which depends on its
+    // format.
+    pub fn raw_type(&self) -> u8 {
+        self.info.dir_type
+    }
+}
+
 pub struct WadArchive {
     pub files: Vec<WadFileInfo>,
-    raw_data: Vec<u8>,
+    format: WadFormat,
+    palette: Option<Vec<u8>>,
+    // Uppercase names to their index in files
+    index: HashMap<String, usize>,
+    source: WadSource,
+    cache: Mutex<DecodeCache>,
+}
+
+// Where the entries of an archive are read from. Only the directory is read
+// up front, entries are read when they're needed.
+enum WadSource {
+    Memory(Vec<u8>),
+    Mapped(Mmap),
+    Reader {
+        reader: Mutex<Box<dyn ReadSeek>>,
+        len: u64,
+    },
+}
+
+trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+impl WadSource {
+    fn len(&self) -> u64 {
+        match self {
+            WadSource::Memory(data) => data.len() as u64,
+            WadSource::Mapped(map) => map.len() as u64,
+            WadSource::Reader { len, .. } => *len,
+        }
+    }
+
+    fn read(&self, offset: u64, size: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        let data = match self {
+            WadSource::Memory(data) => data.as_slice(),
+            WadSource::Mapped(map) => &map[..],
+            WadSource::Reader { reader, .. } => {
+                let mut reader = reader.lock().unwrap();
+                reader.seek(SeekFrom::Start(offset))?;
+                let mut data = vec![0; size];
+                reader.read_exact(&mut data)?;
+                return Ok(Cow::Owned(data));
+            }
+        };
+        let start = offset as usize;
+        Ok(Cow::Borrowed(&data[start..start + size]))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum DecodeKind {
+    Decal,
+    MipmappedImage,
+    Image,
+    Font,
+}
+
+// Shared with the caller, so cache hits don't copy the images
+#[derive(Clone)]
+enum DecodedEntry {
+    Mipmapped(Arc<MipmapedTextureData>),
+    Image(Arc<TextureData>),
+    Font(Arc<FontData>),
+}
+
+// Recently decoded entries keyed by their offset in the archive, the most
+// recently used first.
+struct DecodeCache {
+    capacity: usize,
+    entries: VecDeque<((u32, DecodeKind), DecodedEntry)>,
+}
+
+impl DecodeCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn get(&mut self, key: (u32, DecodeKind)) -> Option<DecodedEntry> {
+        let position = self
+            .entries
+            .iter()
+            .position(|(entry_key, _)| *entry_key == key)?;
+        let entry = self.entries.remove(position).unwrap();
+        let decoded = entry.1.clone();
+        self.entries.push_front(entry);
+        Some(decoded)
+    }
+
+    fn insert(&mut self, key: (u32, DecodeKind), decoded: DecodedEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.push_front((key, decoded));
+        self.entries.truncate(self.capacity);
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.truncate(capacity);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
 }
 
 #[derive(Clone)]
@@ -77,7 +348,7 @@ pub struct FontData {
     pub image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
 }
 
-#[derive(Copy, Clone, Deserialize)]
+#[derive(Copy, Clone, Deserialize, Serialize)]
 struct WadHeader {
     magic: [u8; 4],
     num_dir: u32,
@@ -85,7 +356,7 @@ struct WadHeader {
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone, Deserialize)]
+#[derive(Copy, Clone, Deserialize, Serialize)]
 struct WadDirectory {
     file_position: u32,
     disk_size: u32,
@@ -97,7 +368,7 @@ struct WadDirectory {
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone, Deserialize)]
+#[derive(Copy, Clone, Deserialize, Serialize)]
 struct MipmappedTextureHeader {
     name: [u8; 16],
     width: u32,
@@ -108,7 +379,7 @@ struct MipmappedTextureHeader {
     mipmap3_offset: u32,
 }
 
-#[derive(Copy, Clone, Deserialize)]
+#[derive(Copy, Clone, Deserialize, Serialize)]
 struct TextureHeader {
     width: u32,
     height: u32,
@@ -130,379 +401,1314 @@ impl FontHeader {
 }
 
 impl WadArchive {
-    pub fn open<P: AsRef<Path>>(wad_path: P) -> WadArchive {
-        let file = File::open(wad_path).unwrap();
-        let file_size = file.metadata().unwrap().len();
-        let mut file = BufReader::new(file);
+    // Entries are read from the file when they're decoded.
+    pub fn open<P: AsRef<Path>>(wad_path: P) -> Result<WadArchive, WadError> {
+        let file = File::open(wad_path)?;
+        Self::from_reader(BufReader::new(file))
+    }
 
-        let file_infos = Self::read_file_infos(&mut file);
+    /// Memory maps the file instead of reading it, which avoids a read for
+    /// every entry that's decoded.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or any
+    /// other, while the archive is open. Reading entries from a mapping whose
+    /// file changed is undefined behavior (see `memmap2::Mmap::map`).
+    pub unsafe fn open_mmap<P: AsRef<Path>>(wad_path: P) -> Result<WadArchive, WadError> {
+        let file = File::open(wad_path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        let (format, file_infos) = Self::read_file_infos(Cursor::new(&map[..]))?;
+        Self::new(format, file_infos, WadSource::Mapped(map))
+    }
 
-        file.seek(SeekFrom::Start(0)).unwrap();
-        let mut file_data = vec![0u8; file_size as usize];
-        file.read(&mut file_data).unwrap();
+    pub fn from_bytes(wad_bytes: Vec<u8>) -> Result<Self, WadError> {
+        let mut reader = std::io::Cursor::new(&wad_bytes);
+        let (format, file_infos) = Self::read_file_infos(&mut reader)?;
+        Self::new(format, file_infos, WadSource::Memory(wad_bytes))
+    }
 
-        WadArchive {
-            files: file_infos,
-            raw_data: file_data,
-        }
+    // Entries are read from the reader when they're decoded. Small reads are
+    // made while reading the directory, so unbuffered readers should be
+    // wrapped in a BufReader.
+    pub fn from_reader<R: Read + Seek + Send + 'static>(mut reader: R) -> Result<Self, WadError> {
+        let (format, file_infos) = Self::read_file_infos(&mut reader)?;
+        let len = reader.seek(SeekFrom::End(0))?;
+        let source = WadSource::Reader {
+            reader: Mutex::new(Box::new(reader)),
+            len,
+        };
+        Self::new(format, file_infos, source)
     }
 
-    pub fn from_bytes(wad_bytes: Vec<u8>) -> Self {
-        let mut reader = std::io::Cursor::new(&wad_bytes);
-        let file_infos = Self::read_file_infos(&mut reader);
-        Self {
+    fn new(
+        format: WadFormat,
+        file_infos: Vec<WadFileInfo>,
+        source: WadSource,
+    ) -> Result<Self, WadError> {
+        let mut archive = Self {
             files: file_infos,
-            raw_data: wad_bytes,
+            format,
+            palette: None,
+            index: HashMap::new(),
+            source,
+            cache: Mutex::new(DecodeCache::new(DEFAULT_CACHE_CAPACITY)),
+        };
+        archive.validate_file_infos()?;
+
+        // Some WAD2 archives carry the palette as an entry of their own
+        if format == WadFormat::Wad2 {
+            let (palettes, files): (Vec<_>, Vec<_>) = std::mem::take(&mut archive.files)
+                .into_iter()
+                .partition(|file_info| file_info.info.dir_type == wad2_type::PALETTE);
+            archive.files = files;
+            if let Some(file_info) = palettes.first() {
+                let palette = archive.read_raw(file_info)?;
+                if palette.len() >= PALETTE_LEN {
+                    let palette = palette[..PALETTE_LEN].to_vec();
+                    archive.palette = Some(palette);
+                }
+            }
+        }
+
+        // The engine looks names up case insensitively, and the first entry
+        // with a name wins.
+        for (i, file_info) in archive.files.iter().enumerate() {
+            archive
+                .index
+                .entry(file_info.name.to_ascii_uppercase())
+                .or_insert(i);
         }
+        Ok(archive)
+    }
+
+    // Names are compared case insensitively.
+    pub fn find(&self, name: &str) -> Option<&WadFileInfo> {
+        let index = self.index.get(&name.to_ascii_uppercase())?;
+        Some(&self.files[*index])
+    }
+
+    pub fn format(&self) -> WadFormat {
+        self.format
+    }
+
+    pub fn palette(&self) -> Option<&[u8]> {
+        self.palette.as_deref()
+    }
+
+    // WAD2 entries are decoded with this palette, it's ignored for WAD3.
+    pub fn set_palette(&mut self, palette: Vec<u8>) {
+        self.palette = Some(palette);
+        self.cache.get_mut().unwrap().clear();
+    }
+
+    // Sets how many decoded entries are kept around, 0 disables the cache.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache.get_mut().unwrap().set_capacity(capacity);
     }
 
-    fn read_file_infos<R: Read + Seek>(mut reader: R) -> Vec<WadFileInfo> {
-        let header: WadHeader = bincode::deserialize_from(&mut reader).unwrap();
-        assert_eq!(header.magic[0], 87); // 'W' in ASCII
-        assert_eq!(header.magic[1], 65); // 'A' in ASCII
-        assert_eq!(header.magic[2], 68); // 'D' in ASCII
-        assert_eq!(header.magic[3], 51); // '3' in ASCII
+    fn read_file_infos<R: Read + Seek>(
+        mut reader: R,
+    ) -> Result<(WadFormat, Vec<WadFileInfo>), WadError> {
+        let header: WadHeader =
+            bincode::deserialize_from(&mut reader).map_err(WadError::InvalidHeader)?;
+        let format = match header.magic {
+            WAD2_MAGIC => WadFormat::Wad2,
+            WAD3_MAGIC => WadFormat::Wad3,
+            _ => return Err(WadError::InvalidMagic(header.magic)),
+        };
 
         let mut file_infos = Vec::new();
-        reader
-            .seek(SeekFrom::Start(header.dir_offset as u64))
-            .unwrap();
+        reader.seek(SeekFrom::Start(header.dir_offset as u64))?;
         for _i in 0..header.num_dir {
-            let wad_dir: WadDirectory = bincode::deserialize_from(&mut reader).unwrap();
-            let name = str::from_utf8(&wad_dir.name).unwrap();
-            let name = name.trim_matches(char::from(0));
-            let texture_type = match wad_dir.dir_type {
-                0x40 => TextureType::Decal,
-                0x42 => TextureType::Image,
-                0x43 => TextureType::MipmappedImage,
-                0x46 => TextureType::Font,
-                _ => panic!("unknown dir type! {} - 0x{:X}", name, wad_dir.dir_type),
+            let offset = reader.stream_position()?;
+            let wad_dir: WadDirectory = bincode::deserialize_from(&mut reader)
+                .map_err(|source| WadError::InvalidDirectory { offset, source })?;
+            let name = null_terminated_bytes_to_str(&wad_dir.name)
+                .map_err(|source| WadError::InvalidEntryName { offset, source })?;
+            let texture_type = match (format, wad_dir.dir_type) {
+                (WadFormat::Wad2, wad2_type::MIPMAPPED_IMAGE)
+                    if name.eq_ignore_ascii_case(CONCHARS_NAME)
+                        && wad_dir.disk_size == CONCHARS_SIZE * CONCHARS_SIZE =>
+                {
+                    TextureType::Image
+                }
+                // Palettes aren't listed, they're picked up when the archive is loaded
+                (WadFormat::Wad2, wad2_type::PALETTE) => TextureType::Image,
+                (WadFormat::Wad2, wad2_type::IMAGE) => TextureType::Image,
+                (WadFormat::Wad2, wad2_type::MIPMAPPED_IMAGE) => TextureType::MipmappedImage,
+                // Quake's tools wrote other types we can't decode (e.g. 0x41
+                // and 0x43), they're left out instead of failing the archive
+                (WadFormat::Wad2, _) => continue,
+                (WadFormat::Wad3, 0x40) => TextureType::Decal,
+                (WadFormat::Wad3, 0x42) => TextureType::Image,
+                (WadFormat::Wad3, 0x43) => TextureType::MipmappedImage,
+                (WadFormat::Wad3, 0x46) => TextureType::Font,
+                _ => {
+                    return Err(WadError::UnknownEntryType {
+                        name: name.to_string(),
+                        ty: wad_dir.dir_type,
+                    })
+                }
             };
             file_infos.push(WadFileInfo {
                 name: name.to_string(),
-                texture_type: texture_type,
+                texture_type,
                 info: wad_dir,
             });
         }
 
-        file_infos
+        Ok((format, file_infos))
     }
 
-    pub fn decode_decal(&self, file_info: &WadFileInfo) -> MipmapedTextureData {
-        assert_eq!(file_info.texture_type, TextureType::Decal);
+    fn validate_file_infos(&self) -> Result<(), WadError> {
+        for file_info in &self.files {
+            let end = file_info.info.file_position as u64 + file_info.info.disk_size as u64;
+            if end > self.source.len() {
+                return Err(WadError::EntryOutOfBounds {
+                    name: file_info.name.clone(),
+                    offset: file_info.info.file_position,
+                    size: file_info.info.disk_size,
+                });
+            }
+        }
+        Ok(())
+    }
 
-        let mut reader = self.get_file_data(file_info);
-        let texture_header: MipmappedTextureHeader =
-            bincode::deserialize_from(&mut reader).unwrap();
+    fn check_texture_type(
+        file_info: &WadFileInfo,
+        expected: &[TextureType],
+    ) -> Result<(), WadError> {
+        if expected.contains(&file_info.texture_type) {
+            Ok(())
+        } else {
+            Err(WadError::UnexpectedEntryType {
+                name: file_info.name.clone(),
+                ty: file_info.texture_type,
+            })
+        }
+    }
 
-        let (image_data, mipmap1_data, mipmap2_data, mipmap3_data) =
-            read_mipmapped_image_data(&texture_header, &mut reader);
+    pub fn decode_decal(
+        &self,
+        file_info: &WadFileInfo,
+    ) -> Result<Arc<MipmapedTextureData>, WadError> {
+        Self::check_texture_type(file_info, &[TextureType::Decal])?;
+
+        let decoded = self.decode_cached(file_info, DecodeKind::Decal, |reader| {
+            decode_decal(reader).map(|texture_data| DecodedEntry::Mipmapped(Arc::new(texture_data)))
+        })?;
+        match decoded {
+            DecodedEntry::Mipmapped(texture_data) => Ok(texture_data),
+            _ => unreachable!(),
+        }
+    }
 
-        let num_colors = reader.read_u16::<LittleEndian>().unwrap();
-        assert_eq!(num_colors, 256);
+    pub fn decode_mipmaped_image(
+        &self,
+        file_info: &WadFileInfo,
+    ) -> Result<Arc<MipmapedTextureData>, WadError> {
+        // the only decal in half-life is LOGO in tempdecal.wad, and it has the same layout as a mipmapped image.
+        Self::check_texture_type(
+            file_info,
+            &[TextureType::MipmappedImage, TextureType::Decal],
+        )?;
+
+        let palette = self.wad2_palette(file_info)?;
+        let decoded = self.decode_cached(file_info, DecodeKind::MipmappedImage, |reader| {
+            decode_mipmaped_image(reader, palette)
+                .map(|texture_data| DecodedEntry::Mipmapped(Arc::new(texture_data)))
+        })?;
+        match decoded {
+            DecodedEntry::Mipmapped(texture_data) => Ok(texture_data),
+            _ => unreachable!(),
+        }
+    }
 
-        let converted_image =
-            create_image_greyscale(&image_data, texture_header.width, texture_header.height);
-        let converted_mipmap1 = create_image_greyscale(
-            &mipmap1_data,
-            texture_header.width / 2,
-            texture_header.height / 2,
-        );
-        let converted_mipmap2 = create_image_greyscale(
-            &mipmap2_data,
-            texture_header.width / 4,
-            texture_header.height / 4,
-        );
-        let converted_mipmap3 = create_image_greyscale(
-            &mipmap3_data,
-            texture_header.width / 8,
-            texture_header.height / 8,
-        );
+    pub fn decode_mipmaped_image_from_reader<R: Read + Seek>(
+        reader: R,
+    ) -> Result<MipmapedTextureData, WadError> {
+        decode_mipmaped_image(reader, None).map_err(WadError::InvalidTexture)
+    }
 
-        MipmapedTextureData {
-            image_width: texture_header.width,
-            image_height: texture_header.height,
-            image: converted_image,
-            mipmap1: converted_mipmap1,
-            mipmap2: converted_mipmap2,
-            mipmap3: converted_mipmap3,
+    pub fn decode_image(&self, file_info: &WadFileInfo) -> Result<Arc<TextureData>, WadError> {
+        Self::check_texture_type(file_info, &[TextureType::Image])?;
+
+        let palette = self.wad2_palette(file_info)?;
+        let decoded = self.decode_cached(file_info, DecodeKind::Image, |reader| {
+            if file_info.info.dir_type == wad2_type::MIPMAPPED_IMAGE {
+                decode_conchars(reader, palette.unwrap())
+            } else {
+                decode_image(reader, palette)
+            }
+            .map(|texture_data| DecodedEntry::Image(Arc::new(texture_data)))
+        })?;
+        match decoded {
+            DecodedEntry::Image(texture_data) => Ok(texture_data),
+            _ => unreachable!(),
         }
     }
 
-    pub fn decode_mipmaped_image(&self, file_info: &WadFileInfo) -> MipmapedTextureData {
-        // the only decal in half-life is LOGO in tempdecal.wad, and it has the same layout as a mipmapped image.
-        assert!(
-            file_info.texture_type == TextureType::MipmappedImage
-                || file_info.texture_type == TextureType::Decal
-        );
+    fn wad2_palette(&self, file_info: &WadFileInfo) -> Result<Option<&[u8]>, WadError> {
+        match self.format {
+            WadFormat::Wad2 => match self.palette.as_deref() {
+                Some(palette) => Ok(Some(palette)),
+                None => Err(WadError::MissingPalette {
+                    name: file_info.name.clone(),
+                }),
+            },
+            WadFormat::Wad3 => Ok(None),
+        }
+    }
+
+    pub fn decode_font(&self, file_info: &WadFileInfo) -> Result<Arc<FontData>, WadError> {
+        Self::check_texture_type(file_info, &[TextureType::Font])?;
 
-        let mut reader = self.get_file_data(file_info);
-        Self::decode_mipmaped_image_from_reader(&mut reader)
+        let decoded = self.decode_cached(file_info, DecodeKind::Font, |reader| {
+            decode_font(reader).map(|font_data| DecodedEntry::Font(Arc::new(font_data)))
+        })?;
+        match decoded {
+            DecodedEntry::Font(font_data) => Ok(font_data),
+            _ => unreachable!(),
+        }
     }
 
-    pub fn decode_mipmaped_image_from_reader<R: Read + Seek>(mut reader: R) -> MipmapedTextureData {
-        let texture_header: MipmappedTextureHeader =
-            bincode::deserialize_from(&mut reader).unwrap();
+    fn decode_cached<F>(
+        &self,
+        file_info: &WadFileInfo,
+        kind: DecodeKind,
+        decode: F,
+    ) -> Result<DecodedEntry, WadError>
+    where
+        F: FnOnce(Cursor<&[u8]>) -> bincode::Result<DecodedEntry>,
+    {
+        let key = (file_info.info.file_position, kind);
+        if let Some(decoded) = self.cache.lock().unwrap().get(key) {
+            return Ok(decoded);
+        }
 
-        let (image_data, mipmap1_data, mipmap2_data, mipmap3_data) =
-            read_mipmapped_image_data(&texture_header, &mut reader);
+        let data = self.read_raw(file_info)?;
+        let decoded = decode(Cursor::new(&data)).map_err(|source| WadError::InvalidEntry {
+            name: file_info.name.clone(),
+            source,
+        })?;
+        self.cache.lock().unwrap().insert(key, decoded.clone());
+        Ok(decoded)
+    }
 
-        let num_colors = reader.read_u16::<LittleEndian>().unwrap();
-        let mut palette_data = vec![0u8; (3 * num_colors) as usize];
-        reader.read_exact(&mut palette_data).unwrap();
+    // Returns the entry's data as it is stored in the archive.
+    pub fn read_raw(&self, file_info: &WadFileInfo) -> Result<Cow<'_, [u8]>, WadError> {
+        // Entry bounds are validated when the archive is opened
+        let data = self.source.read(
+            file_info.info.file_position as u64,
+            file_info.info.disk_size as usize,
+        )?;
+        Ok(data)
+    }
+}
 
-        let converted_image = create_image(
-            &image_data,
-            &palette_data,
-            texture_header.width,
-            texture_header.height,
-        );
-        let converted_mipmap1 = create_image(
-            &mipmap1_data,
-            &palette_data,
-            texture_header.width / 2,
-            texture_header.height / 2,
-        );
-        let converted_mipmap2 = create_image(
-            &mipmap2_data,
-            &palette_data,
-            texture_header.width / 4,
-            texture_header.height / 4,
-        );
-        let converted_mipmap3 = create_image(
-            &mipmap3_data,
-            &palette_data,
-            texture_header.width / 8,
-            texture_header.height / 8,
-        );
+// A set of archives searched in the order they were added, like the WADs
+// listed in a map's "wad" key.
+#[derive(Default)]
+pub struct WadCollection {
+    wads: Vec<WadArchive>,
+    // Uppercase names to every (archive, file) index that has them, in
+    // priority order
+    index: HashMap<String, Vec<(usize, usize)>>,
+}
+
+pub struct WadLookup<'a> {
+    pub archive_index: usize,
+    pub archive: &'a WadArchive,
+    pub file: &'a WadFileInfo,
+    // Entries with the same name in lower priority archives
+    pub shadowed: Vec<(usize, &'a WadFileInfo)>,
+}
+
+impl WadCollection {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        MipmapedTextureData {
-            image_width: texture_header.width,
-            image_height: texture_header.height,
-            image: converted_image,
-            mipmap1: converted_mipmap1,
-            mipmap2: converted_mipmap2,
-            mipmap3: converted_mipmap3,
+    pub fn add(&mut self, archive: WadArchive) {
+        let archive_index = self.wads.len();
+        for (name, file_index) in &archive.index {
+            self.index
+                .entry(name.clone())
+                .or_default()
+                .push((archive_index, *file_index));
         }
+        self.wads.push(archive);
     }
 
-    pub fn decode_image(&self, file_info: &WadFileInfo) -> TextureData {
-        assert_eq!(file_info.texture_type, TextureType::Image);
+    pub fn archives(&self) -> &[WadArchive] {
+        &self.wads
+    }
 
-        let mut reader = self.get_file_data(file_info);
+    pub fn find(&self, name: &str) -> Option<(&WadArchive, &WadFileInfo)> {
+        let (archive_index, file_index) = *self.index.get(&name.to_ascii_uppercase())?.first()?;
+        let archive = &self.wads[archive_index];
+        Some((archive, &archive.files[file_index]))
+    }
 
-        let texture_header: TextureHeader = bincode::deserialize_from(&mut reader).unwrap();
+    // Like find, but also reports the entries the winning one shadows.
+    pub fn lookup(&self, name: &str) -> Option<WadLookup<'_>> {
+        let entries = self.index.get(&name.to_ascii_uppercase())?;
+        let (archive_index, file_index) = *entries.first()?;
+        let archive = &self.wads[archive_index];
+        let shadowed = entries[1..]
+            .iter()
+            .map(|(archive_index, file_index)| {
+                (
+                    *archive_index,
+                    &self.wads[*archive_index].files[*file_index],
+                )
+            })
+            .collect();
+        Some(WadLookup {
+            archive_index,
+            archive,
+            file: &archive.files[file_index],
+            shadowed,
+        })
+    }
+}
 
-        let mut image_data = vec![0u8; (texture_header.width * texture_header.height) as usize];
-        reader.read_exact(image_data.as_mut_slice()).unwrap();
+struct WadWriterEntry {
+    name: [u8; ENTRY_NAME_LEN],
+    texture_type: TextureType,
+    data: Vec<u8>,
+}
 
-        let num_colors = reader.read_u16::<LittleEndian>().unwrap();
-        let mut palette_data = vec![0u8; (3 * num_colors) as usize];
-        reader.read_exact(&mut palette_data).unwrap();
+// Builds a WAD3 archive. Entries are written in the order they were added.
+#[derive(Default)]
+pub struct WadWriter {
+    entries: Vec<WadWriterEntry>,
+}
 
-        let converted_image = create_image(
-            &image_data,
-            &palette_data,
-            texture_header.width,
-            texture_header.height,
-        );
+impl WadWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        TextureData {
-            image_width: texture_header.width,
-            image_height: texture_header.height,
-            image: converted_image,
-        }
+    pub fn len(&self) -> usize {
+        self.entries.len()
     }
 
-    pub fn decode_font(&self, file_info: &WadFileInfo) -> FontData {
-        assert_eq!(file_info.texture_type, TextureType::Font);
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
 
-        let mut reader = self.get_file_data(file_info);
+    // Adds an entry whose data is already encoded.
+    pub fn add_raw(
+        &mut self,
+        name: &str,
+        texture_type: TextureType,
+        data: Vec<u8>,
+    ) -> Result<(), WadError> {
+        let name = encode_entry_name(name)?;
+        self.entries.push(WadWriterEntry {
+            name,
+            texture_type,
+            data,
+        });
+        Ok(())
+    }
 
-        let mut texture_header: FontHeader = bincode::deserialize_from(&mut reader).unwrap();
-        // half-life uses 256 width fonts
-        texture_header.width = 256;
+    // Quantizes the image and generates its reduced mip levels. Names that
+    // start with '{' use pure blue for transparent pixels.
+    pub fn add_mipmapped_image(&mut self, name: &str, image: &RgbaImage) -> Result<(), WadError> {
+        check_mipmapped_image_size(name, image)?;
+        let quantizer = PaletteQuantizer::new(image, name.starts_with('{'));
+        let mip_levels = generate_mip_levels(image).map(|mip_level| quantizer.quantize(&mip_level));
+        self.add_indexed_mipmapped_image(
+            name,
+            image.width(),
+            image.height(),
+            mip_levels.each_ref().map(Vec::as_slice),
+            quantizer.palette(),
+        )
+    }
 
-        let font_data = texture_header.font_data().to_vec();
-        let mut font_data_reader = Cursor::new(&font_data);
-        let mut font_info = [CharInfo::default(); 256];
-        for i in 0..256 {
-            let offset = font_data_reader.read_u16::<LittleEndian>().unwrap() as u32;
-            let width = font_data_reader.read_u16::<LittleEndian>().unwrap() as u32;
+    // Adds a mipmapped texture from the palette indices of its four mip
+    // levels, each half the size of the previous one.
+    pub fn add_indexed_mipmapped_image(
+        &mut self,
+        name: &str,
+        width: u32,
+        height: u32,
+        mip_levels: [&[u8]; 4],
+        palette: &[u8],
+    ) -> Result<(), WadError> {
+        let data = encode_mipmapped_image(name, width, height, mip_levels, palette)?;
+        self.add_raw(name, TextureType::MipmappedImage, data)
+    }
 
-            let row_area = texture_header.row_height * 256;
-            let row = offset / row_area;
-            let offset = offset - (row_area * row);
+    // Adds a picture without mip levels, the kind the HUD and menus use.
+    // Transparent pixels are stored as pure blue.
+    pub fn add_image(&mut self, name: &str, image: &RgbaImage) -> Result<(), WadError> {
+        let alpha_key = image.pixels().any(|pixel| pixel[3] < 255);
+        let quantizer = PaletteQuantizer::new(image, alpha_key);
 
-            let x = offset;
-            let y = texture_header.row_height * row;
-            let width = width;
-            let height = texture_header.row_height;
+        let header = TextureHeader {
+            width: image.width(),
+            height: image.height(),
+        };
+        let mut data = bincode::serialize(&header).unwrap();
+        data.extend_from_slice(&quantizer.quantize(image));
+        data.extend_from_slice(&256u16.to_le_bytes());
+        data.extend_from_slice(quantizer.palette());
+        self.add_raw(name, TextureType::Image, data)
+    }
 
-            font_info[i] = CharInfo {
-                x: x,
-                y: y,
-                width: width,
-                height: height,
-            };
-        }
+    // Decals store how opaque each pixel is in the image data, and are
+    // tinted with the last color of the palette. The coverage comes from
+    // the alpha channel, or the brightness of images that are fully opaque.
+    pub fn add_decal(
+        &mut self,
+        name: &str,
+        image: &RgbaImage,
+        color: [u8; 3],
+    ) -> Result<(), WadError> {
+        check_mipmapped_image_size(name, image)?;
+        let use_alpha = image.pixels().any(|pixel| pixel[3] < 255);
+        let mip_levels = generate_mip_levels(image).map(|mip_level| {
+            mip_level
+                .pixels()
+                .map(|pixel| {
+                    if use_alpha {
+                        pixel[3]
+                    } else {
+                        let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|value| value as u32);
+                        ((r * 299 + g * 587 + b * 114) / 1000) as u8
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+        let mut palette = greyscale_palette();
+        palette[PALETTE_LEN - 3..].copy_from_slice(&color);
+
+        let data = encode_mipmapped_image(
+            name,
+            image.width(),
+            image.height(),
+            mip_levels.each_ref().map(Vec::as_slice),
+            &palette,
+        )?;
+        self.add_raw(name, TextureType::Decal, data)
+    }
 
-        let mut image_data = vec![0u8; (texture_header.width * texture_header.height) as usize];
-        reader.read_exact(image_data.as_mut_slice()).unwrap();
-
-        let num_colors = reader.read_u16::<LittleEndian>().unwrap();
-        let mut palette_data = vec![0u8; (num_colors * 3) as usize];
-        // We use read instead of read_exact here as a workaround for FONT2 in fonts.wad.
-        // Otherwise we would hit the end of the file before we read enough bytes.
-        reader.read(palette_data.as_mut_slice()).unwrap();
-
-        let converted_image = create_image_with_alpha_key(
-            &image_data,
-            &palette_data,
-            texture_header.width,
-            texture_header.height,
-            255,
-        );
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; std::mem::size_of::<WadHeader>()];
+        let mut directories = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            directories.push(WadDirectory {
+                file_position: data.len() as u32,
+                disk_size: entry.data.len() as u32,
+                sizes: entry.data.len() as u32,
+                dir_type: entry.texture_type as u8,
+                compression: false,
+                _dummy: 0,
+                name: entry.name,
+            });
+            data.extend_from_slice(&entry.data);
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
 
-        FontData {
-            image_width: texture_header.width,
-            image_height: texture_header.height,
-            row_count: texture_header.row_count,
-            row_height: texture_header.row_height,
-            font_info: font_info,
-            image: converted_image,
+        let header = WadHeader {
+            magic: WAD3_MAGIC,
+            num_dir: directories.len() as u32,
+            dir_offset: data.len() as u32,
+        };
+        // Serializing plain records into memory can't fail
+        data[..std::mem::size_of::<WadHeader>()]
+            .copy_from_slice(&bincode::serialize(&header).unwrap());
+        for directory in &directories {
+            bincode::serialize_into(&mut data, directory).unwrap();
         }
+        data
     }
+}
 
-    fn get_file_data(&self, file_info: &WadFileInfo) -> Cursor<&[u8]> {
-        let file_data = {
-            let raw_data = self.raw_data.as_slice();
-            let start_index = file_info.info.file_position as usize;
-            let end_index = start_index + file_info.info.disk_size as usize;
-            &raw_data[start_index..end_index]
-        };
-        Cursor::new(file_data)
+// Mipmapped textures have to be a multiple of 16 pixels in size, so that
+// every mip level has whole pixels.
+fn check_mipmapped_image_size(name: &str, image: &RgbaImage) -> Result<(), WadError> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 || width % 16 != 0 || height % 16 != 0 {
+        return Err(WadError::InvalidImageSize {
+            name: name.to_owned(),
+            width,
+            height,
+        });
     }
+    Ok(())
 }
 
-fn create_image(
-    image_data: &[u8],
-    palette_data: &[u8],
-    texture_width: u32,
-    texture_height: u32,
-) -> image::ImageBuffer<image::Rgba<u8>, Vec<u8>> {
-    let mut image_rgba_data = Vec::<u8>::new();
-    for palette_index in image_data {
-        let index = (*palette_index as usize) * 3;
-        let r_color = palette_data[index + 0];
-        let g_color = palette_data[index + 1];
-        let b_color = palette_data[index + 2];
-
-        if r_color == 0 && g_color == 0 && b_color == 255 {
-            image_rgba_data.push(0);
-            image_rgba_data.push(0);
-            image_rgba_data.push(0);
-            image_rgba_data.push(0);
+fn generate_mip_levels(image: &RgbaImage) -> [RgbaImage; 4] {
+    [1, 2, 4, 8].map(|mip_level| {
+        if mip_level == 1 {
+            image.clone()
         } else {
-            image_rgba_data.push(r_color);
-            image_rgba_data.push(g_color);
-            image_rgba_data.push(b_color);
-            image_rgba_data.push(255);
+            image::imageops::resize(
+                image,
+                image.width() / mip_level,
+                image.height() / mip_level,
+                FilterType::Triangle,
+            )
         }
+    })
+}
+
+fn encode_mipmapped_image(
+    name: &str,
+    width: u32,
+    height: u32,
+    mip_levels: [&[u8]; 4],
+    palette: &[u8],
+) -> Result<Vec<u8>, WadError> {
+    let encoded_name = encode_entry_name(name)?;
+    let mip_sizes =
+        [1, 2, 4, 8].map(|mip_level| (width / mip_level) as usize * (height / mip_level) as usize);
+    if mip_levels
+        .iter()
+        .zip(mip_sizes)
+        .any(|(data, size)| data.len() != size)
+        || palette.len() < PALETTE_LEN
+    {
+        return Err(WadError::InvalidImageData {
+            name: name.to_owned(),
+        });
     }
 
-    image::ImageBuffer::<image::Rgba<u8>, Vec<u8>>::from_vec(
-        texture_width,
-        texture_height,
-        image_rgba_data,
-    )
-    .unwrap()
+    let mut offsets = [0u32; 4];
+    let mut offset = std::mem::size_of::<MipmappedTextureHeader>() as u32;
+    for (mip_offset, size) in offsets.iter_mut().zip(mip_sizes) {
+        *mip_offset = offset;
+        offset += size as u32;
+    }
+    let header = MipmappedTextureHeader {
+        name: encoded_name,
+        width,
+        height,
+        image_offset: offsets[0],
+        mipmap1_offset: offsets[1],
+        mipmap2_offset: offsets[2],
+        mipmap3_offset: offsets[3],
+    };
+
+    let mut data = bincode::serialize(&header).unwrap();
+    for mip_level in mip_levels {
+        data.extend_from_slice(mip_level);
+    }
+    data.extend_from_slice(&256u16.to_le_bytes());
+    data.extend_from_slice(&palette[..PALETTE_LEN]);
+
+    Ok(data)
 }
 
-fn create_image_with_alpha_key(
-    image_data: &[u8],
-    palette_data: &[u8],
-    texture_width: u32,
-    texture_height: u32,
-    alpha_key: u8,
-) -> image::ImageBuffer<image::Rgba<u8>, Vec<u8>> {
-    let mut image_rgba_data = Vec::<u8>::new();
-    for palette_index in image_data {
-        let index = (*palette_index as usize) * 3;
-        let r_color = palette_data[index + 0];
-        let g_color = palette_data[index + 1];
-        let b_color = palette_data[index + 2];
-
-        if (r_color == 0 && g_color == 0 && b_color == 255) || *palette_index == alpha_key {
-            image_rgba_data.push(0);
-            image_rgba_data.push(0);
-            image_rgba_data.push(0);
-            image_rgba_data.push(0);
-        } else {
-            image_rgba_data.push(b_color);
-            image_rgba_data.push(g_color);
-            image_rgba_data.push(r_color);
-            image_rgba_data.push(255);
-        }
+// Names are stored null terminated, so they can be at most 15 characters.
+fn encode_entry_name(name: &str) -> Result<[u8; ENTRY_NAME_LEN], WadError> {
+    if name.is_empty() || name.len() >= ENTRY_NAME_LEN || !name.is_ascii() {
+        return Err(WadError::InvalidName(name.to_owned()));
     }
+    let mut encoded = [0u8; ENTRY_NAME_LEN];
+    encoded[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(encoded)
+}
 
-    image::ImageBuffer::<image::Rgba<u8>, Vec<u8>>::from_vec(
-        texture_width,
-        texture_height,
-        image_rgba_data,
-    )
-    .unwrap()
+fn decode_decal<R: Read + Seek>(mut reader: R) -> bincode::Result<MipmapedTextureData> {
+    let texture_header: MipmappedTextureHeader = bincode::deserialize_from(&mut reader)?;
+
+    let (image_data, mipmap1_data, mipmap2_data, mipmap3_data) =
+        read_mipmapped_image_data(&texture_header, &mut reader)?;
+
+    let num_colors = reader.read_u16::<LittleEndian>()?;
+    if num_colors != 256 {
+        return Err(invalid_data(format!(
+            "Expected 256 colors in the decal palette, found {}",
+            num_colors
+        )));
+    }
+
+    // Decals store their alpha in the image data, the palette only carries
+    // the color that gets tinted.
+    let palette_data = greyscale_palette();
+    let converted_image = create_image(
+        &image_data,
+        &palette_data,
+        texture_header.width,
+        texture_header.height,
+        None,
+    )?;
+    let converted_mipmap1 = create_image(
+        &mipmap1_data,
+        &palette_data,
+        texture_header.width / 2,
+        texture_header.height / 2,
+        None,
+    )?;
+    let converted_mipmap2 = create_image(
+        &mipmap2_data,
+        &palette_data,
+        texture_header.width / 4,
+        texture_header.height / 4,
+        None,
+    )?;
+    let converted_mipmap3 = create_image(
+        &mipmap3_data,
+        &palette_data,
+        texture_header.width / 8,
+        texture_header.height / 8,
+        None,
+    )?;
+
+    Ok(MipmapedTextureData {
+        image_width: texture_header.width,
+        image_height: texture_header.height,
+        image: converted_image,
+        mipmap1: converted_mipmap1,
+        mipmap2: converted_mipmap2,
+        mipmap3: converted_mipmap3,
+    })
+}
+
+// WAD2 entries are decoded with the given palette instead of their own.
+fn decode_mipmaped_image<R: Read + Seek>(
+    mut reader: R,
+    palette: Option<&[u8]>,
+) -> bincode::Result<MipmapedTextureData> {
+    let texture_header: MipmappedTextureHeader = bincode::deserialize_from(&mut reader)?;
+
+    let (image_data, mipmap1_data, mipmap2_data, mipmap3_data) =
+        read_mipmapped_image_data(&texture_header, &mut reader)?;
+
+    let palette_data = match palette {
+        Some(palette) => palette.to_vec(),
+        None => read_palette(&mut reader)?,
+    };
+
+    let converted_image = create_image(
+        &image_data,
+        &palette_data,
+        texture_header.width,
+        texture_header.height,
+        None,
+    )?;
+    let converted_mipmap1 = create_image(
+        &mipmap1_data,
+        &palette_data,
+        texture_header.width / 2,
+        texture_header.height / 2,
+        None,
+    )?;
+    let converted_mipmap2 = create_image(
+        &mipmap2_data,
+        &palette_data,
+        texture_header.width / 4,
+        texture_header.height / 4,
+        None,
+    )?;
+    let converted_mipmap3 = create_image(
+        &mipmap3_data,
+        &palette_data,
+        texture_header.width / 8,
+        texture_header.height / 8,
+        None,
+    )?;
+
+    Ok(MipmapedTextureData {
+        image_width: texture_header.width,
+        image_height: texture_header.height,
+        image: converted_image,
+        mipmap1: converted_mipmap1,
+        mipmap2: converted_mipmap2,
+        mipmap3: converted_mipmap3,
+    })
+}
+
+fn decode_image<R: Read + Seek>(
+    mut reader: R,
+    palette: Option<&[u8]>,
+) -> bincode::Result<TextureData> {
+    let texture_header: TextureHeader = bincode::deserialize_from(&mut reader)?;
+
+    let len = check_image_size(&mut reader, texture_header.width, texture_header.height)?;
+    let mut image_data = vec![0u8; len];
+    reader.read_exact(image_data.as_mut_slice())?;
+
+    // Quake pictures use the last color of the palette for transparency
+    let (palette_data, alpha_key) = match palette {
+        Some(palette) => (palette.to_vec(), Some(255)),
+        None => (read_palette(&mut reader)?, None),
+    };
+
+    let converted_image = create_image(
+        &image_data,
+        &palette_data,
+        texture_header.width,
+        texture_header.height,
+        alpha_key,
+    )?;
+
+    Ok(TextureData {
+        image_width: texture_header.width,
+        image_height: texture_header.height,
+        image: converted_image,
+    })
+}
+
+// The console font doesn't have a header, and uses index 0 for transparency.
+fn decode_conchars<R: Read>(mut reader: R, palette: &[u8]) -> bincode::Result<TextureData> {
+    let mut image_data = vec![0u8; (CONCHARS_SIZE * CONCHARS_SIZE) as usize];
+    reader.read_exact(&mut image_data)?;
+    let converted_image =
+        create_image(&image_data, palette, CONCHARS_SIZE, CONCHARS_SIZE, Some(0))?;
+    Ok(TextureData {
+        image_width: CONCHARS_SIZE,
+        image_height: CONCHARS_SIZE,
+        image: converted_image,
+    })
+}
+
+fn decode_font<R: Read + Seek>(mut reader: R) -> bincode::Result<FontData> {
+    let mut texture_header: FontHeader = bincode::deserialize_from(&mut reader)?;
+    // half-life uses 256 width fonts
+    texture_header.width = 256;
+    if texture_header.row_height == 0 {
+        return Err(invalid_data("Font row height is zero".to_owned()));
+    }
+    let row_area = texture_header.row_height.checked_mul(256).ok_or_else(|| {
+        invalid_data(format!(
+            "Font row height ({}) is too large",
+            texture_header.row_height
+        ))
+    })?;
+
+    let font_data = texture_header.font_data().to_vec();
+    let mut font_data_reader = Cursor::new(&font_data);
+    let mut font_info = [CharInfo::default(); 256];
+    for char_info in font_info.iter_mut() {
+        let offset = font_data_reader.read_u16::<LittleEndian>()? as u32;
+        let width = font_data_reader.read_u16::<LittleEndian>()? as u32;
+
+        let row = offset / row_area;
+        let offset = offset - (row_area * row);
+
+        let x = offset;
+        let y = texture_header.row_height * row;
+        let height = texture_header.row_height;
+
+        *char_info = CharInfo {
+            x,
+            y,
+            width,
+            height,
+        };
+    }
+
+    let len = check_image_size(&mut reader, texture_header.width, texture_header.height)?;
+    let mut image_data = vec![0u8; len];
+    reader.read_exact(image_data.as_mut_slice())?;
+
+    let num_colors = reader.read_u16::<LittleEndian>()?;
+    let palette_len = num_colors as usize * 3;
+    let mut palette_data = Vec::with_capacity(palette_len.max(PALETTE_LEN));
+    // We read what's left instead of using read_exact here as a workaround for
+    // FONT2 in fonts.wad, which ends before its palette does. Missing colors
+    // are left black.
+    reader
+        .take(palette_len as u64)
+        .read_to_end(&mut palette_data)?;
+    palette_data.resize(palette_len.max(PALETTE_LEN), 0);
+
+    let converted_image = create_image(
+        &image_data,
+        &palette_data,
+        texture_header.width,
+        texture_header.height,
+        Some(255),
+    )?;
+
+    Ok(FontData {
+        image_width: texture_header.width,
+        image_height: texture_header.height,
+        row_count: texture_header.row_count,
+        row_height: texture_header.row_height,
+        font_info,
+        image: converted_image,
+    })
+}
+
+fn invalid_data(message: String) -> bincode::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message).into()
+}
+
+// Makes sure the image could fit in what's left of the entry before we
+// allocate space for it.
+fn check_image_size<R: Read + Seek>(
+    reader: &mut R,
+    width: u32,
+    height: u32,
+) -> bincode::Result<usize> {
+    let position = reader.stream_position()?;
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(position))?;
+    let size = width as u64 * height as u64;
+    if size > len {
+        return Err(invalid_data(format!(
+            "Image dimensions ({}x{}) are larger than the entry",
+            width, height
+        )));
+    }
+    Ok(size as usize)
 }
 
-fn create_image_greyscale(
+// Palettes with fewer than 256 colors are padded with black.
+fn read_palette<R: Read>(mut reader: R) -> bincode::Result<Vec<u8>> {
+    let num_colors = reader.read_u16::<LittleEndian>()? as usize;
+    let mut palette_data = vec![0u8; (3 * num_colors).max(PALETTE_LEN)];
+    reader.read_exact(&mut palette_data[..3 * num_colors])?;
+    Ok(palette_data)
+}
+
+fn create_image(
     image_data: &[u8],
+    palette_data: &[u8],
     texture_width: u32,
     texture_height: u32,
-) -> image::ImageBuffer<image::Rgba<u8>, Vec<u8>> {
-    let mut image_rgba_data = Vec::<u8>::new();
-    for value in image_data {
-        image_rgba_data.push(*value);
-        image_rgba_data.push(*value);
-        image_rgba_data.push(*value);
-        image_rgba_data.push(255);
-    }
-
-    image::ImageBuffer::<image::Rgba<u8>, Vec<u8>>::from_vec(
+    alpha_key: Option<u8>,
+) -> bincode::Result<image::ImageBuffer<image::Rgba<u8>, Vec<u8>>> {
+    decode_paletted_image(
+        image_data,
+        palette_data,
         texture_width,
         texture_height,
-        image_rgba_data,
+        alpha_key,
     )
-    .unwrap()
+    .ok_or_else(|| invalid_data("Image data doesn't match its dimensions".to_owned()))
 }
 
+// The image data for each of the four mip levels
+type MipmappedImageData = (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>);
+
 fn read_mipmapped_image_data<R: Read + Seek>(
     texture_header: &MipmappedTextureHeader,
     mut reader: R,
-) -> (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>) {
-    let mut image_data = vec![0u8; (texture_header.width * texture_header.height) as usize];
-    let mut mipmap1_data =
-        vec![0u8; ((texture_header.width / 2) * (texture_header.height / 2)) as usize];
-    let mut mipmap2_data =
-        vec![0u8; ((texture_header.width / 4) * (texture_header.height / 4)) as usize];
-    let mut mipmap3_data =
-        vec![0u8; ((texture_header.width / 8) * (texture_header.height / 8)) as usize];
+) -> bincode::Result<MipmappedImageData> {
+    let width = texture_header.width;
+    let height = texture_header.height;
+    let mut image_data = vec![0u8; check_image_size(&mut reader, width, height)?];
+    let mut mipmap1_data = vec![0u8; ((width / 2) * (height / 2)) as usize];
+    let mut mipmap2_data = vec![0u8; ((width / 4) * (height / 4)) as usize];
+    let mut mipmap3_data = vec![0u8; ((width / 8) * (height / 8)) as usize];
+
+    reader.seek(SeekFrom::Start(texture_header.image_offset as u64))?;
+    reader.read_exact(image_data.as_mut_slice())?;
+    reader.seek(SeekFrom::Start(texture_header.mipmap1_offset as u64))?;
+    reader.read_exact(mipmap1_data.as_mut_slice())?;
+    reader.seek(SeekFrom::Start(texture_header.mipmap2_offset as u64))?;
+    reader.read_exact(mipmap2_data.as_mut_slice())?;
+    reader.seek(SeekFrom::Start(texture_header.mipmap3_offset as u64))?;
+    reader.read_exact(mipmap3_data.as_mut_slice())?;
+
+    Ok((image_data, mipmap1_data, mipmap2_data, mipmap3_data))
+}
 
-    reader
-        .seek(SeekFrom::Start(texture_header.image_offset as u64))
-        .unwrap();
-    reader.read_exact(image_data.as_mut_slice()).unwrap();
-    reader
-        .seek(SeekFrom::Start(texture_header.mipmap1_offset as u64))
-        .unwrap();
-    reader.read_exact(mipmap1_data.as_mut_slice()).unwrap();
-    reader
-        .seek(SeekFrom::Start(texture_header.mipmap2_offset as u64))
-        .unwrap();
-    reader.read_exact(mipmap2_data.as_mut_slice()).unwrap();
-    reader
-        .seek(SeekFrom::Start(texture_header.mipmap3_offset as u64))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds archives entry by entry, without going through the writer
+    struct TestWad {
+        magic: [u8; 4],
+        entries: Vec<(&'static str, u8, Vec<u8>)>,
+    }
+
+    impl TestWad {
+        fn new() -> Self {
+            Self {
+                magic: WAD3_MAGIC,
+                entries: Vec::new(),
+            }
+        }
+
+        fn wad2() -> Self {
+            Self {
+                magic: WAD2_MAGIC,
+                entries: Vec::new(),
+            }
+        }
+
+        fn entry(mut self, name: &'static str, ty: u8, data: Vec<u8>) -> Self {
+            self.entries.push((name, ty, data));
+            self
+        }
+
+        // A font of 8 pixel wide glyphs that are 4 pixels tall, with a
+        // palette that runs from blue to red
+        fn font(row_height: u32) -> Vec<u8> {
+            let height = 4;
+            let mut data = Vec::new();
+            for value in [256, height, 1, row_height] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            for i in 0..256u16 {
+                // Offset and width of each glyph
+                data.extend_from_slice(&(i % 32 * 8).to_le_bytes());
+                data.extend_from_slice(&8u16.to_le_bytes());
+            }
+            data.extend((0..256 * height).map(|i| (i % 4) as u8));
+            data.extend_from_slice(&256u16.to_le_bytes());
+            data.extend((0..256).flat_map(|i| [i as u8, 0, 255 - i as u8]));
+            data
+        }
+
+        // A picture without mip levels, from its palette indices
+        fn picture(width: u32, height: u32, indices: &[u8]) -> Vec<u8> {
+            let mut data = bincode::serialize(&TextureHeader { width, height }).unwrap();
+            data.extend_from_slice(indices);
+            data
+        }
+
+        // A 1x1 WAD3 picture whose only pixel is the given shade of grey
+        fn grey_picture(value: u8) -> Vec<u8> {
+            let mut data = Self::picture(1, 1, &[value]);
+            data.extend_from_slice(&256u16.to_le_bytes());
+            data.extend(greyscale_palette());
+            data
+        }
+
+        // Never pure blue, so only the alpha keys make pixels transparent
+        fn quake_palette() -> Vec<u8> {
+            (0..=255u8).flat_map(|i| [i, 128, 255 - i]).collect()
+        }
+
+        fn to_bytes(&self) -> Vec<u8> {
+            let header_len = 12;
+            let entries_len: usize = self.entries.iter().map(|(_, _, data)| data.len()).sum();
+            let mut data = self.magic.to_vec();
+            data.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+            data.extend_from_slice(&((header_len + entries_len) as u32).to_le_bytes());
+            for (_, _, entry) in &self.entries {
+                data.extend_from_slice(entry);
+            }
+
+            let mut file_position = header_len;
+            for (name, ty, entry) in &self.entries {
+                data.extend_from_slice(&(file_position as u32).to_le_bytes());
+                // The size on disk and the uncompressed size
+                data.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+                data.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+                data.extend_from_slice(&[*ty, 0, 0, 0]);
+                let mut name = name.as_bytes().to_vec();
+                name.resize(16, 0);
+                data.extend_from_slice(&name);
+                file_position += entry.len();
+            }
+            data
+        }
+
+        fn archive(&self) -> WadArchive {
+            WadArchive::from_bytes(self.to_bytes()).unwrap()
+        }
+    }
+
+    #[test]
+    fn font_palettes_are_rgb() {
+        let archive = TestWad::new()
+            .entry("FONT", TextureType::Font as u8, TestWad::font(4))
+            .archive();
+        let font_data = archive.decode_font(&archive.files[0]).unwrap();
+
+        // Font palettes are stored in the same order as every other palette
+        assert_eq!(font_data.image.get_pixel(1, 0).0, [1, 0, 254, 255]);
+        assert_eq!(font_data.image.get_pixel(2, 0).0, [2, 0, 253, 255]);
+        // Index 0 is pure blue
+        assert_eq!(font_data.image.get_pixel(0, 0).0, [0, 0, 0, 0]);
+        assert_eq!(font_data.font_info[1].x, 8);
+    }
+
+    #[test]
+    fn written_archives_read_back() {
+        let palette: Vec<u8> = (0..=255u8).flat_map(|i| [i, 255 - i, 0]).collect();
+        let mip_levels: Vec<Vec<u8>> = (0..4)
+            .map(|level| {
+                (0..(16 >> level) * (16 >> level))
+                    .map(|i| i as u8)
+                    .collect()
+            })
+            .collect();
+        let mut writer = WadWriter::new();
+        writer
+            .add_indexed_mipmapped_image(
+                "BRICK",
+                16,
+                16,
+                [
+                    &mip_levels[0],
+                    &mip_levels[1],
+                    &mip_levels[2],
+                    &mip_levels[3],
+                ],
+                &palette,
+            )
+            .unwrap();
+        writer
+            .add_raw("FONT", TextureType::Font, TestWad::font(4))
+            .unwrap();
+        assert_eq!(writer.len(), 2);
+
+        let archive = WadArchive::from_bytes(writer.to_bytes()).unwrap();
+        let entries: Vec<_> = archive
+            .files
+            .iter()
+            .map(|file_info| (file_info.name.as_str(), file_info.texture_type))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("BRICK", TextureType::MipmappedImage),
+                ("FONT", TextureType::Font)
+            ]
+        );
+
+        let texture = archive.decode_mipmaped_image(&archive.files[0]).unwrap();
+        assert_eq!((texture.image_width, texture.image_height), (16, 16));
+        assert_eq!(texture.image.get_pixel(3, 1).0, [19, 236, 0, 255]);
+        assert_eq!(texture.mipmap3.dimensions(), (2, 2));
+        assert_eq!(texture.mipmap3.get_pixel(1, 1).0, [3, 252, 0, 255]);
+        let font_data = archive.decode_font(&archive.files[1]).unwrap();
+        assert_eq!(font_data.image.get_pixel(1, 0).0, [1, 0, 254, 255]);
+    }
+
+    #[test]
+    fn imported_images_get_mip_levels() {
+        let image = RgbaImage::from_pixel(32, 16, image::Rgba([200, 100, 50, 255]));
+        let mut writer = WadWriter::new();
+        writer.add_mipmapped_image("BRICK", &image).unwrap();
+        // Mip levels need whole pixels all the way down
+        assert!(matches!(
+            writer.add_mipmapped_image("SMALL", &RgbaImage::new(24, 16)),
+            Err(WadError::InvalidImageSize { width: 24, .. })
+        ));
+
+        let archive = WadArchive::from_bytes(writer.to_bytes()).unwrap();
+        assert_eq!(archive.files.len(), 1);
+        let texture = archive.decode_mipmaped_image(&archive.files[0]).unwrap();
+        assert_eq!(texture.image, image);
+        assert_eq!(texture.mipmap3.dimensions(), (4, 2));
+        assert_eq!(texture.mipmap3.get_pixel(3, 1).0, [200, 100, 50, 255]);
+    }
+
+    #[test]
+    fn wad2_entries_use_the_archive_palette() {
+        let conchars: Vec<u8> = (0..128 * 128).map(|i| (i % 4) as u8).collect();
+        let mip_levels: Vec<Vec<u8>> = (0..4)
+            .map(|level| vec![7; (16 >> level) * (16 >> level)])
+            .collect();
+        // Quake mip textures end after their last mip level
+        let mut wall = encode_mipmapped_image(
+            "WALL",
+            16,
+            16,
+            [
+                &mip_levels[0],
+                &mip_levels[1],
+                &mip_levels[2],
+                &mip_levels[3],
+            ],
+            &TestWad::quake_palette(),
+        )
         .unwrap();
-    reader.read_exact(mipmap3_data.as_mut_slice()).unwrap();
+        wall.truncate(40 + 256 + 64 + 16 + 4);
+
+        let archive = TestWad::wad2()
+            .entry("PALETTE", wad2_type::PALETTE, TestWad::quake_palette())
+            .entry("PIC", wad2_type::IMAGE, TestWad::picture(2, 1, &[1, 255]))
+            .entry("STATUS", 0x41, vec![0; 16])
+            .entry("CONCHARS", wad2_type::MIPMAPPED_IMAGE, conchars)
+            .entry("OLDWALL", 0x43, vec![0; 16])
+            .entry("WALL", wad2_type::MIPMAPPED_IMAGE, wall)
+            .archive();
+        assert_eq!(archive.format(), WadFormat::Wad2);
+        assert_eq!(archive.palette(), Some(&TestWad::quake_palette()[..]));
+        // Entries of unknown types are skipped
+        let entries: Vec<_> = archive
+            .files
+            .iter()
+            .map(|file_info| (file_info.name.as_str(), file_info.texture_type))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("PIC", TextureType::Image),
+                ("CONCHARS", TextureType::Image),
+                ("WALL", TextureType::MipmappedImage)
+            ]
+        );
+
+        // Pictures use the last color for transparency
+        let picture = archive.decode_image(&archive.files[0]).unwrap();
+        assert_eq!(picture.image.get_pixel(0, 0).0, [1, 128, 254, 255]);
+        assert_eq!(picture.image.get_pixel(1, 0).0, [0, 0, 0, 0]);
+
+        // The console font uses the first
+        let conchars = archive.decode_image(&archive.files[1]).unwrap();
+        assert_eq!((conchars.image_width, conchars.image_height), (128, 128));
+        assert_eq!(conchars.image.get_pixel(0, 0).0, [0, 0, 0, 0]);
+        assert_eq!(conchars.image.get_pixel(1, 0).0, [1, 128, 254, 255]);
+
+        let wall = archive.decode_mipmaped_image(&archive.files[2]).unwrap();
+        assert_eq!(wall.image.get_pixel(5, 5).0, [7, 128, 248, 255]);
+        assert_eq!(wall.mipmap3.get_pixel(1, 1).0, [7, 128, 248, 255]);
+    }
 
-    (image_data, mipmap1_data, mipmap2_data, mipmap3_data)
+    #[test]
+    fn wad2_archives_without_a_palette_need_one() {
+        let mut archive = TestWad::wad2()
+            .entry("PIC", wad2_type::IMAGE, TestWad::picture(1, 1, &[2]))
+            .archive();
+        let file_info = archive.files[0].clone();
+        assert!(matches!(
+            archive.decode_image(&file_info),
+            Err(WadError::MissingPalette { .. })
+        ));
+
+        archive.set_palette(TestWad::quake_palette());
+        let picture = archive.decode_image(&file_info).unwrap();
+        assert_eq!(picture.image.get_pixel(0, 0).0, [2, 128, 253, 255]);
+    }
+
+    #[test]
+    fn names_are_found_case_insensitively() {
+        let image = TextureType::Image as u8;
+        let archive = TestWad::new()
+            .entry("Wall", image, TestWad::grey_picture(1))
+            .entry("WALL", image, TestWad::grey_picture(2))
+            .entry("sky", image, TestWad::grey_picture(3))
+            .archive();
+        // The first entry with a name wins, like in the engine
+        let file_info = archive.find("wALL").unwrap();
+        assert_eq!(file_info.name, "Wall");
+        let picture = archive.decode_image(file_info).unwrap();
+        assert_eq!(picture.image.get_pixel(0, 0).0, [1, 1, 1, 255]);
+        assert_eq!(archive.find("SKY").unwrap().name, "sky");
+        assert!(archive.find("floor").is_none());
+    }
+
+    #[test]
+    fn earlier_archives_shadow_later_ones() {
+        let image = TextureType::Image as u8;
+        let mut collection = WadCollection::new();
+        collection.add(
+            TestWad::new()
+                .entry("WALL", image, TestWad::grey_picture(1))
+                .entry("SKY", image, TestWad::grey_picture(2))
+                .archive(),
+        );
+        collection.add(
+            TestWad::new()
+                .entry("FLOOR", image, TestWad::grey_picture(3))
+                .entry("wall", image, TestWad::grey_picture(4))
+                .archive(),
+        );
+        assert_eq!(collection.archives().len(), 2);
+
+        let lookup = collection.lookup("Wall").unwrap();
+        assert_eq!(lookup.archive_index, 0);
+        assert_eq!(lookup.file.name, "WALL");
+        let shadowed: Vec<_> = lookup
+            .shadowed
+            .iter()
+            .map(|(archive_index, file_info)| (*archive_index, file_info.name.as_str()))
+            .collect();
+        assert_eq!(shadowed, [(1, "wall")]);
+        let picture = lookup.archive.decode_image(lookup.file).unwrap();
+        assert_eq!(picture.image.get_pixel(0, 0).0, [1, 1, 1, 255]);
+
+        let (archive, file_info) = collection.find("floor").unwrap();
+        let picture = archive.decode_image(file_info).unwrap();
+        assert_eq!(picture.image.get_pixel(0, 0).0, [3, 3, 3, 255]);
+        assert!(collection.lookup("sky").unwrap().shadowed.is_empty());
+        assert!(collection.lookup("door").is_none());
+    }
+
+    #[test]
+    fn every_source_reads_the_same_entries() {
+        let image = TextureType::Image as u8;
+        let data = TestWad::new()
+            .entry("SKY", image, TestWad::grey_picture(3))
+            .entry("WALL", image, TestWad::grey_picture(7))
+            .to_bytes();
+        let path = std::env::temp_dir().join(format!("gsparser-test-{}.wad", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+
+        let archives = [
+            WadArchive::from_bytes(data.clone()).unwrap(),
+            WadArchive::from_reader(Cursor::new(data.clone())).unwrap(),
+            WadArchive::open(&path).unwrap(),
+            // Nothing writes to the file until the archives are dropped
+            unsafe { WadArchive::open_mmap(&path).unwrap() },
+        ];
+        for archive in &archives {
+            let file_info = archive.find("wall").unwrap();
+            assert_eq!(
+                &*archive.read_raw(file_info).unwrap(),
+                TestWad::grey_picture(7)
+            );
+            let picture = archive.decode_image(file_info).unwrap();
+            assert_eq!(picture.image.get_pixel(0, 0).0, [7, 7, 7, 255]);
+        }
+        drop(archives);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn entries_past_the_end_are_rejected() {
+        let mut data = TestWad::new()
+            .entry("WALL", TextureType::Image as u8, TestWad::grey_picture(7))
+            .to_bytes();
+        // The directory follows the entry, grow the entry's size into it
+        let dir_offset = data.len() - std::mem::size_of::<WadDirectory>();
+        data[dir_offset + 4..dir_offset + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(matches!(
+            WadArchive::from_reader(Cursor::new(data)),
+            Err(WadError::EntryOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn cached_images_are_shared() {
+        let image = RgbaImage::from_fn(16, 16, |x, y| image::Rgba([x as u8 * 16, y as u8, 0, 255]));
+        let mut writer = WadWriter::new();
+        writer.add_mipmapped_image("WALL", &image).unwrap();
+        let mut archive = WadArchive::from_bytes(writer.to_bytes()).unwrap();
+        let file_info = archive.files[0].clone();
+
+        let first = archive.decode_mipmaped_image(&file_info).unwrap();
+        let second = archive.decode_mipmaped_image(&file_info).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        archive.set_cache_capacity(0);
+        let third = archive.decode_mipmaped_image(&file_info).unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert_eq!(first.image, third.image);
+    }
+
+    #[test]
+    fn oversized_font_rows_are_errors() {
+        let archive = TestWad::new()
+            .entry(
+                "FONT",
+                TextureType::Font as u8,
+                TestWad::font(u32::MAX / 16),
+            )
+            .archive();
+        let file_info = archive.find("font").unwrap();
+        assert!(matches!(
+            archive.decode_font(file_info),
+            Err(WadError::InvalidEntry { .. })
+        ));
+    }
 }
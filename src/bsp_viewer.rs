@@ -1,6 +1,5 @@
-use std::collections::HashMap;
-
 use crate::graphics::*;
+use crate::rendering::light_styles::get_switchable_light_style;
 use crate::BspFile;
 use glam::Vec3;
 use gsparser::bsp::BspEntity;
@@ -37,8 +36,9 @@ impl BspViewerState {
 pub struct BspViewer {
     state: BspViewerState,
     last_file_path: String,
-    cached_entities: Vec<HashMap<String, String>>,
+    cached_entities: Vec<Vec<(String, String)>>,
     entities: String,
+    toggled_light_styles: Vec<usize>,
 }
 
 impl BspViewer {
@@ -48,6 +48,7 @@ impl BspViewer {
             last_file_path: String::new(),
             cached_entities: Vec::new(),
             entities: String::new(),
+            toggled_light_styles: Vec::new(),
         }
     }
 
@@ -70,14 +71,14 @@ impl BspViewer {
             self.reset_listbox_index();
             force_new_selection = true;
 
-            self.cached_entities = BspEntity::parse_entities(file_info.reader.read_entities_str())
+            let entities = file_info.reader.read_entities().unwrap();
+            self.cached_entities = BspEntity::parse_entities(entities)
+                .unwrap()
                 .iter()
                 .map(|x| {
-                    let mut result = HashMap::new();
-                    for (key, value) in &x.0 {
-                        result.insert((*key).to_owned(), (*value).to_owned());
-                    }
-                    result
+                    x.0.iter()
+                        .map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+                        .collect()
                 })
                 .collect();
 
@@ -104,6 +105,11 @@ impl BspViewer {
                     let entity = &self.cached_entities[self.state.selected_entity_index as usize];
                     let text = format!("{:#?}", entity);
                     ui.text(text);
+                    if let Some(style) = get_switchable_light_style(entity) {
+                        if ui.button("Toggle light") {
+                            self.toggled_light_styles.push(style);
+                        }
+                    }
                 }
             });
 
@@ -119,6 +125,10 @@ impl BspViewer {
         self.state.selected_entity_index = index;
     }
 
+    pub fn take_toggled_light_styles(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.toggled_light_styles)
+    }
+
     pub fn set_position(&mut self, position: Vec3, facing: Vec3) {
         self.state.position = position;
         self.state.direction = facing;
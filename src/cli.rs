@@ -13,7 +13,36 @@ pub struct Cli {
     #[clap(value_parser, value_name = "FILE")]
     pub file_path: Option<PathBuf>,
 
-    /// Export to the given file
+    /// Export to the given file. Exporting a BSP file to a .wad file
+    /// extracts its embedded textures.
     #[clap(value_parser, value_name = "EXPORT FILE")]
     pub export_file_path: Option<PathBuf>,
+
+    /// Replace the entities of the BSP file with the given .ent file. The
+    /// map is written to EXPORT FILE.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "ENT FILE",
+        requires_all = ["file_path", "export_file_path"]
+    )]
+    pub import_entities: Option<PathBuf>,
+
+    /// Embed the textures the BSP file loads from WADs. The map is written
+    /// to EXPORT FILE.
+    #[clap(
+        long,
+        default_value_t = false,
+        requires_all = ["file_path", "export_file_path"]
+    )]
+    pub embed_textures: bool,
+
+    /// Remove the "wad" key from worldspawn once the textures are embedded
+    #[clap(long, default_value_t = false, requires = "embed_textures")]
+    pub strip_wad_key: bool,
+
+    /// Build a WAD3 file from the PNG files in the given directory. The WAD
+    /// is written to EXPORT FILE.
+    #[clap(long, value_parser, value_name = "IMAGE DIR")]
+    pub import_images: Option<PathBuf>,
 }
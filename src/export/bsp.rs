@@ -1,50 +1,48 @@
 use std::{
-    collections::HashMap,
     fmt::Write,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use glam::Vec3;
 use gltf::{animation::Animations, buffer::BufferWriter, export::write_gltf, material::{Image, MagFilter, Material, MaterialData, MinFilter, PbrMetallicRoughness, Texture, Wrap}, node::{MeshIndex, Node, Nodes}, skin::Skins, vertex_def, Mesh, Model};
 use gsparser::{
-    bsp::{
-        BspEdge, BspEntity, BspFace, BspLeaf, BspNode, BspReader, BspSurfaceEdge, BspTextureInfo,
-        BspVertex,
-    },
-    wad3::{MipmapedTextureData, WadArchive, WadFileInfo},
+    bsp::{BspEntity, BspError, BspFaceGeometry, BspNode, BspReader, BspVariant, BspWriter},
+    palette::{greyscale_palette, PALETTE_LEN},
+    wad3::{MipmapedTextureData, TextureType, WadArchive, WadCollection},
 };
 
-use crate::export::coordinates::convert_coordinates;
-
-const QUIVER_PREFIX: &'static str = "\\quiver\\";
+use crate::export::{coordinates::convert_coordinates, lightmap::LightmapAtlas};
 
 vertex_def!{
     ModelVertex {
         ("POSITION") pos: [f32; 3],
         ("NORMAL") normal: [f32; 3],
         ("TEXCOORD_0") uv: [f32; 2],
+        ("TEXCOORD_1") lightmap_uv: [f32; 2],
+        ("_LIGHT_STYLES") light_styles: [u8; 4],
     }
 }
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
-struct SharedVertex {
-    vertex: usize,
-    texture: usize,
-}
-
 pub struct TextureInfo {
     pub name: String,
-    pub image_data: MipmapedTextureData,
+    pub image_data: Arc<MipmapedTextureData>,
 }
 
 impl TextureInfo {
-    fn new(name: String, image_data: MipmapedTextureData) -> Self {
+    fn new(name: String, image_data: Arc<MipmapedTextureData>) -> Self {
         Self { name, image_data }
     }
 }
 
+pub struct MapModel {
+    pub model: Model<ModelVertex>,
+    // The leaf that each mesh was generated from
+    pub mesh_leaves: Vec<usize>,
+}
+
 pub fn export<P: AsRef<Path>, T: AsRef<Path>>(
     game_root: T,
+    mod_path: Option<&Path>,
     reader: &BspReader,
     export_file_path: P,
     mut log: Option<&mut String>,
@@ -56,10 +54,14 @@ pub fn export<P: AsRef<Path>, T: AsRef<Path>>(
     let game_root = game_root.as_ref();
 
     let mut wad_resources = WadCollection::new();
-    read_wad_resources(reader, game_root, &mut wad_resources);
+    read_wad_resources(reader, game_root, mod_path, &mut wad_resources)
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+    let palette = read_palette(reader, game_root);
 
-    let textures = read_textures(reader, &wad_resources);
-    let model = convert(reader, &textures);
+    let textures = read_textures(reader, &wad_resources, palette.as_deref());
+    let lightmaps = LightmapAtlas::new(reader)
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+    let model = convert(reader, &lightmaps);
 
     let mut buffer_writer = BufferWriter::new();
 
@@ -138,58 +140,115 @@ pub fn export<P: AsRef<Path>, T: AsRef<Path>>(
             .unwrap();
     }
 
+    // Write the lightmap atlas that TEXCOORD_1 points into, one image per
+    // light style slot in _LIGHT_STYLES
+    for (i, layer) in lightmaps.layers.iter().enumerate() {
+        texture_path.set_file_name(format!("lightmap{}.png", i));
+        layer
+            .save_with_format(&texture_path, image::ImageFormat::Png)
+            .map_err(std::io::Error::other)?;
+    }
+
     Ok(())
 }
 
+// Maps store the paths their WADs had on the mapper's machine, e.g.
+// "\quiver\valve\halflife.wad" or "C:\Sierra\Half-Life\cstrike\cs_dust.wad",
+// so only the file name is used. It's looked for in the map's mod directory
+// first, then in the base game's.
 pub fn read_wad_resources<P: AsRef<Path>>(
     reader: &BspReader,
     game_root: P,
+    mod_path: Option<&Path>,
     wad_resources: &mut WadCollection,
-) {
-    let entities = BspEntity::parse_entities(reader.read_entities_str());
-    let game_root = game_root.as_ref();
+) -> Result<(), BspError> {
+    // Quake maps always embed their textures
+    if reader.variant() == BspVariant::Quake {
+        return Ok(());
+    }
+    let entities = BspEntity::parse_entities(reader.read_entities()?)
+        .map_err(BspError::InvalidEntitySyntax)?;
+    let search_paths: Vec<_> = mod_path
+        .map(Path::to_owned)
+        .into_iter()
+        .chain([game_root.as_ref().join("valve")])
+        .collect();
     for entity in &entities {
-        if let Some(value) = entity.0.get("wad") {
-            for wad_path in value.split(';') {
-                assert!(wad_path.starts_with(QUIVER_PREFIX));
-                let wad_path = &wad_path[QUIVER_PREFIX.len()..];
-                let mut path = game_root.to_owned();
-                path.push(wad_path);
-                if !path.exists() {
-                    println!("WARNING: Could not find \"{}\"", path.display());
+        if let Some(value) = entity.get("wad") {
+            for wad_path in value.split(';').filter(|wad_path| !wad_path.is_empty()) {
+                let file_name = wad_path.rsplit(['\\', '/']).next().unwrap_or(wad_path);
+                let Some(path) = search_paths
+                    .iter()
+                    .map(|search_path| search_path.join(file_name))
+                    .find(|path| path.exists())
+                else {
+                    println!("WARNING: Could not find \"{}\"", wad_path);
                     continue;
+                };
+                match WadArchive::open(&path) {
+                    Ok(archive) => wad_resources.add(archive),
+                    Err(error) => {
+                        println!("WARNING: Could not read \"{}\": {}", path.display(), error)
+                    }
                 }
-                let archive = WadArchive::open(path);
-                wad_resources.add(archive);
             }
         }
     }
+    Ok(())
+}
+
+// Returns the shared palette used by the textures of Quake maps.
+pub fn read_palette<P: AsRef<Path>>(reader: &BspReader, game_root: P) -> Option<Vec<u8>> {
+    if reader.variant() != BspVariant::Quake {
+        return None;
+    }
+    let mut path = game_root.as_ref().to_owned();
+    path.push("gfx");
+    path.push("palette.lmp");
+    Some(read_palette_file(&path))
+}
+
+// Falls back to a greyscale palette if the file can't be read.
+pub fn read_palette_file(path: &Path) -> Vec<u8> {
+    match std::fs::read(path) {
+        Ok(palette) if palette.len() >= PALETTE_LEN => palette,
+        _ => {
+            println!(
+                "WARNING: Could not read \"{}\", using a greyscale palette",
+                path.display()
+            );
+            greyscale_palette()
+        }
+    }
 }
 
-pub fn read_textures(reader: &BspReader, wad_resources: &WadCollection) -> Vec<TextureInfo> {
-    let texture_reader = reader.read_textures();
+pub fn read_textures(
+    reader: &BspReader,
+    wad_resources: &WadCollection,
+    palette: Option<&[u8]>,
+) -> Vec<TextureInfo> {
+    let texture_reader = reader.read_textures().unwrap();
     let mut textures = Vec::with_capacity(texture_reader.len());
     for i in 0..texture_reader.len() {
         let reader = texture_reader.get(i).unwrap();
-        let name = reader.get_image_name();
+        let name = reader.get_image_name().unwrap();
         let texture_info = if reader.has_local_image_data() {
-            let len = reader.raw_data().len();
-            let mut data = vec![0u8; len];
-            data.as_mut_slice().copy_from_slice(reader.raw_data());
-            let mut reader = std::io::Cursor::new(&data);
-            let texture_data = WadArchive::decode_mipmaped_image_from_reader(&mut reader);
-            TextureInfo::new(name.to_owned(), texture_data)
+            let texture_data = if let Some(palette) = palette {
+                reader.decode_with_palette(palette)
+            } else {
+                reader.decode()
+            }
+            .unwrap();
+            TextureInfo::new(name.to_owned(), Arc::new(texture_data))
         } else {
-            let search_name = name.to_uppercase();
-            let texture_data =
-                if let Some((archive, file)) = wad_resources.find(search_name.as_str()) {
-                    //println!("Found \"{}\"!", name);
-                    let texture_data = archive.decode_mipmaped_image(file);
-                    Some(texture_data)
-                } else {
-                    println!("Couldn't find \"{}\"", name);
-                    None
-                };
+            let texture_data = if let Some((archive, file)) = wad_resources.find(name) {
+                //println!("Found \"{}\"!", name);
+                let texture_data = archive.decode_mipmaped_image(file).unwrap();
+                Some(texture_data)
+            } else {
+                println!("Couldn't find \"{}\"", name);
+                None
+            };
             TextureInfo::new(name.to_owned(), texture_data.unwrap())
         };
         textures.push(texture_info);
@@ -197,21 +256,65 @@ pub fn read_textures(reader: &BspReader, wad_resources: &WadCollection) -> Vec<T
     textures
 }
 
-fn convert(reader: &BspReader, textures: &[TextureInfo]) -> Model<ModelVertex> {
+// Returns the bytes of the map with the textures it loads from WADs embedded
+// in its texture lump.
+pub fn embed_textures<P: AsRef<Path>>(
+    game_root: P,
+    mod_path: Option<&Path>,
+    reader: &BspReader,
+    strip_wad_key: bool,
+) -> Result<Vec<u8>, BspError> {
+    let mut wad_resources = WadCollection::new();
+    read_wad_resources(reader, game_root, mod_path, &mut wad_resources)?;
+
+    let mut writer = BspWriter::from_reader(reader)?;
+    let missing = writer.embed_textures(|name| {
+        let (archive, file) = wad_resources.find(name)?;
+        if file.texture_type != TextureType::MipmappedImage {
+            return None;
+        }
+        Some(archive.read_raw(file).ok()?.into_owned())
+    })?;
+    for name in &missing {
+        println!("WARNING: Couldn't embed \"{}\"", name);
+    }
+
+    if strip_wad_key {
+        if missing.is_empty() {
+            let source = std::mem::take(&mut writer.entities);
+            let mut entities =
+                BspEntity::parse_entities(&source).map_err(BspError::InvalidEntitySyntax)?;
+            for entity in &mut entities {
+                if entity.get("classname") == Some("worldspawn") {
+                    entity.0.retain(|(key, _)| *key != "wad");
+                }
+            }
+            writer.entities = BspEntity::write_entities(&entities);
+        } else {
+            println!("WARNING: Keeping the \"wad\" key, some textures weren't embedded");
+        }
+    }
+
+    Ok(writer.to_bytes())
+}
+
+fn convert(reader: &BspReader, lightmaps: &LightmapAtlas) -> Model<ModelVertex> {
+    let faces = read_face_geometry(reader);
     let mut indices = Vec::new();
     let mut vertices = Vec::new();
     let mut meshes = Vec::new();
-    let mut vertex_map = HashMap::<SharedVertex, usize>::new();
+    let mut mesh_leaves = Vec::new();
     convert_node(
         reader,
+        &faces,
         reader.read_nodes(),
         0,
         true,
         &mut indices,
         &mut vertices,
-        &mut vertex_map,
         &mut meshes,
-        &textures,
+        &mut mesh_leaves,
+        lightmaps,
     );
 
     Model {
@@ -221,8 +324,9 @@ fn convert(reader: &BspReader, textures: &[TextureInfo]) -> Model<ModelVertex> {
     }
 }
 
-pub fn convert_models(reader: &BspReader, textures: &[TextureInfo]) -> Vec<Model<ModelVertex>> {
+pub fn convert_models(reader: &BspReader, lightmaps: &LightmapAtlas) -> Vec<MapModel> {
     let bsp_models = reader.read_models();
+    let faces = read_face_geometry(reader);
 
     let mut models = Vec::with_capacity(bsp_models.len());
     for bsp_model in bsp_models {
@@ -230,128 +334,79 @@ pub fn convert_models(reader: &BspReader, textures: &[TextureInfo]) -> Vec<Model
         let mut indices = Vec::new();
         let mut vertices = Vec::new();
         let mut meshes = Vec::new();
-        let mut vertex_map = HashMap::<SharedVertex, usize>::new();
+        let mut mesh_leaves = Vec::new();
         convert_node(
             reader,
+            &faces,
             reader.read_nodes(),
             node_index,
             node_index == 0,
             &mut indices,
             &mut vertices,
-            &mut vertex_map,
             &mut meshes,
-            &textures,
+            &mut mesh_leaves,
+            lightmaps,
         );
 
-        models.push(Model {
-            indices,
-            vertices,
-            meshes,
+        models.push(MapModel {
+            model: Model {
+                indices,
+                vertices,
+                meshes,
+            },
+            mesh_leaves,
         });
     }
 
     models
 }
 
-fn process_indexed_triangles(
-    triangle_list: &[SharedVertex],
-    face: &BspFace,
-    normal: [f32; 3],
-    bsp_vertices: &[BspVertex],
-    textures: &[TextureInfo],
-    texture_infos: &[BspTextureInfo],
-    indices: &mut Vec<u32>,
-    vertices: &mut Vec<ModelVertex>,
-    vertex_map: &mut HashMap<SharedVertex, usize>,
-) {
-    assert!(
-        triangle_list.len() % 3 == 0,
-        "Vertices are not a multiple of 3: {}",
-        triangle_list.len()
-    );
-    let texture_info = &texture_infos[face.texture_info as usize];
-    let texture = &textures[texture_info.texture_index as usize].image_data;
-    let mut process_trivert = |trivert| {
-        let index = if let Some(index) = vertex_map.get(trivert) {
-            *index
-        } else {
-            let pos = convert_coordinates(bsp_vertices[trivert.vertex as usize].to_array());
-            let pos_vec = Vec3::from_array(pos);
-            let s = Vec3::from_array(convert_coordinates(texture_info.s));
-            let t = Vec3::from_array(convert_coordinates(texture_info.t));
-            let uv = [
-                pos_vec.dot(s) + texture_info.s_shift,
-                pos_vec.dot(t) + texture_info.t_shift,
-            ];
-
-            let normal = convert_coordinates(normal);
-
-            let uv = [
-                uv[0] / texture.image_width as f32,
-                uv[1] / texture.image_height as f32,
-            ];
-
-            let index = vertices.len();
-            vertices.push(ModelVertex { pos, normal, uv });
-            vertex_map.insert(*trivert, index);
-            index
-        };
-        indices.push(index as u32);
+// Faces that can't be read are logged and left out.
+fn read_face_geometry(reader: &BspReader) -> Vec<Option<BspFaceGeometry<'_>>> {
+    let faces = match reader.iter_face_geometry() {
+        Ok(faces) => faces,
+        Err(error) => {
+            println!("WARNING: Could not read the map's textures: {}", error);
+            return reader.read_faces().iter().map(|_| None).collect();
+        }
     };
-
-    for trivert in triangle_list {
-        process_trivert(trivert);
-    }
-}
-
-pub struct WadCollection {
-    wads: Vec<WadArchive>,
-}
-
-impl WadCollection {
-    pub fn new() -> Self {
-        Self { wads: Vec::new() }
-    }
-
-    pub fn add(&mut self, archive: WadArchive) {
-        self.wads.push(archive);
-    }
-
-    pub fn find(&self, key: &str) -> Option<(&WadArchive, &WadFileInfo)> {
-        for wad in &self.wads {
-            if let Some(file) = wad.files.iter().find(|x| x.name.as_str() == key) {
-                return Some((wad, file));
+    faces
+        .enumerate()
+        .map(|(face_index, geometry)| match geometry {
+            Ok(geometry) => Some(geometry),
+            Err(error) => {
+                println!("WARNING: Skipping face {}: {}", face_index, error);
+                None
             }
-        }
-        None
-    }
-}
-
-fn read_vertex_index(edge_index: &BspSurfaceEdge, edges: &[BspEdge]) -> u32 {
-    let edge_vertex_index: usize = if edge_index.0 > 0 { 0 } else { 1 };
-    let edge_index = edge_index.0.abs() as usize;
-    let edge = &edges[edge_index];
-    edge.vertices[edge_vertex_index] as u32
+        })
+        .collect()
 }
 
 fn convert_node(
     reader: &BspReader,
+    faces: &[Option<BspFaceGeometry>],
     nodes: &[BspNode],
     node_index: i16,
     allow_zero: bool,
     indices: &mut Vec<u32>,
     vertices: &mut Vec<ModelVertex>,
-    vertex_map: &mut HashMap<SharedVertex, usize>,
     meshes: &mut Vec<Mesh>,
-    textures: &[TextureInfo],
+    mesh_leaves: &mut Vec<usize>,
+    lightmaps: &LightmapAtlas,
 ) {
     let node_index = if node_index > 0 || (node_index == 0 && allow_zero) {
         node_index as usize
     } else {
-        let leaf_index = !node_index;
-        let leaf = &reader.read_leaves()[leaf_index as usize];
+        let leaf_index = !node_index as usize;
         convert_leaf(
-            reader, leaf, indices, vertices, vertex_map, meshes, textures,
+            reader,
+            faces,
+            leaf_index,
+            indices,
+            vertices,
+            meshes,
+            mesh_leaves,
+            lightmaps,
         );
         return;
     };
@@ -359,98 +414,83 @@ fn convert_node(
     let current_node = &nodes[node_index];
     convert_node(
         reader,
+        faces,
         nodes,
         current_node.children[0],
         false,
         indices,
         vertices,
-        vertex_map,
         meshes,
-        textures,
+        mesh_leaves,
+        lightmaps,
     );
     convert_node(
         reader,
+        faces,
         nodes,
         current_node.children[1],
         false,
         indices,
         vertices,
-        vertex_map,
         meshes,
-        textures,
+        mesh_leaves,
+        lightmaps,
     );
 }
 
+#[allow(clippy::too_many_arguments)]
 fn convert_leaf(
     reader: &BspReader,
-    leaf: &BspLeaf,
+    faces: &[Option<BspFaceGeometry>],
+    leaf_index: usize,
     indices: &mut Vec<u32>,
     vertices: &mut Vec<ModelVertex>,
-    vertex_map: &mut HashMap<SharedVertex, usize>,
     meshes: &mut Vec<Mesh>,
-    textures: &[TextureInfo],
+    mesh_leaves: &mut Vec<usize>,
+    lightmaps: &LightmapAtlas,
 ) {
-    let bsp_vertices = reader.read_vertices();
-    let texture_infos = reader.read_texture_infos();
+    let leaf = &reader.read_leaves()[leaf_index];
     let mark_surfaces = reader.read_mark_surfaces();
-    let faces = reader.read_faces();
-    let edges = reader.read_edges();
-    let surface_edges = reader.read_surface_edges();
-    let planes = reader.read_planes();
+    let bsp_faces = reader.read_faces();
 
     let mark_surfaces_range = leaf.first_mark_surface..leaf.first_mark_surface + leaf.mark_surfaces;
     for mark_surface_index in mark_surfaces_range {
         let mark_surface = &mark_surfaces[mark_surface_index as usize];
-        let face = &faces[mark_surface.0 as usize];
+        let face_index = mark_surface.0 as usize;
+        let face = &bsp_faces[face_index];
 
         if face.texture_info == 0 {
             continue;
         }
 
-        let surface_edges_range =
-            face.first_edge as usize..face.first_edge as usize + face.edges as usize;
-        let surface_edges = &surface_edges[surface_edges_range];
-
-        let plane = &planes[face.plane as usize];
-
-        let first_vertex = read_vertex_index(&surface_edges[0], edges);
-
-        let mut triangle_list = Vec::new();
-        let to_shared_vertex = |index: u32, face: &BspFace| -> SharedVertex {
-            SharedVertex {
-                vertex: index as usize,
-                texture: face.texture_info as usize,
-            }
+        let geometry = match &faces[face_index] {
+            Some(geometry) => geometry,
+            None => continue,
         };
-        for i in 0..surface_edges.len() - 2 {
-            triangle_list.push(to_shared_vertex(
-                read_vertex_index(&surface_edges[i + 2], edges),
-                face,
-            ));
-            triangle_list.push(to_shared_vertex(
-                read_vertex_index(&surface_edges[i + 1], edges),
-                face,
-            ));
-            triangle_list.push(to_shared_vertex(first_vertex, face));
+        let normal = convert_coordinates(geometry.normal);
+        let light_styles = lightmaps.get_styles(face_index);
+        let first_vertex = vertices.len();
+        for vertex in &geometry.vertices {
+            vertices.push(ModelVertex {
+                pos: convert_coordinates(vertex.position),
+                normal,
+                uv: vertex.uv,
+                lightmap_uv: lightmaps.get_uv(face_index, vertex.texture_coord),
+                light_styles,
+            });
         }
+
         let start = indices.len();
-        process_indexed_triangles(
-            &triangle_list,
-            face,
-            plane.normal,
-            bsp_vertices,
-            textures,
-            texture_infos,
-            indices,
-            vertices,
-            vertex_map,
-        );
+        for triangle in geometry.triangles() {
+            indices.extend(triangle.map(|i| (first_vertex + i) as u32));
+        }
         let end = indices.len();
 
         meshes.push(Mesh {
             indices_range: start..end,
-            texture_index: texture_infos[face.texture_info as usize].texture_index as usize,
+            texture_index: geometry.texture_index,
         });
+        mesh_leaves.push(leaf_index);
     }
 }
 
@@ -563,10 +603,10 @@ fn log_bsp(reader: &BspReader, log: &mut String) {
     }
 
     writeln!(log, "Textures:").unwrap();
-    let texture_reader = reader.read_textures();
+    let texture_reader = reader.read_textures().unwrap();
     for i in 0..texture_reader.len() {
         let reader = texture_reader.get(i).unwrap();
-        let name = reader.get_image_name();
+        let name = reader.get_image_name().unwrap();
         writeln!(
             log,
             "  {} - {} - {}",
@@ -577,8 +617,8 @@ fn log_bsp(reader: &BspReader, log: &mut String) {
         .unwrap();
     }
 
-    let entities = reader.read_entities_str();
-    let entities = BspEntity::parse_entities(entities);
+    let entities = reader.read_entities().unwrap();
+    let entities = BspEntity::parse_entities(entities).unwrap();
     writeln!(log, "Entities:").unwrap();
     for (i, entity) in entities.iter().enumerate() {
         writeln!(log, "  Entity {}", i).unwrap();
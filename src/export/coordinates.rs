@@ -15,6 +15,10 @@ pub fn convert_coordinates<T: Copy>(half_life_xyz: [T; 3]) -> [T; 3] {
     [half_life_xyz[1], half_life_xyz[2], half_life_xyz[0]]
 }
 
+pub fn convert_coordinates_to_half_life<T: Copy>(gltf_xyz: [T; 3]) -> [T; 3] {
+    [gltf_xyz[2], gltf_xyz[0], gltf_xyz[1]]
+}
+
 pub fn write_and_convert_channel(base: &mut Vec3, channel: VectorChannel, value: f32) {
     match channel {
         // HL X => GLTF Z
@@ -0,0 +1,104 @@
+use std::path::Path;
+
+use gsparser::bsp::{BspEntity, BspReader, BspWriter};
+
+pub fn export<P: AsRef<Path>>(reader: &BspReader, export_file_path: P) -> std::io::Result<()> {
+    let entities = reader.read_entities().map_err(invalid_data)?;
+    std::fs::write(export_file_path, entities)
+}
+
+// Returns the bytes of the map with its entity lump replaced by the
+// contents of the .ent file.
+pub fn import<P: AsRef<Path>>(reader: &BspReader, ent_file_path: P) -> std::io::Result<Vec<u8>> {
+    let source = std::fs::read_to_string(ent_file_path)?;
+    let entities = BspEntity::parse_entities(&source).map_err(invalid_data)?;
+    let model_count = reader.read_models().len();
+    for (i, entity) in entities.iter().enumerate() {
+        for model in entity.get_all("model") {
+            validate_model_reference(model, model_count)
+                .map_err(|message| invalid_data(format!("entity {}: {}", i, message)))?;
+        }
+    }
+
+    let mut writer = BspWriter::from_reader(reader).map_err(invalid_data)?;
+    writer.entities = BspEntity::write_entities(&entities);
+    Ok(writer.to_bytes())
+}
+
+// Brush entities reference the map's models as "*N"
+fn validate_model_reference(value: &str, model_count: usize) -> Result<(), String> {
+    if let Some(index) = value.strip_prefix('*') {
+        match index.parse::<usize>() {
+            Ok(index) if index < model_count => {}
+            _ => {
+                return Err(format!(
+                    "model \"{}\" doesn't exist (the map has {} models)",
+                    value, model_count
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use gsparser::bsp::BspModel;
+
+    use super::*;
+
+    // Imports the source into a map with the world and one brush model
+    fn import_source(name: &str, source: &str) -> std::io::Result<Vec<u8>> {
+        let model = BspModel {
+            mins: [-64.0; 3],
+            maxs: [64.0; 3],
+            origin: [0.0; 3],
+            head_nodes: [0; 4],
+            vis_leaves: 0,
+            first_face: 0,
+            faces: 0,
+        };
+        let writer = BspWriter {
+            models: vec![model; 2],
+            ..Default::default()
+        };
+        let reader = BspReader::read(writer.to_bytes()).unwrap();
+
+        let file_name = format!("goldsrc-asset-viewer-{}-{}.ent", name, std::process::id());
+        let path = std::env::temp_dir().join(file_name);
+        std::fs::write(&path, source).unwrap();
+        let result = import(&reader, &path);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn imported_entities_replace_the_lump() {
+        let source = concat!(
+            "{\n\"classname\" \"worldspawn\"\n}\n",
+            "{\n\"classname\" \"func_door\"\n\"model\" \"*1\"\n}\n",
+            "{\n\"classname\" \"monster_barney\"\n\"model\" \"models/barney.mdl\"\n}\n",
+        );
+        let data = import_source("imported_entities", source).unwrap();
+        let reader = BspReader::read(data).unwrap();
+        assert_eq!(reader.read_entities().unwrap(), source);
+    }
+
+    #[test]
+    fn missing_brush_models_are_rejected() {
+        for model in ["*2", "*-1", "*door"] {
+            let source = format!(
+                "{{\n\"classname\" \"func_door\"\n\"model\" \"{}\"\n}}\n",
+                model
+            );
+            let error = import_source("missing_brush_models", &source).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+            let message = format!("model \"{}\" doesn't exist (the map has 2 models)", model);
+            assert!(error.to_string().contains(&message), "{}", error);
+        }
+    }
+}
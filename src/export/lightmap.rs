@@ -0,0 +1,211 @@
+use std::{collections::HashMap, fmt::Display};
+
+use gsparser::bsp::{BspLightmapExtents, BspReader};
+
+const ATLAS_WIDTH: u32 = 1024;
+// The largest 2D texture wgpu allows by default
+const MAX_ATLAS_SIZE: u32 = 8192;
+const LUXEL_SIZE: f32 = 16.0;
+pub const MAX_LIGHTMAP_STYLES: usize = 4;
+const UNLIT_STYLES: [u8; MAX_LIGHTMAP_STYLES] = [0, 255, 255, 255];
+
+#[derive(Copy, Clone, Debug)]
+struct AtlasBlock {
+    x: u32,
+    y: u32,
+    extents: BspLightmapExtents,
+    styles: [u8; MAX_LIGHTMAP_STYLES],
+}
+
+// Each layer holds one of the (up to) four light styles of every face. Only
+// as many layers as the face with the most styles needs are allocated.
+pub struct LightmapAtlas {
+    pub layers: Vec<image::RgbaImage>,
+    blocks: HashMap<usize, AtlasBlock>,
+}
+
+#[derive(Debug)]
+pub struct LightmapAtlasError {
+    pub lightmaps: usize,
+}
+
+impl Display for LightmapAtlasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} lightmaps don't fit in a {}x{} atlas",
+            self.lightmaps, MAX_ATLAS_SIZE, MAX_ATLAS_SIZE
+        )
+    }
+}
+
+impl std::error::Error for LightmapAtlasError {}
+
+impl LightmapAtlas {
+    pub fn new(reader: &BspReader) -> Result<Self, LightmapAtlasError> {
+        let faces = reader.read_faces();
+        let mut lightmaps = Vec::with_capacity(faces.len());
+        for face_index in 0..faces.len() {
+            if let Some(lightmap) = reader.read_lightmap(face_index) {
+                lightmaps.push((face_index, lightmap));
+            }
+        }
+
+        // The first luxel is left white (in the first layer) for faces that
+        // don't have any lighting data
+        let sizes: Vec<_> = lightmaps
+            .iter()
+            .map(|(_, lightmap)| [lightmap.extents.width, lightmap.extents.height])
+            .collect();
+        let ([width, height], positions) = pack_blocks(&sizes).ok_or(LightmapAtlasError {
+            lightmaps: lightmaps.len(),
+        })?;
+        let mut blocks = HashMap::with_capacity(lightmaps.len());
+        for ((face_index, lightmap), [x, y]) in lightmaps.iter().zip(positions) {
+            blocks.insert(
+                *face_index,
+                AtlasBlock {
+                    x,
+                    y,
+                    extents: lightmap.extents,
+                    styles: lightmap.styles,
+                },
+            );
+        }
+
+        let layer_count = lightmaps
+            .iter()
+            .map(|(_, lightmap)| lightmap.style_count().min(MAX_LIGHTMAP_STYLES))
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let mut layers = vec![image::RgbaImage::new(width, height); layer_count];
+        layers[0].put_pixel(0, 0, image::Rgba([255, 255, 255, 255]));
+        for (face_index, lightmap) in &lightmaps {
+            let block = &blocks[face_index];
+            for (style_index, layer) in layers.iter_mut().enumerate() {
+                let Some((_, samples)) = lightmap.get_style(style_index) else {
+                    break;
+                };
+                for (i, sample) in samples.iter().enumerate() {
+                    let sample_x = block.x + (i as u32 % block.extents.width);
+                    let sample_y = block.y + (i as u32 / block.extents.width);
+                    layer.put_pixel(
+                        sample_x,
+                        sample_y,
+                        image::Rgba([sample[0], sample[1], sample[2], 255]),
+                    );
+                }
+            }
+        }
+
+        Ok(Self { layers, blocks })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.layers[0].width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.layers[0].height()
+    }
+
+    pub fn get_styles(&self, face_index: usize) -> [u8; MAX_LIGHTMAP_STYLES] {
+        self.blocks
+            .get(&face_index)
+            .map(|block| block.styles)
+            .unwrap_or(UNLIT_STYLES)
+    }
+
+    // Takes the unscaled texture space coordinates of a vertex on the face.
+    pub fn get_uv(&self, face_index: usize, texture_coord: [f32; 2]) -> [f32; 2] {
+        let width = self.width() as f32;
+        let height = self.height() as f32;
+        if let Some(block) = self.blocks.get(&face_index) {
+            // Offset by half a luxel so we sample the center of each luxel
+            let s = texture_coord[0] - block.extents.texture_mins[0] as f32 + (LUXEL_SIZE / 2.0);
+            let t = texture_coord[1] - block.extents.texture_mins[1] as f32 + (LUXEL_SIZE / 2.0);
+            [
+                (block.x as f32 + (s / LUXEL_SIZE)) / width,
+                (block.y as f32 + (t / LUXEL_SIZE)) / height,
+            ]
+        } else {
+            [0.5 / width, 0.5 / height]
+        }
+    }
+}
+
+// Shelf packs the blocks, tallest first, after the reserved first luxel. The
+// atlas is widened until it's no taller than MAX_ATLAS_SIZE. Returns the size
+// of the atlas and the position of each block.
+fn pack_blocks(sizes: &[[u32; 2]]) -> Option<([u32; 2], Vec<[u32; 2]>)> {
+    let mut order: Vec<_> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i][1]));
+    let mut width = sizes
+        .iter()
+        .map(|[width, _]| *width)
+        .max()
+        .unwrap_or(1)
+        .max(ATLAS_WIDTH);
+    while width <= MAX_ATLAS_SIZE {
+        let mut positions = vec![[0, 0]; sizes.len()];
+        let mut x = 1;
+        let mut y = 0;
+        let mut shelf_height = 1;
+        for &i in &order {
+            let [block_width, block_height] = sizes[i];
+            if x + block_width > width {
+                x = 0;
+                y += shelf_height;
+                shelf_height = 0;
+            }
+            positions[i] = [x, y];
+            x += block_width;
+            shelf_height = shelf_height.max(block_height);
+        }
+        let height = y + shelf_height;
+        if height <= MAX_ATLAS_SIZE {
+            return Some(([width, height], positions));
+        }
+        width *= 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_packed_after_the_reserved_luxel() {
+        let (size, positions) = pack_blocks(&[[4, 2], [3, 5], [1020, 1]]).unwrap();
+        assert_eq!(size, [ATLAS_WIDTH, 6]);
+        // Tallest first, wrapping to a new shelf once the row is full
+        assert_eq!(positions, vec![[4, 0], [1, 0], [0, 5]]);
+    }
+
+    #[test]
+    fn empty_atlas_only_holds_the_reserved_luxel() {
+        let (size, positions) = pack_blocks(&[]).unwrap();
+        assert_eq!(size, [ATLAS_WIDTH, 1]);
+        assert!(positions.is_empty());
+    }
+
+    #[test]
+    fn tall_atlases_are_widened() {
+        // One block per shelf at 1024 wide would be 16 * 1024 tall
+        let sizes = vec![[1024, 1024]; 16];
+        let ([width, height], positions) = pack_blocks(&sizes).unwrap();
+        assert!(width > ATLAS_WIDTH && width <= MAX_ATLAS_SIZE);
+        assert!(height <= MAX_ATLAS_SIZE);
+        for [x, y] in positions {
+            assert!(x + 1024 <= width && y + 1024 <= height);
+        }
+    }
+
+    #[test]
+    fn oversized_atlases_are_rejected() {
+        let sizes = vec![[MAX_ATLAS_SIZE, MAX_ATLAS_SIZE]; 2];
+        assert!(pack_blocks(&sizes).is_none());
+    }
+}
@@ -45,6 +45,22 @@ pub fn export<P: AsRef<Path>>(
 
             writeln!(log, "  {} - {}", label, name).unwrap();
         }
+
+        writeln!(log, "Animation Events:").unwrap();
+        for animation in &file.animations {
+            for event in &animation.events {
+                writeln!(
+                    log,
+                    "  {} (frame {}): {} {:?} \"{}\"",
+                    animation.name,
+                    event.frame,
+                    event.id,
+                    event.kind(),
+                    event.options
+                )
+                .unwrap();
+            }
+        }
     }
 
     // Compute bone transforms
@@ -166,7 +182,8 @@ pub fn export<P: AsRef<Path>>(
     for gs_animation in &file.animations {
         let mut animation = gltf::animation::Animation::new(gs_animation.name.clone());
         let mut should_add = false;
-        for bone_animation in &gs_animation.bone_animations {
+        // glTF doesn't have blending, so only the first blend is exported
+        for bone_animation in &gs_animation.blends[0] {
             let target_bone = bone_animation.target;
 
             // We need to collapse animations that target the same component
@@ -1,4 +1,7 @@
 pub mod bsp;
 pub mod coordinates;
+pub mod ent;
+pub mod lightmap;
 pub mod mdl;
 pub mod transform;
+pub mod wad;
@@ -0,0 +1,36 @@
+use std::{error::Error, path::Path};
+
+use gsparser::wad3::WadWriter;
+
+// Builds a WAD from the PNG files in the directory, named after the files.
+// Images that are a multiple of 16 pixels in size become mipmapped textures,
+// the others are stored as plain pictures.
+pub fn import_images<P: AsRef<Path>>(image_dir: P) -> Result<WadWriter, Box<dyn Error>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(image_dir)? {
+        let path = entry?.path();
+        let is_png = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+        if is_png {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut writer = WadWriter::new();
+    for path in paths {
+        let name = path
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("\"{}\" doesn't have a valid name", path.display()))?;
+        let image = image::open(&path)?.to_rgba8();
+        if image.width() % 16 == 0 && image.height() % 16 == 0 {
+            writer.add_mipmapped_image(name, &image)?;
+        } else {
+            writer.add_image(name, &image)?;
+        }
+    }
+    Ok(writer)
+}
@@ -1,5 +1,5 @@
 use glam::Vec3;
-use gsparser::bsp::{BspClipNode, BspContents, BspNode, BspReader, FromValue};
+use gsparser::bsp::{BspContents, BspNode, BspReader};
 
 use crate::export::coordinates::convert_coordinates;
 
@@ -24,32 +24,6 @@ pub fn hittest_node_for_leaf(
     )
 }
 
-pub struct IntersectionInfo {
-    pub position: Vec3,
-    pub normal: Vec3,
-}
-
-pub fn hittest_clip_node(
-    reader: &BspReader,
-    clip_node_index: usize,
-    start: Vec3,
-    end: Vec3,
-) -> Option<IntersectionInfo> {
-    let p1 = start;
-    let p2 = end;
-    let nodes = reader.read_clip_nodes();
-    hittest_node_for_leaf_impl(
-        reader,
-        nodes,
-        clip_node_index as i16,
-        p1,
-        p2,
-        true,
-        &clip_node_resolver,
-        Vec3::new(0.0, 1.0, 0.0),
-    )
-}
-
 trait RaycastNode {
     fn plane(&self) -> u32;
     fn children(&self) -> &[i16; 2];
@@ -65,16 +39,6 @@ impl RaycastNode for BspNode {
     }
 }
 
-impl RaycastNode for BspClipNode {
-    fn plane(&self) -> u32 {
-        self.plane_index as u32
-    }
-
-    fn children(&self) -> &[i16; 2] {
-        &self.children
-    }
-}
-
 fn hittest_node_for_leaf_impl<
     T: RaycastNode,
     V,
@@ -168,7 +132,7 @@ fn node_resolver(
     } else {
         let leaf_index = !node_index;
         let leaf = &reader.read_leaves()[leaf_index as usize];
-        if leaf.contents() == BspContents::Solid {
+        if leaf.contents().ok() == Some(BspContents::Solid) {
             return ResolvedNode::Leaf(Some((p1, leaf_index as usize)));
         } else {
             return ResolvedNode::Leaf(None);
@@ -176,26 +140,3 @@ fn node_resolver(
     };
     ResolvedNode::NodeIndex(node_index)
 }
-
-fn clip_node_resolver(
-    _reader: &BspReader,
-    node_index: i16,
-    allow_zero: bool,
-    p1: Vec3,
-    normal: Vec3,
-) -> ResolvedNode<IntersectionInfo> {
-    let node_index = if node_index > 0 || (node_index == 0 && allow_zero) {
-        node_index as usize
-    } else {
-        let contents = BspContents::from_value(node_index as i32).unwrap();
-        if contents == BspContents::Solid {
-            return ResolvedNode::Leaf(Some(IntersectionInfo {
-                position: p1,
-            normal,
-        }));
-        } else {
-            return ResolvedNode::Leaf(None);
-        }
-    };
-    ResolvedNode::NodeIndex(node_index)
-}
@@ -15,15 +15,17 @@ use bsp_viewer::BspViewer;
 use clap::*;
 use cli::Cli;
 use glam::Vec2;
-use export::bsp::{read_textures, read_wad_resources, WadCollection};
-use gsparser::bsp::{BspEntity, BspReader};
-use gsparser::wad3::{WadArchive, WadFileInfo};
+use export::bsp::{read_palette, read_textures, read_wad_resources};
+use export::lightmap::LightmapAtlas;
+use gsparser::bsp::{BspEntity, BspReader, BspVariant};
+use gsparser::palette::greyscale_palette;
+use gsparser::wad3::{WadArchive, WadCollection, WadFileInfo, WadFormat};
 use hittest::hittest_node_for_leaf;
 use imgui::*;
 use imgui_wgpu::RendererConfig;
 use mouse::{MouseInputController, MouseInputMode};
 use rendering::bsp::BspRenderer;
-use rendering::Renderer;
+use rendering::{LightingMode, Renderer};
 use rfd::FileDialog;
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
@@ -66,7 +68,30 @@ enum FileInfo {
 fn main() {
     let cli = Cli::parse();
 
-    if cli.export_file_path.is_none() {
+    if let Some(ent_file_path) = &cli.import_entities {
+        let file_path = cli.file_path.as_ref().expect("Expected input path!");
+        let output_path = cli
+            .export_file_path
+            .as_ref()
+            .expect("Expected output path!");
+        import_entities(file_path, ent_file_path, output_path);
+        println!("Done!");
+    } else if cli.embed_textures {
+        let file_path = cli.file_path.as_ref().expect("Expected input path!");
+        let output_path = cli
+            .export_file_path
+            .as_ref()
+            .expect("Expected output path!");
+        embed_textures(file_path, output_path, cli.strip_wad_key);
+        println!("Done!");
+    } else if let Some(image_dir) = &cli.import_images {
+        let output_path = cli
+            .export_file_path
+            .as_ref()
+            .expect("Expected output path!");
+        import_images(image_dir, output_path);
+        println!("Done!");
+    } else if cli.export_file_path.is_none() {
         show_ui(cli);
     } else {
         if let Some(file_path) = cli.file_path {
@@ -74,6 +99,16 @@ fn main() {
             let file_info = load_file(file_path).unwrap();
             match file_info {
                 FileInfo::MdlFile(file) => export_mdl(&file, &export_file_path, cli.log),
+                FileInfo::BspFile(file)
+                    if get_extension_from_path(&export_file_path).as_deref() == Some("ent") =>
+                {
+                    export::ent::export(&file.reader, &export_file_path).unwrap()
+                }
+                FileInfo::BspFile(file)
+                    if get_extension_from_path(&export_file_path).as_deref() == Some("wad") =>
+                {
+                    extract_textures(&file, &export_file_path)
+                }
                 FileInfo::BspFile(file) => export_bsp(&file, &export_file_path, cli.log),
                 _ => panic!(),
             }
@@ -95,21 +130,111 @@ fn export_mdl(mdl_file: &MdlFile, export_file_path: &PathBuf, log: bool) {
 fn export_bsp(file: &BspFile, export_file_path: &PathBuf, log: bool) {
     let mut log = if log { Some(String::new()) } else { None };
     let path = PathBuf::from(&file.path).canonicalize().unwrap();
-    let game_root_path = get_game_root_path(&path).unwrap();
-    export::bsp::export(game_root_path, &file.reader, export_file_path, log.as_mut()).unwrap();
+    let game_root_path = get_game_root_path(&path, file.reader.variant()).unwrap();
+    let mod_path = get_mod_path(&path, game_root_path);
+    export::bsp::export(
+        game_root_path,
+        mod_path,
+        &file.reader,
+        export_file_path,
+        log.as_mut(),
+    )
+    .unwrap();
     if let Some(log) = log {
         std::fs::write("log.txt", log).unwrap();
     }
 }
 
-fn get_game_root_path(path: &Path) -> Option<&Path> {
+fn extract_textures(file: &BspFile, export_file_path: &Path) {
+    let palette = if file.reader.variant() == BspVariant::Quake {
+        let path = PathBuf::from(&file.path).canonicalize().unwrap();
+        let game_root_path = get_game_root_path(&path, file.reader.variant()).unwrap();
+        export::bsp::read_palette(&file.reader, game_root_path)
+    } else {
+        None
+    };
+    let writer = file.reader.extract_textures(palette.as_deref()).unwrap();
+    println!("Extracted {} textures", writer.len());
+    std::fs::write(export_file_path, writer.to_bytes()).unwrap();
+}
+
+fn embed_textures(file_path: &Path, output_path: &Path, strip_wad_key: bool) {
+    let result = load_bsp_file(file_path).and_then(|file| {
+        let path = PathBuf::from(&file.path).canonicalize()?;
+        let game_root_path = get_game_root_path(&path, file.reader.variant())
+            .ok_or("the map isn't inside a game directory")?;
+        let mod_path = get_mod_path(&path, game_root_path);
+        let data =
+            export::bsp::embed_textures(game_root_path, mod_path, &file.reader, strip_wad_key)?;
+        std::fs::write(output_path, data)?;
+        Ok(())
+    });
+    if let Err(error) = result {
+        eprintln!(
+            "Failed to embed the textures of \"{}\": {}",
+            file_path.display(),
+            error
+        );
+        std::process::exit(1);
+    }
+}
+
+fn import_images(image_dir: &Path, output_path: &Path) {
+    match export::wad::import_images(image_dir) {
+        Ok(writer) => {
+            println!("Imported {} images", writer.len());
+            std::fs::write(output_path, writer.to_bytes()).unwrap();
+        }
+        Err(error) => {
+            eprintln!("Failed to import \"{}\": {}", image_dir.display(), error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn import_entities(file_path: &Path, ent_file_path: &Path, output_path: &Path) {
+    let file = match load_bsp_file(file_path) {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("Failed to load \"{}\": {}", file_path.display(), error);
+            std::process::exit(1);
+        }
+    };
+    let data = match export::ent::import(&file.reader, ent_file_path) {
+        Ok(data) => data,
+        Err(error) => {
+            eprintln!(
+                "Failed to import \"{}\": {}",
+                ent_file_path.display(),
+                error
+            );
+            std::process::exit(1);
+        }
+    };
+    if let Err(error) = std::fs::write(output_path, data) {
+        eprintln!("Failed to write \"{}\": {}", output_path.display(), error);
+        std::process::exit(1);
+    }
+}
+
+fn get_game_root_path(path: &Path, variant: BspVariant) -> Option<&Path> {
+    // Quake resources are relative to the mod directory (e.g. "id1/maps/e1m1.bsp")
+    if variant == BspVariant::Quake {
+        return path.parent()?.parent();
+    }
     path.ancestors().skip(1).find(|x| {
-        assert!(x.is_dir(), "{:?}", x);
-        let file_stem = x.file_stem().unwrap().to_str().unwrap();
-        file_stem == "Half-Life"
+        x.file_stem()
+            .is_some_and(|file_stem| file_stem == "Half-Life")
     })
 }
 
+// The directory of the mod the map belongs to, e.g. "Half-Life/cstrike" for
+// "Half-Life/cstrike/maps/de_dust2.bsp"
+fn get_mod_path<'a>(path: &'a Path, game_root_path: &Path) -> Option<&'a Path> {
+    path.ancestors()
+        .find(|x| x.parent() == Some(game_root_path))
+}
+
 fn show_ui(cli: Cli) {
     env_logger::init();
 
@@ -211,6 +336,9 @@ fn show_ui(cli: Cli) {
     let mut mouse_controller = MouseInputController::new();
     let mut down_keys = HashSet::<VirtualKeyCode>::new();
     let mut noclip = false;
+    let mut lighting_mode = LightingMode::Lightmapped;
+    let mut freeze_pvs = false;
+    let mut show_leaves = false;
     event_loop.run(move |event, _, control_flow| {
         *control_flow = if cfg!(feature = "metal-auto-capture") {
             ControlFlow::Exit
@@ -376,12 +504,13 @@ fn show_ui(cli: Cli) {
 
                                                 let mut found = None;
                                                 let entities = BspEntity::parse_entities(
-                                                    file_info.reader.read_entities_str(),
-                                                );
+                                                    file_info.reader.read_entities().unwrap(),
+                                                )
+                                                .unwrap();
                                                 for (entity_index, entity) in
                                                     entities.iter().enumerate()
                                                 {
-                                                    if let Some(value) = entity.0.get("model") {
+                                                    if let Some(value) = entity.get("model") {
                                                         if value.starts_with('*') {
                                                             let model_ref: usize = value
                                                                 .trim_start_matches('*')
@@ -427,6 +556,11 @@ fn show_ui(cli: Cli) {
                     window.set_title(&format!("{} - {}", WINDOW_TITLE, new_path.display()));
                     renderer =
                         load_renderer(file_info.as_ref(), &device, &queue, surface_config.clone());
+                    if let Some(renderer) = renderer.as_mut() {
+                        renderer.set_lighting_mode(lighting_mode);
+                        renderer.set_pvs_frozen(freeze_pvs);
+                        renderer.set_show_leaves(show_leaves);
+                    }
                     pending_path = None;
                 }
 
@@ -444,6 +578,9 @@ fn show_ui(cli: Cli) {
                         mouse_delta
                     };
 
+                    for style in bsp_viewer.take_toggled_light_styles() {
+                        renderer.toggle_light_style(style);
+                    }
                     renderer.update(
                         &device,
                         &queue,
@@ -521,6 +658,47 @@ fn show_ui(cli: Cli) {
                             }
                         });
 
+                        ui.menu("View", || {
+                            ui.menu("Lighting", || {
+                                for (name, mode) in [
+                                    ("Fullbright", LightingMode::Fullbright),
+                                    ("Lightmap only", LightingMode::LightmapOnly),
+                                    ("Lightmapped", LightingMode::Lightmapped),
+                                ] {
+                                    if ui
+                                        .menu_item_config(name)
+                                        .selected(lighting_mode == mode)
+                                        .build()
+                                    {
+                                        lighting_mode = mode;
+                                        if let Some(renderer) = renderer.as_mut() {
+                                            renderer.set_lighting_mode(lighting_mode);
+                                        }
+                                    }
+                                }
+                            });
+                            if ui
+                                .menu_item_config("Freeze PVS")
+                                .selected(freeze_pvs)
+                                .build()
+                            {
+                                freeze_pvs = !freeze_pvs;
+                                if let Some(renderer) = renderer.as_mut() {
+                                    renderer.set_pvs_frozen(freeze_pvs);
+                                }
+                            }
+                            if ui
+                                .menu_item_config("Show Leaves")
+                                .selected(show_leaves)
+                                .build()
+                            {
+                                show_leaves = !show_leaves;
+                                if let Some(renderer) = renderer.as_mut() {
+                                    renderer.set_show_leaves(show_leaves);
+                                }
+                            }
+                        });
+
                         ui.menu("Game", || {
                             if ui.menu_item_config("Noclip").selected(noclip).build() {
                                 noclip = !noclip;
@@ -597,21 +775,49 @@ fn get_extension_from_path<P: AsRef<Path>>(path: P) -> Option<String> {
     Some(extension_str.to_owned())
 }
 
-fn load_wad_file<P: AsRef<Path>>(path: P) -> WadFile {
+fn load_wad_file<P: AsRef<Path>>(path: P) -> Result<WadFile, Box<dyn std::error::Error>> {
     let path = path.as_ref();
-    let archive = WadArchive::open(path);
+    let mut archive = WadArchive::open(path)?;
+    if archive.format() == WadFormat::Wad2 && archive.palette().is_none() {
+        archive.set_palette(read_quake_palette(path));
+    }
     let (files, file_names) = load_wad_archive(&archive);
-    WadFile {
+    Ok(WadFile {
         path: path.display().to_string(),
         archive: archive,
         files: files,
         file_names: file_names,
-    }
+    })
+}
+
+// Quake WADs live in the mod directory (e.g. "id1/gfx.wad"), next to the
+// gfx directory that has the palette.
+fn read_quake_palette(wad_path: &Path) -> Vec<u8> {
+    let wad_dir = wad_path.parent().unwrap_or(Path::new("."));
+    let candidates = [
+        wad_dir.join("gfx").join("palette.lmp"),
+        wad_dir.join("palette.lmp"),
+        wad_dir.join("..").join("gfx").join("palette.lmp"),
+    ];
+    let Some(path) = candidates.iter().find(|path| path.exists()) else {
+        eprintln!(
+            "WARNING: Could not find palette.lmp for \"{}\", using a greyscale palette",
+            wad_path.display()
+        );
+        return greyscale_palette();
+    };
+    export::bsp::read_palette_file(path)
 }
 
-fn load_mdl_file<P: AsRef<Path>>(path: P) -> MdlFile {
+fn load_mdl_file<P: AsRef<Path>>(path: P) -> Result<MdlFile, Box<dyn std::error::Error>> {
     let path = path.as_ref();
-    let mdl_file = gsparser::mdl::MdlFile::open(path).unwrap();
+    let mdl_file = gsparser::mdl::MdlFile::open(path)?;
+    for file_name in &mdl_file.missing_sequence_groups {
+        eprintln!(
+            "WARNING: Could not open sequence group file \"{}\", its sequences won't be animated",
+            file_name
+        );
+    }
 
     let mut texture_names = Vec::new();
     for texture in &mdl_file.textures {
@@ -625,36 +831,42 @@ fn load_mdl_file<P: AsRef<Path>>(path: P) -> MdlFile {
         body_part_names.push(imgui_str);
     }
 
-    MdlFile {
+    Ok(MdlFile {
         path: path.display().to_string(),
         file: mdl_file,
         texture_names: texture_names,
         body_part_names: body_part_names,
-    }
+    })
 }
 
-fn load_bsp_file<P: AsRef<Path>>(path: P) -> BspFile {
+fn load_bsp_file<P: AsRef<Path>>(path: P) -> Result<BspFile, Box<dyn std::error::Error>> {
     let path = path.as_ref();
-    let data = std::fs::read(path).unwrap();
-    let reader = BspReader::read(data);
+    let data = std::fs::read(path)?;
+    let reader = BspReader::read(data)?;
+    // Validate the entities up front, the rest of the viewer expects them
+    BspEntity::parse_entities(reader.read_entities()?)?;
 
-    BspFile {
+    Ok(BspFile {
         path: path.display().to_string(),
         reader,
-    }
+    })
 }
 
 fn load_file<P: AsRef<Path>>(path: P) -> Option<FileInfo> {
     let path = path.as_ref();
-    if let Some(extension) = get_extension_from_path(path) {
-        match extension.as_str() {
-            "wad" => Some(FileInfo::WadFile(load_wad_file(path))),
-            "mdl" => Some(FileInfo::MdlFile(load_mdl_file(path))),
-            "bsp" => Some(FileInfo::BspFile(load_bsp_file(path))),
-            _ => None,
+    let extension = get_extension_from_path(path)?;
+    let result = match extension.as_str() {
+        "wad" => load_wad_file(path).map(FileInfo::WadFile),
+        "mdl" => load_mdl_file(path).map(FileInfo::MdlFile),
+        "bsp" => load_bsp_file(path).map(FileInfo::BspFile),
+        _ => return None,
+    };
+    match result {
+        Ok(file_info) => Some(file_info),
+        Err(error) => {
+            eprintln!("Failed to load \"{}\": {}", path.display(), error);
+            None
         }
-    } else {
-        None
     }
 }
 
@@ -670,16 +882,36 @@ fn load_renderer(
             FileInfo::MdlFile(_) => None,
             FileInfo::BspFile(file) => {
                 let path = PathBuf::from(&file.path).canonicalize().unwrap();
-                let game_root_path = get_game_root_path(&path).unwrap();
+                let game_root_path = get_game_root_path(&path, file.reader.variant()).unwrap();
 
                 let mut wad_resources = WadCollection::new();
-                read_wad_resources(&file.reader, &game_root_path, &mut wad_resources);
-
-                let textures = read_textures(&file.reader, &wad_resources);
-                let map_models = export::bsp::convert_models(&file.reader, &textures);
-
-                let renderer =
-                    BspRenderer::new(&file.reader, &map_models, &textures, device, queue, config);
+                let mod_path = get_mod_path(&path, game_root_path);
+                if let Err(error) =
+                    read_wad_resources(&file.reader, game_root_path, mod_path, &mut wad_resources)
+                {
+                    eprintln!("WARNING: Could not read the map's WADs: {}", error);
+                }
+                let palette = read_palette(&file.reader, &game_root_path);
+
+                let textures = read_textures(&file.reader, &wad_resources, palette.as_deref());
+                let lightmaps = match LightmapAtlas::new(&file.reader) {
+                    Ok(lightmaps) => lightmaps,
+                    Err(error) => {
+                        eprintln!("Failed to load \"{}\": {}", file.path, error);
+                        return None;
+                    }
+                };
+                let map_models = export::bsp::convert_models(&file.reader, &lightmaps);
+
+                let renderer = BspRenderer::new(
+                    &file.reader,
+                    &map_models,
+                    &textures,
+                    &lightmaps,
+                    device,
+                    queue,
+                    config,
+                );
 
                 Some(Box::new(renderer))
             }
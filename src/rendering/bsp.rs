@@ -1,26 +1,50 @@
 use std::{
     borrow::Cow,
     collections::{HashMap, HashSet},
+    time::Duration,
 };
 
 use bytemuck::{Pod, Zeroable};
 use glam::{Mat4, Vec2, Vec3};
 use gltf::{Mesh, Model};
-use gsparser::bsp::{BspEntity, BspReader};
+use gsparser::bsp::{BspEntity, BspReader, BspVisibleLeaves};
 use wgpu::util::DeviceExt;
 use winit::event::VirtualKeyCode;
 
 use crate::{
     export::{
-        bsp::{ModelVertex, TextureInfo},
-        coordinates::convert_coordinates,
+        bsp::{MapModel, ModelVertex, TextureInfo},
+        coordinates::{convert_coordinates, convert_coordinates_to_half_life},
+        lightmap::LightmapAtlas,
     },
-    hittest::hittest_clip_node,
     rendering::movement::MovingEntity,
     FileInfo,
 };
 
-use super::{camera::Camera, debug::create_debug_point, Renderer};
+use super::{
+    camera::Camera,
+    debug::create_debug_point,
+    light_styles::{LightStyles, MAX_LIGHT_STYLES},
+    LightingMode, Renderer,
+};
+
+// Brush entities that move or rotate in game. Where they are at load time
+// doesn't tell which leaves they'll be in, so they're never culled.
+const MOVING_BRUSH_CLASSES: &[&str] = &[
+    "func_door",
+    "func_door_rotating",
+    "func_plat",
+    "func_platrot",
+    "func_train",
+    "func_tracktrain",
+    "func_rotating",
+    "func_pendulum",
+    "func_button",
+    "func_rot_button",
+    "momentary_door",
+    "momentary_rot_button",
+    "func_vehicle",
+];
 
 struct GpuModel {
     index_buffer: wgpu::Buffer,
@@ -36,6 +60,10 @@ struct GpuVertex {
     pos: [f32; 4],
     normal: [f32; 4],
     uv: [f32; 2],
+    lightmap_uv: [f32; 2],
+    light_styles: [u8; 4],
+    // The world leaf the vertex belongs to, 0 outside of the world model
+    leaf: u32,
 }
 
 impl GpuVertex {
@@ -44,6 +72,34 @@ impl GpuVertex {
             pos: Vec3::from_array(vertex.pos).extend(1.0).to_array(),
             normal: Vec3::from_array(vertex.normal).extend(0.0).to_array(),
             uv: vertex.uv,
+            lightmap_uv: vertex.lightmap_uv,
+            light_styles: vertex.light_styles,
+            leaf: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+struct GpuLightingSettings {
+    mode: u32,
+    show_leaves: u32,
+    _padding: [u32; 2],
+    styles: [f32; MAX_LIGHT_STYLES],
+}
+
+impl GpuLightingSettings {
+    fn new(mode: LightingMode, show_leaves: bool, styles: [f32; MAX_LIGHT_STYLES]) -> Self {
+        let mode = match mode {
+            LightingMode::Fullbright => 0,
+            LightingMode::LightmapOnly => 1,
+            LightingMode::Lightmapped => 2,
+        };
+        Self {
+            mode,
+            show_leaves: show_leaves as u32,
+            _padding: [0; 2],
+            styles,
         }
     }
 }
@@ -51,9 +107,27 @@ impl GpuVertex {
 pub struct BspRenderer {
     models_to_render: Vec<usize>,
     map_models: Vec<GpuModel>,
+    // The leaf each mesh of the world model belongs to
+    world_mesh_leaves: Vec<usize>,
+    // The world leaves touched by each brush model, None if the model moves
+    // or they couldn't be found, and the model is always drawn
+    model_leaves: Vec<Option<Vec<usize>>>,
+    camera_leaf: Option<usize>,
+    visible_leaves: Option<BspVisibleLeaves>,
+    pvs_frozen: bool,
+    show_leaves: bool,
     textures: Vec<(wgpu::Texture, wgpu::TextureView, wgpu::BindGroup)>,
     sampler: wgpu::Sampler,
 
+    _lightmap_texture: wgpu::Texture,
+    _lightmap_view: wgpu::TextureView,
+    _lightmap_sampler: wgpu::Sampler,
+    lighting_buffer: wgpu::Buffer,
+    lightmap_bind_group: wgpu::BindGroup,
+    lighting_mode: LightingMode,
+    light_styles: LightStyles,
+    time: Duration,
+
     _shader: wgpu::ShaderModule,
     config: wgpu::SurfaceConfiguration,
 
@@ -64,6 +138,7 @@ pub struct BspRenderer {
     _bind_group_layout: wgpu::BindGroupLayout,
     model_bind_group_layout: wgpu::BindGroupLayout,
     _texture_bind_group_layout: wgpu::BindGroupLayout,
+    _lightmap_bind_group_layout: wgpu::BindGroupLayout,
     _pipeline_layout: wgpu::PipelineLayout,
     render_pipeline: wgpu::RenderPipeline,
 
@@ -77,8 +152,9 @@ pub struct BspRenderer {
 impl BspRenderer {
     pub fn new(
         reader: &BspReader,
-        loaded_map_models: &[Model<ModelVertex>],
+        loaded_map_models: &[MapModel],
         loaded_textures: &[TextureInfo],
+        lightmaps: &LightmapAtlas,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         config: wgpu::SurfaceConfiguration,
@@ -143,12 +219,47 @@ impl BspRenderer {
                     count: None,
                 }],
             });
+        let lightmap_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(
+                                std::mem::size_of::<GpuLightingSettings>() as u64,
+                            ),
+                        },
+                        count: None,
+                    },
+                ],
+            });
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
             bind_group_layouts: &[
                 &bind_group_layout,
                 &model_bind_group_layout,
                 &texture_bind_group_layout,
+                &lightmap_bind_group_layout,
             ],
             push_constant_ranges: &[],
         });
@@ -197,18 +308,18 @@ impl BspRenderer {
         }
 
         // Find the "info_player_start" entity
-        let entities = BspEntity::parse_entities(reader.read_entities_str());
+        let entities = BspEntity::parse_entities(reader.read_entities().unwrap()).unwrap();
         let mut player_start_entity = None;
         for entity in &entities {
-            if let Some(value) = entity.0.get("classname") {
-                if *value == "info_player_start" {
+            if let Some(value) = entity.get("classname") {
+                if value == "info_player_start" {
                     player_start_entity = Some(entity);
                 }
             }
         }
         let camera_start = {
             let entity = player_start_entity.unwrap();
-            let value = entity.0.get("origin").unwrap();
+            let value = entity.get("origin").unwrap();
             let mut split = value.split(" ");
             let x: f32 = split.next().unwrap().parse().unwrap();
             let y: f32 = split.next().unwrap().parse().unwrap();
@@ -228,6 +339,48 @@ impl BspRenderer {
             &device,
         );
 
+        // Lightmap atlas
+        let lighting_mode = LightingMode::Lightmapped;
+        let light_styles = LightStyles::new(&entities);
+        let (lightmap_texture, lightmap_view) =
+            create_texture_array_and_view(device, queue, &lightmaps.layers);
+        let lightmap_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: None,
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let lighting_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Lighting Uniform Buffer"),
+            contents: bytemuck::bytes_of(&GpuLightingSettings::new(
+                lighting_mode,
+                false,
+                light_styles.evaluate(Duration::ZERO),
+            )),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let lightmap_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &lightmap_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&lightmap_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&lightmap_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: lighting_buffer.as_entire_binding(),
+                },
+            ],
+            label: None,
+        });
+
         let vertex_buffers = [wgpu::VertexBufferLayout {
             array_stride: std::mem::size_of::<GpuVertex>() as wgpu::BufferAddress,
             step_mode: wgpu::VertexStepMode::Vertex,
@@ -247,6 +400,21 @@ impl BspRenderer {
                     offset: 8 * 4,
                     shader_location: 2,
                 },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x2,
+                    offset: 10 * 4,
+                    shader_location: 3,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Uint8x4,
+                    offset: 12 * 4,
+                    shader_location: 4,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Uint32,
+                    offset: 13 * 4,
+                    shader_location: 5,
+                },
             ],
         }];
 
@@ -254,7 +422,7 @@ impl BspRenderer {
         // TODO: Can we assume 1:1 (minus models not tied to any entities)?
         let mut model_to_entity = HashMap::new();
         for (entity_index, entity) in entities.iter().enumerate() {
-            if let Some(model_value) = entity.0.get("model") {
+            if let Some(model_value) = entity.get("model") {
                 if model_value.starts_with('*') {
                     let model_index: usize = model_value.trim_start_matches('*').parse().unwrap();
                     let old = model_to_entity.insert(model_index, entity_index);
@@ -263,16 +431,17 @@ impl BspRenderer {
             }
         }
 
-        let map_models: Vec<GpuModel> = loaded_map_models
+        let bsp_models = reader.read_models();
+        let (map_models, model_leaves): (Vec<GpuModel>, Vec<_>) = loaded_map_models
             .iter()
             .enumerate()
-            .map(|(i, model)| -> GpuModel {
+            .map(|(i, map_model)| -> (GpuModel, Option<Vec<usize>>) {
                 let origin = {
                     let mut origin = Vec3::ZERO;
 
                     if let Some(entity_index) = model_to_entity.get(&i) {
                         let entity = &entities[*entity_index];
-                        if let Some(origin_str) = entity.0.get("origin") {
+                        if let Some(origin_str) = entity.get("origin") {
                             let mut parts = origin_str.split_whitespace();
                             let hl_x: isize = parts.next().unwrap().parse().unwrap();
                             let hl_y: isize = parts.next().unwrap().parse().unwrap();
@@ -286,23 +455,51 @@ impl BspRenderer {
                     origin
                 };
 
-                create_gpu_model_for_model(
-                    model,
+                let is_moving = model_to_entity
+                    .get(&i)
+                    .and_then(|entity_index| entities[*entity_index].get("classname"))
+                    .is_some_and(|class_name| MOVING_BRUSH_CLASSES.contains(&class_name));
+
+                // The world model is culled per mesh instead
+                let leaves = if i == 0 {
+                    Some(Vec::new())
+                } else if is_moving {
+                    None
+                } else {
+                    let bsp_model = &bsp_models[i];
+                    let origin =
+                        Vec3::from_array(convert_coordinates_to_half_life(origin.to_array()));
+                    let mins = Vec3::from_array(bsp_model.mins) + origin;
+                    let maxs = Vec3::from_array(bsp_model.maxs) + origin;
+                    match reader.find_leaves_in_box(mins.to_array(), maxs.to_array()) {
+                        Ok(leaves) => Some(leaves),
+                        Err(error) => {
+                            eprintln!("WARNING: Model {} won't be culled: {}", i, error);
+                            None
+                        }
+                    }
+                };
+
+                let gpu_model = create_gpu_model_for_model(
+                    &map_model.model,
+                    (i == 0).then_some(map_model.mesh_leaves.as_slice()),
                     origin,
                     device,
                     &model_bind_group_layout,
                     &sampler,
-                )
+                );
+                (gpu_model, leaves)
             })
-            .collect();
+            .unzip();
+        let world_mesh_leaves = loaded_map_models[0].mesh_leaves.clone();
 
         // Record which models to hide
         // TODO: More robust logic
         let mut models_to_render: Vec<usize> = (0..map_models.len()).collect();
         for entity in &entities {
-            if let Some(class_name) = entity.0.get("classname") {
+            if let Some(class_name) = entity.get("classname") {
                 if class_name.starts_with("trigger") || class_name.starts_with("func_ladder") {
-                    if let Some(model_value) = entity.0.get("model") {
+                    if let Some(model_value) = entity.get("model") {
                         if model_value.starts_with('*') {
                             let model_index: usize =
                                 model_value.trim_start_matches('*').parse().unwrap();
@@ -362,9 +559,24 @@ impl BspRenderer {
         Self {
             models_to_render,
             map_models,
+            world_mesh_leaves,
+            model_leaves,
+            camera_leaf: None,
+            visible_leaves: None,
+            pvs_frozen: false,
+            show_leaves: false,
             textures,
             sampler,
 
+            _lightmap_texture: lightmap_texture,
+            _lightmap_view: lightmap_view,
+            _lightmap_sampler: lightmap_sampler,
+            lighting_buffer,
+            lightmap_bind_group,
+            lighting_mode,
+            light_styles,
+            time: Duration::ZERO,
+
             _shader: shader,
             config,
 
@@ -375,6 +587,7 @@ impl BspRenderer {
             _bind_group_layout: bind_group_layout,
             model_bind_group_layout,
             _texture_bind_group_layout: texture_bind_group_layout,
+            _lightmap_bind_group_layout: lightmap_bind_group_layout,
             _pipeline_layout: pipeline_layout,
             render_pipeline,
 
@@ -386,11 +599,30 @@ impl BspRenderer {
         }
     }
 
-    fn render_model<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, model: &'a GpuModel) {
+    fn is_leaf_visible(&self, leaf_index: usize) -> bool {
+        self.visible_leaves
+            .as_ref()
+            .map(|visible_leaves| visible_leaves.contains(leaf_index))
+            .unwrap_or(true)
+    }
+
+    // When the leaf of each mesh is provided, meshes outside of the PVS are
+    // skipped.
+    fn render_model<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        model: &'a GpuModel,
+        mesh_leaves: Option<&[usize]>,
+    ) {
         render_pass.set_index_buffer(model.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         render_pass.set_vertex_buffer(0, model.vertex_buffer.slice(..));
 
-        for mesh in &model.meshes {
+        for (i, mesh) in model.meshes.iter().enumerate() {
+            if let Some(mesh_leaves) = mesh_leaves {
+                if !self.is_leaf_visible(mesh_leaves[i]) {
+                    continue;
+                }
+            }
             let texture = mesh.texture_index;
             let (_, _, bind_group) = &self.textures[texture];
             render_pass.set_bind_group(2, bind_group, &[]);
@@ -436,16 +668,30 @@ impl Renderer for BspRenderer {
             render_pass.push_debug_group("Prepare frame render pass.");
             render_pass.set_pipeline(&self.render_pipeline);
             render_pass.set_bind_group(0, self.camera.bind_group(), &[]);
+            render_pass.set_bind_group(3, &self.lightmap_bind_group, &[]);
 
             render_pass.insert_debug_marker("Draw!");
             for model_index in &self.models_to_render {
                 let model = &self.map_models[*model_index];
-                render_pass.set_bind_group(1, &model.model_bind_group, &[]);
-                self.render_model(&mut render_pass, model);
+                if *model_index == 0 {
+                    render_pass.set_bind_group(1, &model.model_bind_group, &[]);
+                    self.render_model(&mut render_pass, model, Some(&self.world_mesh_leaves));
+                } else if self.model_leaves[*model_index]
+                    .as_ref()
+                    .map(|leaves| {
+                        leaves
+                            .iter()
+                            .any(|leaf_index| self.is_leaf_visible(*leaf_index))
+                    })
+                    .unwrap_or(true)
+                {
+                    render_pass.set_bind_group(1, &model.model_bind_group, &[]);
+                    self.render_model(&mut render_pass, model, None);
+                }
             }
 
             if let Some(model) = self.debug_point.as_ref() {
-                self.render_model(&mut render_pass, model);
+                self.render_model(&mut render_pass, model, None);
             }
 
             render_pass.pop_debug_group();
@@ -546,35 +792,45 @@ impl Renderer for BspRenderer {
                     FileInfo::BspFile(file) => &file.reader,
                     _ => panic!(),
                 };
-                let clip_node_index = reader.read_models()[0].head_nodes[1] as usize;
-                if let Some(intersection) =
-                    hittest_clip_node(reader, clip_node_index, start_position, end_position)
-                {
-                    println!("intersection: {}", intersection.position);
-                    println!("temp_end_position: {}", end_position);
-                    let rest = intersection.position - end_position;
-                    let direction = -rest.normalize();
-                    let speed = rest.length();
-                    
-                    let temp = direction.dot(intersection.normal).abs();
-
-                    if speed < 0.001 || temp < 0.001 {
-                        position = start_position;
-                    } else {
-                        let direction = direction - (intersection.normal * direction.dot(intersection.normal));
-                        let direction = direction.normalize();
-
-                        let end_position = (direction * speed) + start_position;
-                        println!("plane_normal: {}", intersection.normal);
-                        println!("direction: {}", direction);
-                        println!("end_position: {}", end_position);
-                        println!();
-                        if end_position.is_nan() {
-                            position = start_position;
-                        } else {
-                            position = end_position;
+                // Hull 1 of the world model is the standing player's clip hull
+                let trace = reader.trace(
+                    0,
+                    1,
+                    convert_coordinates_to_half_life(start_position.to_array()),
+                    convert_coordinates_to_half_life(end_position.to_array()),
+                );
+                match trace {
+                    Ok(trace) => {
+                        if let Some(plane) = trace.plane {
+                            let intersection =
+                                Vec3::from_array(convert_coordinates(trace.end_position));
+                            let normal = Vec3::from_array(convert_coordinates(plane.normal));
+                            let rest = intersection - end_position;
+                            let direction = -rest.normalize();
+                            let speed = rest.length();
+
+                            let temp = direction.dot(normal).abs();
+
+                            if speed < 0.001 || temp < 0.001 {
+                                position = start_position;
+                            } else {
+                                let direction = direction - (normal * direction.dot(normal));
+                                let direction = direction.normalize();
+
+                                let end_position = (direction * speed) + start_position;
+                                if end_position.is_nan() {
+                                    position = start_position;
+                                } else {
+                                    position = end_position;
+                                }
+                            }
                         }
                     }
+                    Err(error) => {
+                        // Stop the player rather than letting them through walls
+                        eprintln!("WARNING: Couldn't trace the player's movement: {}", error);
+                        position = start_position;
+                    }
                 }
             }
 
@@ -582,12 +838,36 @@ impl Renderer for BspRenderer {
             self.player.set_position(position);
         }
 
+        if !self.pvs_frozen {
+            let reader = match file_info.as_ref().unwrap() {
+                FileInfo::BspFile(file) => &file.reader,
+                _ => panic!(),
+            };
+            let position = convert_coordinates_to_half_life(self.camera.position().to_array());
+            // Everything is drawn if the PVS can't be read
+            let leaf_index = reader.find_leaf(position).ok();
+            if self.camera_leaf != leaf_index {
+                self.camera_leaf = leaf_index;
+                self.visible_leaves =
+                    leaf_index.and_then(|leaf_index| reader.read_visible_leaves(leaf_index).ok());
+            }
+        }
+
         self.camera.update(queue);
 
+        self.time += delta;
+        let lighting = GpuLightingSettings::new(
+            self.lighting_mode,
+            self.show_leaves,
+            self.light_styles.evaluate(self.time),
+        );
+        queue.write_buffer(&self.lighting_buffer, 0, bytemuck::bytes_of(&lighting));
+
         if let Some(new_debug_point) = self.new_debug_point.take() {
             let model = create_debug_point_model(new_debug_point, self.textures.len() - 1);
             let gpu_model = create_gpu_model_for_model(
                 &model,
+                None,
                 Vec3::ZERO,
                 device,
                 &self.model_bind_group_layout,
@@ -608,6 +888,22 @@ impl Renderer for BspRenderer {
     fn set_debug_point(&mut self, point: Vec3) {
         self.new_debug_point = Some(point);
     }
+
+    fn set_lighting_mode(&mut self, mode: LightingMode) {
+        self.lighting_mode = mode;
+    }
+
+    fn toggle_light_style(&mut self, style: usize) {
+        self.light_styles.toggle(style);
+    }
+
+    fn set_pvs_frozen(&mut self, frozen: bool) {
+        self.pvs_frozen = frozen;
+    }
+
+    fn set_show_leaves(&mut self, show: bool) {
+        self.show_leaves = show;
+    }
 }
 
 fn create_texture_and_view(
@@ -644,6 +940,60 @@ fn create_texture_and_view(
     (texture, texture_view)
 }
 
+fn create_texture_array_and_view(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layers: &[image::ImageBuffer<image::Rgba<u8>, Vec<u8>>],
+) -> (wgpu::Texture, wgpu::TextureView) {
+    let width = layers[0].width();
+    let height = layers[0].height();
+    let texture_extent = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: layers.len() as u32,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: None,
+        size: texture_extent,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+    for (i, layer) in layers.iter().enumerate() {
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: i as u32,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            layer,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(width * 4),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+    (texture, texture_view)
+}
+
 fn create_depth_texture(
     device: &wgpu::Device,
     config: &wgpu::SurfaceConfiguration,
@@ -677,14 +1027,24 @@ fn create_depth_texture(
     (texture, view, sampler)
 }
 
+// The leaf of each mesh is stored in its vertices, so the shader can color
+// the world's leaves.
 fn create_gpu_model_for_model(
     model: &Model<ModelVertex>,
+    mesh_leaves: Option<&[usize]>,
     origin: Vec3,
     device: &wgpu::Device,
     model_bind_group_layout: &wgpu::BindGroupLayout,
     sampler: &wgpu::Sampler,
 ) -> GpuModel {
-    let vertices: Vec<GpuVertex> = model.vertices.iter().map(|x| GpuVertex::from(x)).collect();
+    let mut vertices: Vec<GpuVertex> = model.vertices.iter().map(|x| GpuVertex::from(x)).collect();
+    if let Some(mesh_leaves) = mesh_leaves {
+        for (mesh, leaf_index) in model.meshes.iter().zip(mesh_leaves) {
+            for index in &model.indices[mesh.indices_range.clone()] {
+                vertices[*index as usize].leaf = *leaf_index as u32;
+            }
+        }
+    }
     let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("Vertex Buffer"),
         contents: bytemuck::cast_slice(&vertices),
@@ -0,0 +1,196 @@
+use std::time::Duration;
+
+use gsparser::bsp::BspEntity;
+
+pub const MAX_LIGHT_STYLES: usize = 64;
+
+// Styles from 32 onward are handed out to named lights by the compile tools.
+const FIRST_SWITCHABLE_STYLE: usize = 32;
+const FRAMES_PER_SECOND: f32 = 10.0;
+const SPAWN_FLAG_START_OFF: u32 = 1;
+
+const ON_PATTERN: &str = "m";
+const OFF_PATTERN: &str = "a";
+
+// From world.cpp in the Half-Life SDK
+const PRESET_PATTERNS: [&str; 13] = [
+    // 0 normal
+    "m",
+    // 1 FLICKER (first variety)
+    "mmnmmommommnonmmonqnmmo",
+    // 2 SLOW STRONG PULSE
+    "abcdefghijklmnopqrstuvwxyzyxwvutsrqponmlkjihgfedcba",
+    // 3 CANDLE (first variety)
+    "mmmmmaaaaammmmmaaaaaabcdefgabcdefg",
+    // 4 FAST STROBE
+    "mamamamamama",
+    // 5 GENTLE PULSE 1
+    "jklmnopqrstuvwxyzyxwvutsrqponmlkj",
+    // 6 FLICKER (second variety)
+    "nmonqnmomnmomomno",
+    // 7 CANDLE (second variety)
+    "mmmaaaabcdefgmmmmaaaammmaamm",
+    // 8 CANDLE (third variety)
+    "mmmaaammmaaammmabcdefaaaammmmabcdefmmmaaaa",
+    // 9 SLOW STROBE (fourth variety)
+    "aaaaaaaazzzzzzzz",
+    // 10 FLUORESCENT FLICKER
+    "mmamammmmammamamaaamammma",
+    // 11 SLOW PULSE NOT FADE TO BLACK
+    "abcdefghijklmnopqrrqponmlkjihgfedcba",
+    // 12 UNDERWATER LIGHT MUTATION
+    "mmnnmmnnnmmnn",
+];
+// 63 testing
+const TESTING_STYLE: usize = 63;
+
+struct LightStyle {
+    pattern: String,
+    enabled: bool,
+}
+
+impl LightStyle {
+    fn new(pattern: &str, enabled: bool) -> Self {
+        Self {
+            pattern: pattern.to_owned(),
+            enabled,
+        }
+    }
+
+    fn value(&self, frame: usize) -> f32 {
+        let pattern = if self.enabled {
+            self.pattern.as_bytes()
+        } else {
+            OFF_PATTERN.as_bytes()
+        };
+        if pattern.is_empty() {
+            return 1.0;
+        }
+        let value = pattern[frame % pattern.len()].clamp(b'a', b'z') - b'a';
+        // The engine scales each step by 22, where 256 is full brightness ('m').
+        (value as f32 * 22.0) / 256.0
+    }
+}
+
+pub struct LightStyles {
+    styles: Vec<LightStyle>,
+}
+
+impl LightStyles {
+    pub fn new(entities: &[BspEntity]) -> Self {
+        let mut styles: Vec<_> = (0..MAX_LIGHT_STYLES)
+            .map(|style| {
+                let pattern = PRESET_PATTERNS.get(style).unwrap_or(&ON_PATTERN);
+                LightStyle::new(pattern, true)
+            })
+            .collect();
+        styles[TESTING_STYLE] = LightStyle::new(OFF_PATTERN, true);
+
+        for entity in entities {
+            if let Some(style) = get_switchable_light_style(&entity.0) {
+                let pattern = entity.get("pattern").unwrap_or(ON_PATTERN);
+                let spawn_flags: u32 = entity
+                    .get("spawnflags")
+                    .and_then(|x| x.parse().ok())
+                    .unwrap_or(0);
+                let enabled = spawn_flags & SPAWN_FLAG_START_OFF == 0;
+                styles[style] = LightStyle::new(pattern, enabled);
+            }
+        }
+
+        Self { styles }
+    }
+
+    pub fn toggle(&mut self, style: usize) {
+        if let Some(style) = self.styles.get_mut(style) {
+            style.enabled = !style.enabled;
+        }
+    }
+
+    pub fn evaluate(&self, time: Duration) -> [f32; MAX_LIGHT_STYLES] {
+        let frame = (time.as_secs_f32() * FRAMES_PER_SECOND) as usize;
+        let mut values = [0.0; MAX_LIGHT_STYLES];
+        for (value, style) in values.iter_mut().zip(&self.styles) {
+            *value = style.value(frame);
+        }
+        values
+    }
+}
+
+pub fn get_switchable_light_style<K, V>(entity: &[(K, V)]) -> Option<usize>
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let get = |key: &str| {
+        entity
+            .iter()
+            .rev()
+            .find(|(entry_key, _)| entry_key.as_ref() == key)
+            .map(|(_, value)| value.as_ref())
+    };
+    let class_name = get("classname")?;
+    if class_name != "light" && class_name != "light_spot" {
+        return None;
+    }
+    let style: usize = get("style")?.parse().ok()?;
+    if (FIRST_SWITCHABLE_STYLE..MAX_LIGHT_STYLES).contains(&style) {
+        Some(style)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: f32 = (12.0 * 22.0) / 256.0;
+
+    fn value(styles: &LightStyles, frame: u64, style: usize) -> f32 {
+        styles.evaluate(Duration::from_millis(frame * 100))[style]
+    }
+
+    #[test]
+    fn presets_step_through_their_patterns() {
+        let styles = LightStyles::new(&[]);
+        // 'm' is normal brightness
+        assert_eq!(value(&styles, 0, 0), FULL);
+        assert_eq!(value(&styles, 7, 0), FULL);
+        // The slow strong pulse goes from 'a' up to 'z'
+        assert_eq!(value(&styles, 0, 2), 0.0);
+        assert_eq!(value(&styles, 1, 2), 22.0 / 256.0);
+        assert_eq!(value(&styles, 25, 2), (25.0 * 22.0) / 256.0);
+        // The fast strobe alternates every frame
+        assert_eq!(value(&styles, 1, 4), 0.0);
+        assert_eq!(value(&styles, 2, 4), FULL);
+        // Styles without a preset are on, apart from the testing style
+        assert_eq!(value(&styles, 0, 20), FULL);
+        assert_eq!(value(&styles, 0, TESTING_STYLE), 0.0);
+    }
+
+    #[test]
+    fn named_lights_can_be_toggled() {
+        let source = concat!(
+            "{\n\"classname\" \"light\"\n\"style\" \"32\"\n\"pattern\" \"az\"\n}\n",
+            "{\n\"classname\" \"light_spot\"\n\"style\" \"33\"\n\"spawnflags\" \"1\"\n}\n",
+            "{\n\"classname\" \"light\"\n\"style\" \"5\"\n\"pattern\" \"a\"\n}\n",
+            "{\n\"classname\" \"info_target\"\n\"style\" \"34\"\n\"pattern\" \"a\"\n}\n",
+        );
+        let mut styles = LightStyles::new(&BspEntity::parse_entities(source).unwrap());
+
+        assert_eq!(value(&styles, 0, 32), 0.0);
+        assert_eq!(value(&styles, 1, 32), (25.0 * 22.0) / 256.0);
+        // Lights that start off stay at 'a' until they're turned on
+        assert_eq!(value(&styles, 0, 33), 0.0);
+        styles.toggle(33);
+        assert_eq!(value(&styles, 0, 33), FULL);
+        styles.toggle(32);
+        assert_eq!(value(&styles, 1, 32), 0.0);
+        styles.toggle(MAX_LIGHT_STYLES);
+
+        // Only lights with styles from 32 onward get their own pattern
+        assert_eq!(value(&styles, 0, 5), (9.0 * 22.0) / 256.0);
+        assert_eq!(value(&styles, 0, 34), FULL);
+    }
+}
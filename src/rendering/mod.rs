@@ -8,8 +8,16 @@ use crate::FileInfo;
 pub mod bsp;
 mod camera;
 mod debug;
+pub mod light_styles;
 mod movement;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LightingMode {
+    Fullbright,
+    LightmapOnly,
+    Lightmapped,
+}
+
 pub trait Renderer {
     fn render(
         &self,
@@ -43,4 +51,14 @@ pub trait Renderer {
     fn get_position_and_direction(&self) -> (Vec3, Vec3);
 
     fn set_debug_point(&mut self, point: Vec3);
+
+    fn set_lighting_mode(&mut self, mode: LightingMode);
+
+    fn toggle_light_style(&mut self, style: usize);
+
+    // Stops updating the PVS as the camera moves.
+    fn set_pvs_frozen(&mut self, frozen: bool);
+
+    // Tints each leaf of the world a different color.
+    fn set_show_leaves(&mut self, show: bool);
 }
@@ -1,6 +1,6 @@
 use crate::graphics::{create_imgui_texture, MipTexture, TextureBundle};
 use crate::WadFile;
-use gsparser::wad3::{CharInfo, TextureType, WadArchive, WadFileInfo};
+use gsparser::wad3::{CharInfo, TextureType, WadArchive, WadError, WadFileInfo};
 use imgui::*;
 use imgui_wgpu::Renderer;
 use std::collections::HashMap;
@@ -15,13 +15,15 @@ pub struct FontMetadata {
 #[derive(Clone)]
 pub struct ExtraTextureData {
     pub texture_type: TextureType,
+    pub raw_type: u8,
     pub font: Option<FontMetadata>,
 }
 
 impl ExtraTextureData {
-    pub fn new(texture_type: TextureType) -> ExtraTextureData {
+    pub fn new(texture_type: TextureType, raw_type: u8) -> ExtraTextureData {
         ExtraTextureData {
             texture_type: texture_type,
+            raw_type,
             font: None,
         }
     }
@@ -114,13 +116,14 @@ impl WadViewer {
                 .files
                 .get(&file_info.file_names[self.state.selected_file_index as usize])
                 .unwrap();
-            self.texture_bundle = Some(get_texture_bundle(
-                &file_info.archive,
-                &info,
-                device,
-                queue,
-                renderer,
-            ));
+            self.texture_bundle =
+                match get_texture_bundle(&file_info.archive, &info, device, queue, renderer) {
+                    Ok(texture_bundle) => Some(texture_bundle),
+                    Err(error) => {
+                        eprintln!("Failed to decode \"{}\": {}", info.name, error);
+                        None
+                    }
+                };
         }
 
         let mut temp_state = self.state.clone();
@@ -133,8 +136,7 @@ impl WadViewer {
                     ui.text(&file_names[temp_state.selected_file_index as usize]);
                     ui.text(format!(
                         "Type: {:?} (0x{:X})",
-                        texture_bundle.extra_data.texture_type,
-                        texture_bundle.extra_data.texture_type as u8
+                        texture_bundle.extra_data.texture_type, texture_bundle.extra_data.raw_type
                     ));
                     ui.text(format!(
                         "Size: {} x {}",
@@ -209,39 +211,42 @@ impl WadViewer {
 pub fn get_decoded_data(
     archive: &WadArchive,
     info: &WadFileInfo,
-) -> (
-    Vec<image::ImageBuffer<image::Rgba<u8>, Vec<u8>>>,
-    ExtraTextureData,
-) {
-    let mut extra_data = ExtraTextureData::new(info.texture_type);
+) -> Result<
+    (
+        Vec<image::ImageBuffer<image::Rgba<u8>, Vec<u8>>>,
+        ExtraTextureData,
+    ),
+    WadError,
+> {
+    let mut extra_data = ExtraTextureData::new(info.texture_type, info.raw_type());
     let datas = {
         if info.texture_type == TextureType::Decal
             || info.texture_type == TextureType::MipmappedImage
         {
-            let image_data = archive.decode_mipmaped_image(&info);
+            let image_data = archive.decode_mipmaped_image(&info)?;
             vec![
-                image_data.image,
-                image_data.mipmap1,
-                image_data.mipmap2,
-                image_data.mipmap3,
+                image_data.image.clone(),
+                image_data.mipmap1.clone(),
+                image_data.mipmap2.clone(),
+                image_data.mipmap3.clone(),
             ]
         } else if info.texture_type == TextureType::Image {
-            let image_data = archive.decode_image(&info);
-            vec![image_data.image]
+            let image_data = archive.decode_image(&info)?;
+            vec![image_data.image.clone()]
         } else if info.texture_type == TextureType::Font {
-            let font_data = archive.decode_font(&info);
+            let font_data = archive.decode_font(&info)?;
 
             extra_data.font = Some(FontMetadata {
                 row_count: font_data.row_count,
                 row_height: font_data.row_height,
                 char_infos: font_data.font_info,
             });
-            vec![font_data.image]
+            vec![font_data.image.clone()]
         } else {
             panic!("New texture type! {:?}", info.texture_type);
         }
     };
-    (datas, extra_data)
+    Ok((datas, extra_data))
 }
 
 pub fn get_texture_bundle(
@@ -250,8 +255,8 @@ pub fn get_texture_bundle(
     device: &mut wgpu::Device,
     queue: &mut wgpu::Queue,
     renderer: &mut Renderer,
-) -> TextureBundle<ExtraTextureData> {
-    let (decoded_images, texture_data) = get_decoded_data(&archive, &info);
+) -> Result<TextureBundle<ExtraTextureData>, WadError> {
+    let (decoded_images, texture_data) = get_decoded_data(&archive, &info)?;
     let mut textures = Vec::with_capacity(decoded_images.len());
 
     for decoded_image in decoded_images {
@@ -266,10 +271,10 @@ pub fn get_texture_bundle(
         });
     }
 
-    TextureBundle {
+    Ok(TextureBundle {
         mip_textures: textures,
         extra_data: texture_data,
-    }
+    })
 }
 
 pub fn load_wad_archive(archive: &WadArchive) -> (HashMap<ImString, WadFileInfo>, Vec<ImString>) {